@@ -20,3 +20,6 @@ fn main() {
   );
   res.compile().unwrap();
 }
+
+#[cfg(not(windows))]
+fn main() {}
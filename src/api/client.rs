@@ -0,0 +1,52 @@
+use std::sync::mpsc::Receiver;
+
+use serde_json::Value;
+
+use super::{
+  http::{Request, Response},
+  Api,
+};
+use crate::bus::{state::MachineState, Event};
+
+/// Talks to an `Api` directly instead of over a socket, sending the token like the GUI does
+pub struct Client<'a> {
+  api: &'a Api,
+  token: String,
+}
+
+impl<'a> Client<'a> {
+  pub fn new(api: &'a Api) -> Self {
+    Self {
+      api,
+      token: api.token().to_string(),
+    }
+  }
+
+  pub fn with_token(api: &'a Api, token: &str) -> Self {
+    Self {
+      api,
+      token: token.to_string(),
+    }
+  }
+
+  fn request(&self, method: &str, path: &str) -> Request {
+    Request::new(method, path).with_header("Authorization", &format!("Bearer {}", self.token))
+  }
+
+  pub fn get(&self, path: &str) -> Response {
+    self.api.handle(&self.request("GET", path))
+  }
+
+  pub fn post(&self, path: &str, body: &Value) -> Response {
+    self.api.handle(&self.request("POST", path).with_json(body))
+  }
+
+  pub fn put(&self, path: &str, body: &Value) -> Response {
+    self.api.handle(&self.request("PUT", path).with_json(body))
+  }
+
+  /// What `GET /events` streams: the current state, then every event
+  pub fn events(&self) -> Result<(MachineState, Receiver<Event>), Response> {
+    self.api.events(&self.request("GET", "/events"))
+  }
+}
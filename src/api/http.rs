@@ -0,0 +1,248 @@
+use std::io::{self, BufRead, Read, Write};
+
+use serde_json::Value;
+
+/// Anything bigger is not a request the GUI would send
+const MAX_HEADER_LINES: usize = 64;
+const MAX_LINE: usize = 8 * 1024;
+const MAX_BODY: usize = 1 << 20;
+
+/// Just enough HTTP/1.1 for a local client: one request per connection, no chunked bodies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+  pub method: String,
+  pub path: String,
+  pub query: Vec<(String, String)>,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads one line into `line`, failing on one longer than `MAX_LINE` rather than reading it all
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<()> {
+  line.clear();
+  reader.by_ref().take(MAX_LINE as u64 + 1).read_line(line)?;
+  if line.len() > MAX_LINE {
+    return Err(invalid("Line too long"));
+  }
+  Ok(())
+}
+
+impl Request {
+  pub fn new(method: &str, target: &str) -> Self {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    Self {
+      method: method.to_string(),
+      path: path.to_string(),
+      query: query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+          let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+          (key.to_string(), value.to_string())
+        })
+        .collect(),
+      headers: Vec::new(),
+      body: Vec::new(),
+    }
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn with_json(mut self, body: &Value) -> Self {
+    self.body = body.to_string().into_bytes();
+    self.with_header("Content-Type", "application/json")
+  }
+
+  pub fn read(reader: &mut impl BufRead) -> io::Result<Self> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+      return Err(invalid("Malformed request line"));
+    };
+    let mut request = Request::new(method, target);
+
+    let mut ended = false;
+    for _ in 0..MAX_HEADER_LINES {
+      read_line(reader, &mut line)?;
+      let header = line.trim_end();
+      if header.is_empty() {
+        ended = true;
+        break;
+      }
+
+      let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| invalid("Malformed header"))?;
+      request = request.with_header(name.trim(), value.trim());
+    }
+    if !ended {
+      return Err(invalid("Too many headers"));
+    }
+
+    let length = match request.header("Content-Length") {
+      Some(length) => length
+        .parse::<usize>()
+        .map_err(|_| invalid("Malformed Content-Length"))?,
+      None => 0,
+    };
+    if length > MAX_BODY {
+      return Err(invalid("Request body too large"));
+    }
+
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+    Ok(request)
+  }
+
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn query(&self, name: &str) -> Option<&str> {
+    self
+      .query
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// The body as JSON, or `null` when there is none
+  pub fn json(&self) -> serde_json::Result<Value> {
+    if self.body.is_empty() {
+      return Ok(Value::Null);
+    }
+    serde_json::from_slice(&self.body)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+  pub status: u16,
+  pub body: Value,
+}
+
+fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    422 => "Unprocessable Entity",
+    _ => "Internal Server Error",
+  }
+}
+
+impl Response {
+  pub fn json(status: u16, body: Value) -> Self {
+    Self { status, body }
+  }
+
+  pub fn error(status: u16, message: impl Into<String>) -> Self {
+    Self::json(status, serde_json::json!({ "error": message.into() }))
+  }
+
+  pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+    let body = self.body.to_string();
+    write!(
+      writer,
+      "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+      self.status,
+      reason(self.status),
+      body.len(),
+      body
+    )?;
+    writer.flush()
+  }
+}
+
+/// Starts a `text/event-stream` response, followed by `write_event` for every event
+pub fn write_event_stream_head(writer: &mut impl Write) -> io::Result<()> {
+  write!(
+    writer,
+    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n"
+  )?;
+  writer.flush()
+}
+
+pub fn write_event(writer: &mut impl Write, name: &str, data: &Value) -> io::Result<()> {
+  write!(writer, "event: {}\ndata: {}\n\n", name, data)?;
+  writer.flush()
+}
+
+/// A comment line, so a client that went away is noticed on a quiet bus
+pub fn write_keep_alive(writer: &mut impl Write) -> io::Result<()> {
+  writer.write_all(b":\n\n")?;
+  writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read(raw: &[u8]) -> io::Result<Request> {
+    Request::read(&mut io::BufReader::new(raw))
+  }
+
+  #[test]
+  fn reads_a_request() {
+    let request = read(
+      b"PUT /config/power.timer?token=abc HTTP/1.1\r\nHost: localhost:1\r\nContent-Length: 3\r\n\r\n120",
+    )
+    .unwrap();
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/config/power.timer");
+    assert_eq!(request.query("token"), Some("abc"));
+    assert_eq!(request.header("host"), Some("localhost:1"));
+    assert_eq!(request.json().unwrap(), 120);
+  }
+
+  #[test]
+  fn refuses_an_overlong_line() {
+    let mut raw = b"GET /".to_vec();
+    raw.extend(vec![b'a'; MAX_LINE]);
+    raw.extend(b" HTTP/1.1\r\n\r\n");
+    assert_eq!(read(&raw).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let mut raw = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+    raw.extend(vec![b'a'; MAX_LINE]);
+    raw.extend(b"\r\n\r\n");
+    assert_eq!(read(&raw).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn refuses_too_many_headers() {
+    let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+    for _ in 0..=MAX_HEADER_LINES {
+      raw.extend(b"X-Padding: a\r\n");
+    }
+    raw.extend(b"\r\n");
+    assert_eq!(read(&raw).unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn refuses_a_large_body() {
+    let raw = format!(
+      "PUT /config HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+      MAX_BODY + 1
+    );
+    assert_eq!(
+      read(raw.as_bytes()).unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+  }
+}
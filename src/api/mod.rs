@@ -0,0 +1,555 @@
+pub mod client;
+pub mod http;
+
+use std::{
+  fmt,
+  io::BufReader,
+  net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{Receiver, RecvTimeoutError},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use crate::{
+  backend::{dry_run, Backend},
+  bus::{state::MachineState, Event, EventBus},
+  cli::{self, Instance},
+  config::{
+    layers::{self, LayeredConfig},
+    persist,
+    validate::{self, ConfigError, Diagnostic},
+    Config,
+  },
+  log::{debug, info, warning},
+};
+use http::{Request, Response};
+
+/// How often an idle event stream sends a comment to find out whether the client is still there
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Where the GUI finds the address and token, next to the per-user config
+const DISCOVERY_FILE: &str = "api.json";
+
+/// How long a client may take to send its request, or to take an event off the stream
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections served at once, event streams included. Later ones are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl fmt::Display for BadRequest {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for BadRequest {}
+
+fn bad_request(message: impl Into<String>) -> anyhow::Error {
+  BadRequest(message.into()).into()
+}
+
+/// A random token for one run of the app, from the system's secure generator
+pub fn generate_token() -> Result<String> {
+  let mut bytes = [0; 32];
+  getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Cannot generate the API token: {}", e))?;
+  Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Compares without stopping at the first difference, so the time taken says nothing about
+/// the token
+fn same_token(given: &str, token: &str) -> bool {
+  given.len() == token.len()
+    && given
+      .bytes()
+      .zip(token.bytes())
+      .fold(0, |diff, (a, b)| diff | (a ^ b))
+      == 0
+}
+
+/// The JSON API the external GUI talks to. Every request needs the token, either as
+/// `Authorization: Bearer <token>` or as `?token=<token>` for the event stream.
+pub struct Api {
+  token: String,
+  layers: LayeredConfig,
+  backend: Option<&'static dyn Backend>,
+  instance: Box<dyn Instance + Send + Sync>,
+  bus: &'static EventBus,
+}
+
+impl Api {
+  pub fn new(
+    token: String,
+    layers: LayeredConfig,
+    backend: Option<&'static dyn Backend>,
+    instance: Box<dyn Instance + Send + Sync>,
+    bus: &'static EventBus,
+  ) -> Self {
+    Self {
+      token,
+      layers,
+      backend,
+      instance,
+      bus,
+    }
+  }
+
+  pub fn token(&self) -> &str {
+    &self.token
+  }
+
+  pub fn is_authorized(&self, request: &Request) -> bool {
+    let given = request
+      .header("Authorization")
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .or_else(|| request.query("token"));
+    given.is_some_and(|given| same_token(given.trim(), &self.token))
+  }
+
+  /// Answers every request except the event stream
+  pub fn handle(&self, request: &Request) -> Response {
+    if !self.is_authorized(request) {
+      return Response::error(401, "Missing or wrong token");
+    }
+
+    let reason = format!("of the API request {} {}", request.method, request.path);
+    match dry_run::because(reason, || self.route(request)) {
+      Ok(Some(value)) => Response::json(200, value),
+      Ok(None) => Response::error(404, format!("No such endpoint {}", request.path)),
+      Err(e) => {
+        let status = if e.is::<BadRequest>() || cli::is_usage_error(&e) {
+          400
+        } else if e.is::<ConfigError>() {
+          422
+        } else {
+          500
+        };
+        Response::error(status, format!("{:#}", e))
+      }
+    }
+  }
+
+  /// The current machine state and every event after it, or the response refusing the stream
+  pub fn events(&self, request: &Request) -> Result<(MachineState, Receiver<Event>), Response> {
+    if !self.is_authorized(request) {
+      return Err(Response::error(401, "Missing or wrong token"));
+    }
+    Ok(self.bus.subscribe())
+  }
+
+  fn evaluate(&self, args: &[&str]) -> Result<Value> {
+    cli::evaluate(args, &self.layers, self.backend, self.instance.as_ref())
+  }
+
+  fn route(&self, request: &Request) -> Result<Option<Value>> {
+    let segments = request
+      .path
+      .trim_matches('/')
+      .split('/')
+      .collect::<Vec<_>>();
+
+    let value = match (request.method.as_str(), segments.as_slice()) {
+      ("GET", ["status"]) => {
+        let mut status = self.evaluate(&["status"])?;
+        status["startup_items"] = self.evaluate(&["startup", "list"])?;
+        status
+      }
+
+      ("GET", ["config"]) => serde_json::to_value(self.instance.config()?)?,
+      ("PUT", ["config"]) => {
+        let body = request.json().map_err(|e| bad_request(e.to_string()))?;
+        let diagnostics = validate::check_value(&body);
+        if diagnostics.iter().any(Diagnostic::is_error) {
+          return Err(ConfigError { diagnostics }.into());
+        }
+
+        let config: Config =
+          serde_json::from_value(body).map_err(|e| bad_request(e.to_string()))?;
+        let config = self.instance.update(&mut |current| {
+          *current = config.clone();
+          Ok(())
+        })?;
+        serde_json::to_value(config)?
+      }
+      ("PUT", ["config", key]) => {
+        let value = request.json().map_err(|e| bad_request(e.to_string()))?;
+        let config = self.instance.update(&mut |config| {
+          *config = layers::with_key(config, key, value.clone())?;
+          Ok(())
+        })?;
+        serde_json::to_value(config)?
+      }
+
+      ("GET", ["power", "schemes"]) => self.evaluate(&["power", "list"])?,
+      ("GET", ["audio", "devices"]) => self.evaluate(&["audio", "list"])?,
+      ("GET", ["display", "rates"]) => self.evaluate(&["display", "rates"])?,
+      ("GET", ["wifi", "networks"]) => self.evaluate(&["wifi", "scan"])?,
+      ("GET", ["startup", "items"]) => self.evaluate(&["startup", "list"])?,
+
+      ("GET", ["dry-run"]) => self.evaluate(&["dry-run"])?,
+
+      ("POST", ["actions", action]) => {
+        let body = request.json().map_err(|e| bad_request(e.to_string()))?;
+        self.action(action, &body)?
+      }
+
+      _ => return Ok(None),
+    };
+    Ok(Some(value))
+  }
+
+  fn action(&self, action: &str, body: &Value) -> Result<Value> {
+    let text = |name: &str| {
+      body[name]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| bad_request(format!("Expected a string \"{}\"", name)))
+    };
+    let flag = |name: &str| {
+      body[name]
+        .as_bool()
+        .ok_or_else(|| bad_request(format!("Expected a boolean \"{}\"", name)))
+    };
+
+    match action {
+      "power-scheme" => self.evaluate(&["power", "set", &text("scheme")?]),
+      "default-output" => self.evaluate(&["audio", "set-default", &text("device")?]),
+      "refresh-rate" => {
+        let hz = body["hz"]
+          .as_u64()
+          .ok_or_else(|| bad_request("Expected a number \"hz\""))?;
+        self.evaluate(&["display", "set", &hz.to_string()])
+      }
+      "wifi" => self.evaluate(&["wifi", if flag("on")? { "on" } else { "off" }]),
+      "startup-item" => {
+        let state = if flag("enabled")? {
+          "enable"
+        } else {
+          "disable"
+        };
+        self.evaluate(&["startup", state, &text("name")?])
+      }
+      "toggle" => self.evaluate(&["toggle", &text("feature")?]),
+      "profile" => self.evaluate(&["profile", &text("name")?]),
+      "reload" => self.evaluate(&["reload"]),
+      _ => Err(bad_request(format!("Unknown action {}", action))),
+    }
+  }
+}
+
+/// The event name and data of one server-sent event
+fn event_frame(event: &Event) -> Result<(String, Value)> {
+  let mut data = serde_json::to_value(event)?;
+  let name = data
+    .as_object_mut()
+    .and_then(|data| data.remove("type"))
+    .and_then(|name| name.as_str().map(str::to_string))
+    .ok_or_else(|| anyhow!("Event without a type"))?;
+  Ok((name, data))
+}
+
+fn stream_events(
+  stream: &mut TcpStream,
+  state: MachineState,
+  events: Receiver<Event>,
+) -> Result<()> {
+  http::write_event_stream_head(stream)?;
+  http::write_event(stream, "state", &serde_json::to_value(state)?)?;
+
+  loop {
+    let written = match events.recv_timeout(KEEP_ALIVE) {
+      Ok(event) => {
+        let (name, data) = event_frame(&event)?;
+        http::write_event(stream, &name, &data)
+      }
+      Err(RecvTimeoutError::Timeout) => http::write_keep_alive(stream),
+      Err(RecvTimeoutError::Disconnected) => return Ok(()),
+    };
+
+    // The client went away
+    if written.is_err() {
+      return Ok(());
+    }
+  }
+}
+
+fn serve(api: &Api, mut stream: TcpStream, port: u16) -> Result<()> {
+  let request = Request::read(&mut BufReader::new(&stream))?;
+
+  // A page in the browser can reach loopback too, but not with a loopback Host header
+  let host = request.header("Host").unwrap_or_default();
+  if host != format!("127.0.0.1:{}", port) && host != format!("localhost:{}", port) {
+    return Ok(Response::error(403, "Wrong host").write_to(&mut stream)?);
+  }
+
+  if request.method == "GET" && request.path == "/events" {
+    return match api.events(&request) {
+      Ok((state, events)) => stream_events(&mut stream, state, events),
+      Err(response) => Ok(response.write_to(&mut stream)?),
+    };
+  }
+
+  let response = api.handle(&request);
+  debug!("{} {} {}", request.method, request.path, response.status);
+  response.write_to(&mut stream)?;
+  Ok(())
+}
+
+pub fn discovery_path(layers: &LayeredConfig) -> Result<PathBuf> {
+  let directory = layers
+    .user
+    .parent()
+    .ok_or_else(|| anyhow!("Cannot find the config directory"))?;
+  Ok(directory.join(DISCOVERY_FILE))
+}
+
+/// Writes where the API listens and its token for the GUI to pick up, all at once so the GUI
+/// never reads half of it
+fn write_discovery(path: &Path, address: SocketAddr, token: &str) -> Result<()> {
+  if let Some(directory) = path.parent() {
+    std::fs::create_dir_all(directory)?;
+  }
+
+  let discovery = json!({ "url": format!("http://{}", address), "token": token });
+  persist::replace(path, &format!("{:#}", discovery))?;
+  Ok(())
+}
+
+/// Removes what `start` wrote for the GUI, as the token dies with the app
+pub fn remove_discovery(layers: &LayeredConfig) {
+  let Ok(path) = discovery_path(layers) else {
+    return;
+  };
+  match std::fs::remove_file(&path) {
+    Ok(()) => {}
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+    Err(e) => warning!("Cannot remove {}: {}", path.display(), e),
+  }
+}
+
+/// Listens on a free loopback port and serves `api` on its own thread.
+/// Returns the address it listens on.
+pub fn start(api: Api) -> Result<SocketAddr> {
+  let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+  let address = listener.local_addr()?;
+  write_discovery(&discovery_path(&api.layers)?, address, &api.token)?;
+
+  let api = Arc::new(api);
+  std::thread::Builder::new()
+    .name("Api_Thread".to_string())
+    .spawn(move || api_thread(listener, api))?;
+
+  Ok(address)
+}
+
+// Initialize the API thread
+fn api_thread(listener: TcpListener, api: Arc<Api>) {
+  info!("  + Running API Thread");
+
+  let port = listener.local_addr().map_or(0, |address| address.port());
+  let connections = Arc::new(AtomicUsize::new(0));
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        warning!("Cannot accept an API connection: {}", e);
+        continue;
+      }
+    };
+
+    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+      connections.fetch_sub(1, Ordering::SeqCst);
+      warning!("Too many API connections, closing a new one");
+      continue;
+    }
+
+    // A client that stops sending or reading only holds up its own thread, and not forever
+    let timeouts = stream
+      .set_read_timeout(Some(IO_TIMEOUT))
+      .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT)));
+    if let Err(e) = timeouts {
+      connections.fetch_sub(1, Ordering::SeqCst);
+      warning!("Cannot set the API connection timeouts: {}", e);
+      continue;
+    }
+
+    let api = api.clone();
+    let connections = connections.clone();
+    std::thread::spawn(move || {
+      if let Err(e) = serve(&api, stream, port) {
+        warning!("API request failed: {:#}", e);
+      }
+      connections.fetch_sub(1, Ordering::SeqCst);
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+
+  use super::*;
+  use crate::{
+    backend::fake::{FakeBackend, FakeCall},
+    config::store::ConfigStore,
+    testing::{temp_dir, Running},
+  };
+  use client::Client;
+
+  static BUS: EventBus = EventBus::new();
+
+  fn api(name: &str) -> (Api, &'static FakeBackend) {
+    let dir = temp_dir(name);
+    let layers = LayeredConfig {
+      machine: dir.join("machine.json"),
+      user: dir.join("user.json"),
+      overrides: Vec::new(),
+    };
+    let backend: &'static FakeBackend = Box::leak(Box::new(FakeBackend::new()));
+    let api = Api::new(
+      generate_token().unwrap(),
+      layers,
+      Some(backend),
+      Box::new(Running(ConfigStore::new(Config::new()))),
+      &BUS,
+    );
+    (api, backend)
+  }
+
+  #[test]
+  fn tokens_are_random() {
+    let token = generate_token().unwrap();
+    assert_eq!(token.len(), 64);
+    assert_ne!(token, generate_token().unwrap());
+  }
+
+  #[test]
+  fn a_wrong_token_is_unauthorized() {
+    let (api, _) = api("api-token");
+
+    for client in [
+      Client::with_token(&api, "wrong"),
+      Client::with_token(&api, ""),
+    ] {
+      assert_eq!(client.get("/config").status, 401);
+      assert_eq!(client.events().err().unwrap().status, 401);
+    }
+    assert_eq!(Client::new(&api).get("/config").status, 200);
+  }
+
+  #[test]
+  fn every_endpoint_is_routed() {
+    let (api, _) = api("api-routes");
+    let client = Client::new(&api);
+
+    for path in [
+      "/status",
+      "/config",
+      "/power/schemes",
+      "/audio/devices",
+      "/display/rates",
+      "/wifi/networks",
+      "/startup/items",
+      "/dry-run",
+    ] {
+      let response = client.get(path);
+      assert_eq!(response.status, 200, "{} {}", path, response.body);
+    }
+    assert!(client.events().is_ok());
+
+    assert_eq!(client.get("/nowhere").status, 404);
+    assert_eq!(client.post("/config", &json!({})).status, 404);
+  }
+
+  #[test]
+  fn actions_reach_the_backend() {
+    let (api, backend) = api("api-actions");
+    let client = Client::new(&api);
+
+    let response = client.post("/actions/power-scheme", &json!({ "scheme": "Balanced" }));
+    assert_eq!(response.status, 200, "{}", response.body);
+    let response = client.post("/actions/wifi", &json!({ "on": false }));
+    assert_eq!(response.status, 200, "{}", response.body);
+    assert_eq!(
+      backend.take_calls(),
+      [
+        FakeCall::SetActivePowerScheme("381B4222-F694-41F0-9685-FF5BB260DF2E".to_string()),
+        FakeCall::SetWifiState(false),
+      ]
+    );
+
+    assert_eq!(client.post("/actions/wifi", &json!({})).status, 400);
+    assert_eq!(client.post("/actions/nothing", &json!({})).status, 400);
+  }
+
+  #[test]
+  fn config_changes_go_to_the_running_instance() {
+    let (api, _) = api("api-config");
+    let client = Client::new(&api);
+
+    let response = client.put("/config/power.timer", &json!(120));
+    assert_eq!(response.status, 200, "{}", response.body);
+    assert_eq!(response.body["power"]["timer"], 120);
+    assert_eq!(client.get("/config").status, 200);
+    assert_eq!(client.get("/config").body["power"]["timer"], 120);
+
+    assert_eq!(
+      client.put("/config/power.timer", &json!("soon")).status,
+      422
+    );
+    assert_eq!(client.put("/config", &json!([])).status, 422);
+
+    let mut unreadable = Request::new("PUT", "/config")
+      .with_header("Authorization", &format!("Bearer {}", api.token()));
+    unreadable.body = b"{\"power\":".to_vec();
+    assert_eq!(api.handle(&unreadable).status, 400);
+  }
+
+  fn send(address: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+  }
+
+  #[test]
+  fn only_loopback_hosts_are_served() {
+    let (api, _) = api("api-host");
+    let token = api.token().to_string();
+    let discovery = discovery_path(&api.layers).unwrap();
+    let layers = api.layers.clone();
+    let address = start(api).unwrap();
+
+    let written: Value =
+      serde_json::from_str(&std::fs::read_to_string(&discovery).unwrap()).unwrap();
+    assert_eq!(written["token"], token.as_str());
+    assert_eq!(written["url"], format!("http://{}", address));
+
+    let request = |host: &str| {
+      format!(
+        "GET /config HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\r\n",
+        host, token
+      )
+    };
+    let response = send(
+      address,
+      &request(&format!("evil.example:{}", address.port())),
+    );
+    assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+    let response = send(address, &request(&format!("localhost:{}", address.port())));
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+    remove_discovery(&layers);
+    assert!(!discovery.exists());
+  }
+}
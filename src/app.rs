@@ -0,0 +1,1027 @@
+use crate::{
+  api::{self, Api},
+  backend::{
+    dry_run::{self, DryRun, Planned},
+    journal::Journal,
+    types::BatteryPresence,
+    win32::Win32Backend,
+    ClockBackend, DisplayBackend, PowerBackend,
+  },
+  battery::{format_duration, history_path, History, Reading},
+  bus::{
+    sampler::{Sampler, Source, SAMPLE_INTERVAL},
+    state::MachineState,
+    Event, EventBus,
+  },
+  cli::{self, Instance},
+  config::{
+    layers::LayeredConfig,
+    store::ConfigStore,
+    validate,
+    watch::{reload, ConfigWatcher},
+    Config,
+  },
+  ipc,
+  log::{error, info, log_path, warning, Level, LEVELS, LOGGER},
+  mods::{display::get_current_frequency, media, startup::task_scheduler::TaskScheduler},
+  replay::Recorder,
+  rules::{
+    reads,
+    runtime::{runtime_path, unix_now, RuntimeState},
+    schemes, RuleEngine,
+  },
+  supervisor::{
+    module::{Modules, WorkerModule},
+    Supervisor, Worker, STOP_CHECK,
+  },
+};
+
+use anyhow::{anyhow, Result};
+use std::{
+  mem::MaybeUninit,
+  path::{Path, PathBuf},
+  sync::{
+    mpsc::{RecvTimeoutError, Sender},
+    OnceLock,
+  },
+  time::{Duration, Instant},
+};
+use trayicon::{MenuBuilder, TrayIcon, TrayIconBuilder};
+use windows::{
+  core::{w, HSTRING},
+  Win32::{
+    Foundation::{CloseHandle, HANDLE, HWND, TRUE},
+    Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+    System::{
+      Console::{AttachConsole, ATTACH_PARENT_PROCESS},
+      Threading::{GetCurrentProcess, OpenProcessToken},
+    },
+    UI::WindowsAndMessaging::{
+      DispatchMessageW, GetMessageW, MessageBoxW, TranslateMessage, MB_ICONERROR, MB_OK,
+      MB_SYSTEMMODAL,
+    },
+  },
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Events {
+  LeftClickTrayIcon,
+
+  Startup,
+
+  Profile(usize),
+
+  Discord,
+  Power,
+  Ethernet,
+  Taskbar,
+  DryRun,
+
+  TurnOffMonitor,
+  RefreshRate,
+
+  CheckConfig,
+  ConfigReloaded,
+  LogLevel(Level),
+
+  Workers,
+  WorkersChanged,
+
+  BatteryChanged,
+  RevertAll,
+  Exit,
+}
+
+static CONFIG: ConfigStore = ConfigStore::new(Config::new());
+static LAYERS: OnceLock<LayeredConfig> = OnceLock::new();
+static BUS: EventBus = EventBus::new();
+static SUPERVISOR: Supervisor = Supervisor::new();
+static BACKEND: DryRun<Journal<Win32Backend>> = DryRun::new(Journal::new(Win32Backend));
+
+/// Why a dry run would have changed something picked from the menu
+const TRAY_REASON: &str = "of the tray menu";
+
+/// How long the workers get to finish on exit
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Sampled no matter what, every other source belongs to a module
+const CORE_SOURCES: [Source; 4] = [Source::Power, Source::Processes, Source::Cpu, Source::Clock];
+
+/// The workers that only run while some enabled rule needs what they sample
+fn modules() -> Modules {
+  let mut modules = Modules::new();
+  modules.add(WorkerModule::new(
+    "Media_Thread",
+    &SUPERVISOR,
+    |config| reads(config, Source::Audio),
+    media_thread,
+  ));
+  modules.add(WorkerModule::new(
+    "Network_Thread",
+    &SUPERVISOR,
+    |config| reads(config, Source::Network),
+    network_thread,
+  ));
+  modules.add(WorkerModule::new(
+    "Window_Thread",
+    &SUPERVISOR,
+    |config| reads(config, Source::Windows),
+    window_thread,
+  ));
+  modules
+}
+
+fn setup_tray_icon_menu(tray_icon: &mut trayicon::TrayIcon<Events>) -> Result<()> {
+  let config = CONFIG.snapshot();
+  let degraded = SUPERVISOR.is_degraded();
+
+  let profiles = config
+    .profile_names()
+    .iter()
+    .enumerate()
+    .fold(MenuBuilder::new(), |menu, (i, name)| {
+      menu.checkable(name, *name == config.profile, Events::Profile(i))
+    });
+
+  let log_levels = LEVELS.iter().fold(MenuBuilder::new(), |menu, level| {
+    menu.checkable(
+      level.as_str(),
+      *level == config.log.level,
+      Events::LogLevel(*level),
+    )
+  });
+
+  let workers = SUPERVISOR
+    .statuses()
+    .iter()
+    .fold(MenuBuilder::new(), |menu, status| {
+      menu.item(&status.to_string(), Events::Workers)
+    });
+
+  tray_icon
+    .set_menu(
+      &MenuBuilder::new()
+        .checkable("Run with Windows", config.startup, Events::Startup)
+        .separator()
+        .submenu(format!("Profile: {}", config.profile).as_str(), profiles)
+        .separator()
+        .checkable("Microphone", config.microphone.enabled, Events::Discord)
+        .checkable("Power", config.power.enabled, Events::Power)
+        .checkable("Ethernet", config.ethernet, Events::Ethernet)
+        .checkable("Taskbar", config.taskbar.enabled, Events::Taskbar)
+        .checkable("Dry run", config.dry_run, Events::DryRun)
+        .separator()
+        .item("Turn off monitor", Events::TurnOffMonitor)
+        .item(
+          format!("Refresh Rate: {} Hz", get_current_frequency()).as_str(),
+          Events::RefreshRate,
+        )
+        .separator()
+        .item("Check config", Events::CheckConfig)
+        .submenu(
+          format!("Log level: {}", config.log.level).as_str(),
+          log_levels,
+        )
+        .when(|menu| {
+          if degraded {
+            menu.submenu("Degraded", workers)
+          } else {
+            menu
+          }
+        })
+        .item("Revert all", Events::RevertAll)
+        .item("Exit", Events::Exit),
+    )
+    .unwrap();
+
+  tray_icon
+    .set_tooltip(&tooltip(degraded, &BUS.state()))
+    .unwrap();
+
+  if let Some(layers) = LAYERS.get() {
+    if let Err(e) = layers.write(&config) {
+      warning!("Cannot save the config: {:#}", e);
+    }
+  }
+
+  Ok(())
+}
+
+/// The app name, then the battery level and how long it lasts at the current rate
+fn tooltip(degraded: bool, machine: &MachineState) -> String {
+  let mut tooltip = if degraded {
+    "Pwcca Auto (degraded)".to_string()
+  } else {
+    "Pwcca Auto".to_string()
+  };
+
+  let left = match (machine.minutes_to_empty, machine.minutes_to_full) {
+    (Some(minutes), _) if !machine.plugged_in => {
+      format!(", {} left", format_duration(minutes_duration(minutes)))
+    }
+    (_, Some(minutes)) if machine.plugged_in => {
+      format!(", full in {}", format_duration(minutes_duration(minutes)))
+    }
+    _ => String::new(),
+  };
+  match machine.battery {
+    BatteryPresence::Present => tooltip.push_str(&format!(
+      "\nBattery: {}%{}",
+      machine.battery_percentage, left
+    )),
+    BatteryPresence::Unknown => tooltip.push_str("\nBattery: unknown"),
+    BatteryPresence::Absent => {}
+  }
+  tooltip
+}
+
+fn minutes_duration(minutes: u32) -> Duration {
+  Duration::from_secs(minutes as u64 * 60)
+}
+
+pub fn attach_console() {
+  let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+fn show_message(text: &str, caption: &str) {
+  unsafe {
+    MessageBoxW(
+      HWND::default(),
+      &HSTRING::from(text),
+      &HSTRING::from(caption),
+      MB_SYSTEMMODAL | MB_OK,
+    )
+  };
+}
+
+fn show_error(text: &str) {
+  unsafe {
+    MessageBoxW(
+      HWND::default(),
+      &HSTRING::from(text),
+      w!("Error"),
+      MB_SYSTEMMODAL | MB_ICONERROR | MB_OK,
+    )
+  };
+}
+
+fn is_elevated() -> Result<bool> {
+  let mut elevated = false;
+  let mut token_handle = HANDLE::default();
+
+  unsafe {
+    if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token_handle).is_ok() {
+      let mut elevation = TOKEN_ELEVATION::default();
+      let size = std::mem::size_of_val(&elevation) as u32;
+      let mut returnlength = 0;
+
+      if GetTokenInformation(
+        token_handle,
+        TokenElevation,
+        Some(&mut elevation as *mut _ as *mut _),
+        size,
+        &mut returnlength,
+      )
+      .is_ok()
+      {
+        elevated = elevation.TokenIsElevated != 0;
+      }
+    };
+
+    let _ = CloseHandle(token_handle);
+  };
+
+  Ok(elevated)
+}
+
+/// Applies the settings that belong to the whole app rather than a worker
+fn configure(config: &Config) {
+  LOGGER.configure(config.log.level, &config.log.targets);
+  BACKEND.set_enabled(config.dry_run);
+}
+
+/// The tray app as seen by commands forwarded from a later launch
+struct Running {
+  layers: &'static LayeredConfig,
+  sender: Sender<Events>,
+}
+
+impl Instance for Running {
+  fn config(&self) -> Result<Config> {
+    Ok(CONFIG.snapshot())
+  }
+
+  fn update(&self, f: &mut dyn FnMut(&mut Config) -> Result<()>) -> Result<Config> {
+    let mut result = Ok(());
+    let config = CONFIG.update(|config| {
+      let mut updated = config.clone();
+      result = f(&mut updated).and_then(|_| self.layers.check_overrides(&updated));
+      if result.is_ok() {
+        *config = updated;
+      }
+    });
+    result?;
+
+    // The tray saves the config when it rebuilds the menu
+    self.sender.send(Events::ConfigReloaded)?;
+    Ok(config)
+  }
+
+  fn reload(&self) -> Result<bool> {
+    let changed = reload(&CONFIG, self.layers)?;
+    if changed {
+      self.sender.send(Events::ConfigReloaded)?;
+    }
+    Ok(changed)
+  }
+  fn planned(&self) -> Result<Vec<Planned>> {
+    Ok(BACKEND.planned())
+  }
+}
+
+pub fn run(layers: LayeredConfig, record: Option<PathBuf>) -> Result<()> {
+  // Check if another instance is running, claiming the control channel if not
+  let listener = match ipc::listen() {
+    Ok(listener) => Some(listener),
+    Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+      unsafe {
+        MessageBoxW(
+          HWND::default(),
+          w!("Another instance is already running"),
+          w!("Error"),
+          MB_SYSTEMMODAL | MB_ICONERROR | MB_OK,
+        )
+      };
+
+      std::process::exit(1);
+    }
+    Err(e) => {
+      warning!("Cannot listen for commands: {}", e);
+      None
+    }
+  };
+
+  // Check if the process is elevated
+  if !is_elevated()? {
+    unsafe {
+      MessageBoxW(
+        HWND::default(),
+        w!("This application requires administrator privileges"),
+        w!("Error"),
+        MB_SYSTEMMODAL | MB_ICONERROR | MB_OK,
+      )
+    };
+
+    std::process::exit(1);
+  }
+
+  // Main application starts here
+  let layers = LAYERS.get_or_init(|| layers);
+  match layers.read() {
+    Ok(config) => CONFIG.replace(config),
+    Err(e) => {
+      show_error(&format!("{:#}", e));
+      std::process::exit(1);
+    }
+  };
+
+  let log = log_path(&layers.user);
+  if let Err(e) = LOGGER.open(&log) {
+    show_error(&format!(
+      "Cannot write the log to {}: {:#}",
+      log.display(),
+      e
+    ));
+  }
+  configure(&CONFIG.snapshot());
+
+  info!("Running Pwcca Auto");
+
+  let (sender, receiver) = std::sync::mpsc::channel::<Events>();
+  let config_sender = sender.clone();
+  let workers_sender = sender.clone();
+  let control_sender = sender.clone();
+  let api_sender = sender.clone();
+  let battery_sender = sender.clone();
+
+  // Tray icon
+  let mut tray_icon = TrayIconBuilder::new()
+    .sender(move |e| sender.send(*e).unwrap())
+    .icon_from_buffer(include_bytes!("../res/icon.ico"))
+    .tooltip("Pwcca Auto")
+    .on_click(Events::LeftClickTrayIcon)
+    .build()
+    .unwrap();
+
+  setup_tray_icon_menu(&mut tray_icon)?;
+
+  // The first sample is published before anyone reacts to the machine state
+  for event in Sampler::default().sample(&BACKEND) {
+    BUS.publish(event);
+  }
+
+  // Threading
+  SUPERVISOR.spawn("Sampler_Thread", sampler_thread)?;
+  SUPERVISOR.spawn("Rules_Thread", rules_thread)?;
+  SUPERVISOR.spawn("Modules_Thread", modules_thread)?;
+  let history = history_path(&layers.user);
+  SUPERVISOR.spawn("Battery_Thread", move |worker| {
+    battery_thread(worker, &history, battery_sender.clone())
+  })?;
+  SUPERVISOR.spawn("Config_Thread", move |worker| {
+    config_thread(worker, config_sender.clone())
+  })?;
+  if let Some(path) = record {
+    SUPERVISOR.spawn("Recorder_Thread", move |worker| {
+      recorder_thread(worker, &path)
+    })?;
+  }
+
+  // The tray owns the icon and cannot be restarted, it reports on the others instead
+  let workers = SUPERVISOR.subscribe();
+  let _ = std::thread::Builder::new()
+    .name("Workers_Thread".to_string())
+    .spawn(move || {
+      for _ in workers {
+        let _ = workers_sender.send(Events::WorkersChanged);
+      }
+    });
+  if let Some(listener) = listener {
+    let _ = std::thread::Builder::new()
+      .name("Control_Thread".to_string())
+      .spawn(move || control_thread(listener, control_sender));
+  }
+  let served = api::generate_token().and_then(|token| {
+    api::start(Api::new(
+      token,
+      layers.clone(),
+      Some(&BACKEND),
+      Box::new(Running {
+        layers,
+        sender: api_sender,
+      }),
+      &BUS,
+    ))
+  });
+  match served {
+    Ok(address) => info!("Serving the API on http://{}", address),
+    Err(e) => error!("Cannot serve the API: {:#}", e),
+  }
+  let _ = std::thread::Builder::new()
+    .name("Tray_Thread".to_string())
+    .spawn(move || tray_thread(receiver, tray_icon));
+
+  // Application loop
+  loop {
+    unsafe {
+      let mut msg = MaybeUninit::uninit();
+      let bret = GetMessageW(msg.as_mut_ptr(), None, 0, 0);
+      if bret == TRUE {
+        let _ = TranslateMessage(msg.as_ptr());
+        DispatchMessageW(msg.as_ptr());
+      } else {
+        break;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Applies a change from the tray menu, unless it changes a value an override sets
+fn change(f: impl FnOnce(&mut Config)) -> Config {
+  let mut blocked = None;
+  let config = CONFIG.update(|config| {
+    let mut updated = config.clone();
+    f(&mut updated);
+    match LAYERS.get().map(|layers| layers.check_overrides(&updated)) {
+      Some(Err(e)) => blocked = Some(e),
+      _ => *config = updated,
+    }
+  });
+
+  if let Some(e) = blocked {
+    warning!("{:#}", e);
+    show_message(&format!("{:#}", e), "Pwcca Auto");
+  }
+  config
+}
+
+fn tray_thread(receiver: std::sync::mpsc::Receiver<Events>, mut tray_icon: TrayIcon<Events>) {
+  // Initialize the tray thread
+  info!("  + Running Tray Thread");
+
+  let task_scheduler = TaskScheduler::new().expect("Cannot construct task scheduler");
+  let mut checked_schemes = None;
+  check_power_schemes(&CONFIG.snapshot(), &mut checked_schemes);
+
+  receiver.iter().for_each(|m| match m {
+    Events::LeftClickTrayIcon => {
+      tray_icon.show_menu().unwrap();
+    }
+    Events::Startup => {
+      if change(|config| config.toggle_startup()).startup {
+        let _ = task_scheduler.create_startup_task("PwccaAuto");
+      } else {
+        let _ = task_scheduler.delete_startup_task("PwccaAuto");
+      }
+
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Profile(index) => {
+      let config = change(|config| {
+        if let Some(name) = config.profile_names().get(index) {
+          if let Err(e) = config.switch_profile(name) {
+            error!("Cannot switch profile: {:#}", e);
+          }
+        }
+      });
+      check_power_schemes(&config, &mut checked_schemes);
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Discord => {
+      change(|config| config.toggle_microphone());
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Power => {
+      let config = change(|config| config.toggle_power());
+      check_power_schemes(&config, &mut checked_schemes);
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Ethernet => {
+      change(|config| config.toggle_ethernet());
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Taskbar => {
+      change(|config| config.toggle_taskbar());
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::DryRun => {
+      change(|config| config.toggle_dry_run());
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::TurnOffMonitor => {
+      let _ = dry_run::because(TRAY_REASON, || BACKEND.turn_off_monitor());
+    }
+    Events::RefreshRate => {
+      let refresh_rate = BACKEND.refresh_rate();
+      let max_refresh_rate = BACKEND.refresh_rates().map(|rates| rates.last().copied());
+      if let (Ok(refresh_rate), Ok(Some(max_refresh_rate))) = (refresh_rate, max_refresh_rate) {
+        let _ = dry_run::because(TRAY_REASON, || {
+          BACKEND.set_refresh_rate(if refresh_rate == 60 {
+            max_refresh_rate
+          } else {
+            60
+          })
+        });
+      }
+
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::CheckConfig => {
+      if let Some(Ok(checked)) = LAYERS.get().map(LayeredConfig::check_files) {
+        let report = checked
+          .iter()
+          .map(|(path, diagnostics)| validate::report(path, diagnostics))
+          .collect::<Vec<_>>()
+          .join("\n\n");
+        show_message(&report, "Config");
+      }
+    }
+    Events::LogLevel(level) => {
+      change(|config| config.set_log_level(level));
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::ConfigReloaded => {
+      check_power_schemes(&CONFIG.snapshot(), &mut checked_schemes);
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::WorkersChanged => {
+      let _ = setup_tray_icon_menu(&mut tray_icon);
+    }
+    Events::Workers => {}
+    Events::BatteryChanged => {
+      let _ = tray_icon.set_tooltip(&tooltip(SUPERVISOR.is_degraded(), &BUS.state()));
+    }
+    Events::RevertAll => {
+      let failures = revert_all();
+      if failures.is_empty() {
+        show_message("Everything the app changed was restored", "Revert all");
+      } else {
+        show_message(&failures.join("\n"), "Revert all");
+      }
+    }
+    Events::Exit => {
+      for name in SUPERVISOR.stop(STOP_TIMEOUT) {
+        warning!("{} did not stop in time", name);
+      }
+
+      let failures = revert_all();
+      if !failures.is_empty() {
+        show_message(&failures.join("\n"), "Pwcca Auto");
+      }
+      if let Some(layers) = LAYERS.get() {
+        api::remove_discovery(layers);
+      }
+      std::process::exit(0)
+    }
+  });
+}
+
+/// Blocks on the next connection, so it is not stopped with the workers but ends with the
+/// process instead
+fn control_thread(listener: ipc::Listener, sender: Sender<Events>) -> Result<()> {
+  // Initialize the control thread
+  info!("  + Running Control Thread");
+
+  let layers = LAYERS
+    .get()
+    .ok_or_else(|| anyhow!("The config layers are not set"))?;
+  let instance = Running { layers, sender };
+
+  ipc::serve_all(listener, move |request| {
+    info!("Forwarded command: {}", request.args.join(" "));
+    let (mut output, planned) = BACKEND.capture(|| {
+      dry_run::because(cli::reason(&request.args), || {
+        cli::execute(&request.args, layers, Some(&BACKEND), &instance)
+      })
+    });
+    output.note_planned(&planned);
+    output
+  })
+}
+
+fn config_thread(worker: &Worker, sender: Sender<Events>) -> Result<()> {
+  // Initialize the config thread
+  info!("  + Running Config Thread");
+
+  let layers = LAYERS
+    .get()
+    .ok_or_else(|| anyhow!("The config layers are not set"))?;
+  let mut watchers = layers
+    .files()
+    .map(|(_, path)| ConfigWatcher::new(path.to_path_buf()));
+
+  let changes = CONFIG.subscribe();
+
+  while !worker.is_stopping() {
+    let changed = watchers
+      .iter_mut()
+      .filter_map(|watcher| watcher.poll().then(|| watcher.path().display().to_string()))
+      .collect::<Vec<_>>();
+
+    if !changed.is_empty() {
+      match reload(&CONFIG, layers) {
+        Ok(true) => {
+          info!("Reloaded {}", changed.join(", "));
+          sender.send(Events::ConfigReloaded)?;
+        }
+        Ok(false) => {}
+        Err(e) => error!("Keeping the previous config: {:#}", e),
+      }
+    }
+
+    // Every change, from the tray or a reload, goes out on the bus until the next file check
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+      match changes.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(config) => {
+          configure(&config);
+          BUS.publish(Event::ConfigChanged(Box::new(config)));
+        }
+        Err(RecvTimeoutError::Timeout) => break,
+        Err(RecvTimeoutError::Disconnected) => {
+          return Err(anyhow::Error::msg("Config store dropped"))
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Publishes whatever changes in `sources` until the worker is stopped
+fn sample(worker: &Worker, sources: &[Source]) -> Result<()> {
+  // A restarted sampler carries on from what the bus already knows
+  let mut sampler = Sampler::with_sources(BUS.state(), sources);
+
+  while !worker.wait(SAMPLE_INTERVAL) {
+    for event in sampler.sample(&BACKEND) {
+      BUS.publish(event);
+    }
+  }
+
+  Ok(())
+}
+
+fn sampler_thread(worker: &Worker) -> Result<()> {
+  // Initialize the sampler thread
+  info!("  + Running Sampler Thread");
+
+  sample(worker, &CORE_SOURCES)
+}
+
+fn media_thread(worker: &Worker) -> Result<()> {
+  // Initialize the media thread
+  info!("  + Running Media Thread");
+
+  let result = sample(worker, &[Source::Audio]);
+
+  // The backend initialized COM on this thread with the first read
+  media::uninit();
+  result
+}
+
+fn network_thread(worker: &Worker) -> Result<()> {
+  // Initialize the network thread
+  info!("  + Running Network Thread");
+
+  sample(worker, &[Source::Network])
+}
+
+fn window_thread(worker: &Worker) -> Result<()> {
+  // Initialize the window thread
+  info!("  + Running Window Thread");
+
+  sample(worker, &[Source::Windows])
+}
+
+fn modules_thread(worker: &Worker) -> Result<()> {
+  // Initialize the modules thread
+  info!("  + Running Modules Thread");
+
+  let mut modules = modules();
+  let changes = CONFIG.subscribe();
+  let mut config = CONFIG.snapshot();
+  modules.apply(&config);
+
+  while !worker.is_stopping() {
+    match changes.recv_timeout(STOP_CHECK) {
+      Ok(changed) => {
+        config = changed;
+        modules.apply(&config);
+      }
+      // Catches up with modules that were still stopping or failed to start
+      Err(RecvTimeoutError::Timeout) => modules.apply(&config),
+      Err(RecvTimeoutError::Disconnected) => {
+        return Err(anyhow::Error::msg("Config store dropped"))
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Appends the machine state to a recording for `--replay` every time it changes
+fn recorder_thread(worker: &Worker, path: &Path) -> Result<()> {
+  // Initialize the recorder thread
+  info!("  + Running Recorder Thread");
+
+  let mut recorder = Recorder::open(path)?;
+  let (mut machine, events) = BUS.subscribe();
+  recorder.record(&machine)?;
+
+  while !worker.is_stopping() {
+    match events.recv_timeout(STOP_CHECK) {
+      Ok(event) => {
+        // Everything queued up is one snapshot
+        for event in std::iter::once(event).chain(events.try_iter()) {
+          machine.apply(&event);
+        }
+        recorder.record(&machine)?;
+      }
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return Err(anyhow::Error::msg("Event bus dropped")),
+    }
+  }
+
+  Ok(())
+}
+
+/// Appends the battery level to its history every time it changes and publishes how fast it
+/// drains or charges, as the firmware estimate jumps around too much to act on
+fn battery_thread(worker: &Worker, path: &Path, sender: Sender<Events>) -> Result<()> {
+  // Initialize the battery thread
+  info!("  + Running Battery Thread");
+
+  let mut history = History::open(path)?;
+  let (mut machine, events) = BUS.subscribe();
+  let mut published = None;
+  let mut presence = None;
+
+  while !worker.is_stopping() {
+    let now = unix_now().as_secs();
+    let recorded = machine.has_battery()
+      && history.record(Reading {
+        at: now,
+        percentage: machine.battery_percentage,
+        plugged_in: machine.plugged_in,
+      })?;
+
+    // The estimate moves with the clock too, as the level holds for longer than expected
+    let estimate = history.estimate(now).filter(|_| machine.has_battery());
+    let event = Event::BatteryEstimateChanged {
+      drain_rate: estimate.and_then(|estimate| estimate.drain_rate()),
+      minutes_to_empty: estimate
+        .and_then(|estimate| estimate.time_to_empty)
+        .map(|left| (left.as_secs() / 60) as u32),
+      minutes_to_full: estimate
+        .and_then(|estimate| estimate.time_to_full)
+        .map(|left| (left.as_secs() / 60) as u32),
+    };
+    let changed = published.as_ref() != Some(&event);
+    if changed {
+      BUS.publish(event.clone());
+      published = Some(event);
+    }
+    let presence_changed = presence.replace(machine.battery) != Some(machine.battery);
+    if recorded || changed || presence_changed {
+      sender.send(Events::BatteryChanged)?;
+    }
+
+    match events.recv_timeout(STOP_CHECK) {
+      Ok(event) => {
+        for event in std::iter::once(event).chain(events.try_iter()) {
+          machine.apply(&event);
+        }
+      }
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return Err(anyhow::Error::msg("Event bus dropped")),
+    }
+  }
+
+  Ok(())
+}
+
+fn rules_thread(worker: &Worker) -> Result<()> {
+  // Initialize the rules thread
+  info!("  + Running Rules Thread");
+
+  let mut engine = RuleEngine::new();
+  let start = Instant::now();
+
+  let (mut machine, events) = BUS.subscribe();
+  let mut config = CONFIG.snapshot();
+
+  let path = LAYERS.get().map(|layers| runtime_path(&layers.user));
+  let boot_time = BACKEND
+    .boot_time()
+    .map_err(|e| warning!("Cannot get the boot time: {:#}", e))
+    .ok();
+
+  engine.configure(&with_power_schemes(&config));
+  if let Some(path) = &path {
+    restore_runtime(&mut engine, path, boot_time, start.elapsed());
+  }
+  let mut saved = None;
+
+  while !worker.is_stopping() {
+    for firing in engine.tick(&machine, &BACKEND, start.elapsed()) {
+      let edge = if firing.entered { "fired" } else { "exited" };
+      info!("Rule {} {}: {:?}", firing.rule, edge, firing.actions);
+      for error in firing.errors {
+        error!("Rule {} action failed: {}", firing.rule, error);
+      }
+    }
+
+    if let Some(path) = &path {
+      save_runtime(&engine, path, boot_time, start.elapsed(), &mut saved);
+    }
+
+    // Nothing changes without an event, except for a running battery timer
+    let timeout = engine
+      .next_deadline(start.elapsed())
+      .map_or(STOP_CHECK, |deadline| deadline.min(STOP_CHECK));
+    let event = match events.recv_timeout(timeout) {
+      Ok(event) => Some(event),
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => return Err(anyhow::Error::msg("Event bus dropped")),
+    };
+
+    // Everything queued up is applied before the rules run again
+    for event in event.into_iter().chain(events.try_iter()) {
+      match event {
+        Event::ConfigChanged(new_config) => {
+          config = *new_config;
+          engine.configure(&with_power_schemes(&config));
+        }
+        Event::ChangesReverted => engine.reset(),
+        event => machine.apply(&event),
+      }
+    }
+  }
+
+  if let Some(path) = &path {
+    saved = None;
+    save_runtime(&engine, path, boot_time, start.elapsed(), &mut saved);
+  }
+
+  Ok(())
+}
+
+/// `config` with the power schemes it names replaced by those that exist on this machine
+fn with_power_schemes(config: &Config) -> Config {
+  match BACKEND.power_schemes() {
+    Ok(schemes) => schemes::with_schemes(config, &schemes),
+    Err(e) => {
+      error!("Cannot list the power schemes: {:#}", e);
+      config.clone()
+    }
+  }
+}
+
+/// Reports power schemes that cannot be used, before a rule needs them. Only checks while power
+/// saving is on and the schemes differ from `checked`, so a problem is reported once rather than
+/// on every reload.
+fn check_power_schemes(config: &Config, checked: &mut Option<(String, String)>) {
+  let power = &config.power;
+  let schemes = power
+    .enabled
+    .then(|| (power.battery_scheme.clone(), power.ac_scheme.clone()));
+  if schemes.is_none() || schemes == *checked {
+    *checked = schemes;
+    return;
+  }
+  *checked = schemes;
+
+  let checked = BACKEND
+    .power_schemes()
+    .and_then(|schemes| schemes::resolve_power(&config.power, &schemes));
+
+  if let Err(e) = checked {
+    error!("{:#}", e);
+    show_error(&format!("{:#}", e));
+  }
+}
+
+/// How often the runtime state is saved while nothing changes, so a restart can tell how long
+/// the app was gone
+const RUNTIME_HEARTBEAT: Duration = Duration::from_secs(60);
+
+fn restore_runtime(engine: &mut RuleEngine, path: &Path, boot_time: Option<u64>, now: Duration) {
+  let state = match RuntimeState::load(path) {
+    Ok(Some(state)) => state,
+    Ok(None) => return,
+    Err(e) => {
+      warning!("Cannot restore the runtime state: {:#}", e);
+      return;
+    }
+  };
+
+  // Changed settings stay changed through a reboot, unlike the counters
+  BACKEND.inner().set_originals(state.originals.clone());
+
+  let unix_now = unix_now().as_secs();
+  match state.stale_because(boot_time, unix_now) {
+    Some(reason) => info!("Ignoring the saved runtime state, {}", reason),
+    None => {
+      engine.restore(&state, now, unix_now);
+      info!(
+        "Restored the runtime state, engaged rules: {:?}, on battery since: {:?}",
+        engine.engaged(),
+        state.on_battery_since
+      );
+    }
+  }
+}
+
+/// Saves the runtime state when it changed, or when the last save is older than
+/// `RUNTIME_HEARTBEAT`
+fn save_runtime(
+  engine: &RuleEngine,
+  path: &Path,
+  boot_time: Option<u64>,
+  now: Duration,
+  saved: &mut Option<(RuntimeState, Duration)>,
+) {
+  let mut state = engine.runtime_state(now, unix_now(), boot_time);
+  state.originals = BACKEND.inner().originals();
+
+  if let Some((last, at)) = saved.as_ref() {
+    let unchanged = RuntimeState {
+      saved_at: last.saved_at,
+      ..state.clone()
+    } == *last;
+    if unchanged && now.saturating_sub(*at) < RUNTIME_HEARTBEAT {
+      return;
+    }
+  }
+
+  if let Err(e) = state.save(path) {
+    error!("Cannot save the runtime state: {:#}", e);
+  }
+  *saved = Some((state, now));
+}
+
+/// Puts back every setting the app changed, returning what could not be restored
+fn revert_all() -> Vec<String> {
+  let failures = BACKEND.inner().revert_all();
+  BUS.publish(Event::ChangesReverted);
+
+  if let Some(layers) = LAYERS.get() {
+    if let Err(e) = RuntimeState::forget_changes(&runtime_path(&layers.user)) {
+      error!("Cannot save the runtime state: {:#}", e);
+    }
+  }
+  failures
+}
@@ -0,0 +1,292 @@
+use std::{
+  cell::RefCell,
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+  },
+  time::SystemTime,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+  types::{
+    AudioDevice, CpuTimes, DeviceType, LocalTime, PowerScheme, StartupItem, SystemPowerStatus,
+    WifiNetwork,
+  },
+  AudioBackend, ClockBackend, DisplayBackend, NetworkBackend, PowerBackend, ProcessBackend,
+  StartupBackend, WindowBackend,
+};
+use crate::log::{info, timestamp};
+
+/// Older planned actions are dropped past this many
+pub const MAX_PLANNED: usize = 200;
+
+/// Why a change is made when nobody said
+const NO_REASON: &str = "it was requested directly";
+
+thread_local! {
+  static REASON: RefCell<Option<String>> = const { RefCell::new(None) };
+  static CAPTURED: RefCell<Option<Vec<Planned>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `reason` attached to every change a dry run records on this thread
+pub fn because<T>(reason: impl Into<String>, f: impl FnOnce() -> T) -> T {
+  let previous = REASON.with(|current| current.replace(Some(reason.into())));
+  let result = f();
+  REASON.with(|current| *current.borrow_mut() = previous);
+  result
+}
+
+fn reason() -> String {
+  REASON.with(|current| {
+    current
+      .borrow()
+      .clone()
+      .unwrap_or_else(|| NO_REASON.to_string())
+  })
+}
+
+/// A change a dry run kept from happening
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Planned {
+  pub time: String,
+  pub action: String,
+  pub reason: String,
+}
+
+impl std::fmt::Display for Planned {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Would {} because {}", self.action, self.reason)
+  }
+}
+
+/// Passes everything to `inner`, except that every change is only recorded while the dry run
+/// is enabled
+pub struct DryRun<B> {
+  inner: B,
+  enabled: AtomicBool,
+  planned: Mutex<VecDeque<Planned>>,
+}
+
+impl<B> DryRun<B> {
+  pub const fn new(inner: B) -> Self {
+    Self {
+      inner,
+      enabled: AtomicBool::new(false),
+      planned: Mutex::new(VecDeque::new()),
+    }
+  }
+
+  pub fn inner(&self) -> &B {
+    &self.inner
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled.load(Ordering::Relaxed)
+  }
+
+  pub fn set_enabled(&self, enabled: bool) {
+    self.enabled.store(enabled, Ordering::Relaxed);
+  }
+
+  /// What the dry run kept from happening, oldest first
+  pub fn planned(&self) -> Vec<Planned> {
+    self.planned.lock().unwrap().iter().cloned().collect()
+  }
+
+  /// Runs `f` and returns what the dry run kept it from doing on this thread
+  pub fn capture<T>(&self, f: impl FnOnce() -> T) -> (T, Vec<Planned>) {
+    let previous = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = f();
+    let planned = CAPTURED.with(|captured| captured.replace(previous));
+    (result, planned.unwrap_or_default())
+  }
+
+  /// Runs `change` on the real backend, or records `action` instead during a dry run
+  fn change(
+    &self,
+    action: impl FnOnce() -> String,
+    change: impl FnOnce(&B) -> Result<()>,
+  ) -> Result<()> {
+    if !self.is_enabled() {
+      return change(&self.inner);
+    }
+
+    let planned = Planned {
+      time: timestamp(SystemTime::now()),
+      action: action(),
+      reason: reason(),
+    };
+    info!("{}", planned);
+    CAPTURED.with(|captured| {
+      if let Some(captured) = captured.borrow_mut().as_mut() {
+        captured.push(planned.clone());
+      }
+    });
+
+    let mut all = self.planned.lock().unwrap();
+    if all.len() == MAX_PLANNED {
+      all.pop_front();
+    }
+    all.push_back(planned);
+    Ok(())
+  }
+}
+
+fn on_off(on: bool) -> &'static str {
+  if on {
+    "on"
+  } else {
+    "off"
+  }
+}
+
+impl<B: PowerBackend> PowerBackend for DryRun<B> {
+  fn power_status(&self) -> Result<SystemPowerStatus> {
+    self.inner.power_status()
+  }
+
+  fn power_schemes(&self) -> Result<Vec<PowerScheme>> {
+    self.inner.power_schemes()
+  }
+
+  fn active_power_scheme(&self) -> Result<PowerScheme> {
+    self.inner.active_power_scheme()
+  }
+
+  fn set_active_power_scheme(&self, scheme: &PowerScheme) -> Result<()> {
+    self.change(
+      || format!("set the power scheme to {}", scheme.name),
+      |inner| inner.set_active_power_scheme(scheme),
+    )
+  }
+}
+
+impl<B: AudioBackend> AudioBackend for DryRun<B> {
+  fn audio_devices(&self, device_type: DeviceType) -> Result<Vec<AudioDevice>> {
+    self.inner.audio_devices(device_type)
+  }
+
+  fn default_audio_device(&self, device_type: DeviceType) -> Result<AudioDevice> {
+    self.inner.default_audio_device(device_type)
+  }
+
+  fn active_audio_applications(&self, device_type: DeviceType) -> Result<Vec<String>> {
+    self.inner.active_audio_applications(device_type)
+  }
+
+  fn set_default_output(&self, device: &AudioDevice) -> Result<()> {
+    self.change(
+      || format!("make {} the default output", device.name),
+      |inner| inner.set_default_output(device),
+    )
+  }
+}
+
+impl<B: NetworkBackend> NetworkBackend for DryRun<B> {
+  fn is_ethernet_plugged_in(&self) -> Result<bool> {
+    self.inner.is_ethernet_plugged_in()
+  }
+
+  fn wifi_state(&self) -> Result<bool> {
+    self.inner.wifi_state()
+  }
+
+  fn wifi_networks(&self) -> Result<Vec<WifiNetwork>> {
+    self.inner.wifi_networks()
+  }
+
+  fn set_wifi_state(&self, on: bool) -> Result<()> {
+    self.change(
+      || format!("turn Wi-Fi {}", on_off(on)),
+      |inner| inner.set_wifi_state(on),
+    )
+  }
+}
+
+impl<B: DisplayBackend> DisplayBackend for DryRun<B> {
+  fn refresh_rates(&self) -> Result<Vec<u32>> {
+    self.inner.refresh_rates()
+  }
+
+  fn refresh_rate(&self) -> Result<u32> {
+    self.inner.refresh_rate()
+  }
+
+  fn set_refresh_rate(&self, frequency: u32) -> Result<()> {
+    self.change(
+      || format!("set the refresh rate to {} Hz", frequency),
+      |inner| inner.set_refresh_rate(frequency),
+    )
+  }
+
+  fn turn_off_monitor(&self) -> Result<()> {
+    self.change(
+      || "turn off the monitor".to_string(),
+      |inner| inner.turn_off_monitor(),
+    )
+  }
+}
+
+impl<B: StartupBackend> StartupBackend for DryRun<B> {
+  fn startup_items(&self) -> Result<Vec<StartupItem>> {
+    self.inner.startup_items()
+  }
+
+  fn set_startup_item_state(&self, name: &str, enabled: bool) -> Result<()> {
+    self.change(
+      || {
+        let state = if enabled { "enable" } else { "disable" };
+        format!("{} {} at startup", state, name)
+      },
+      |inner| inner.set_startup_item_state(name, enabled),
+    )
+  }
+}
+
+impl<B: WindowBackend> WindowBackend for DryRun<B> {
+  fn maximized_window_processes(&self) -> Result<Vec<String>> {
+    self.inner.maximized_window_processes()
+  }
+
+  fn foreground_window_process(&self) -> Result<Option<String>> {
+    self.inner.foreground_window_process()
+  }
+
+  fn taskbar_autohide(&self) -> Result<bool> {
+    self.inner.taskbar_autohide()
+  }
+
+  fn set_taskbar_autohide(&self, hide: bool) -> Result<()> {
+    self.change(
+      || {
+        let state = if hide { "hide" } else { "show" };
+        format!("{} the taskbar", state)
+      },
+      |inner| inner.set_taskbar_autohide(hide),
+    )
+  }
+}
+
+impl<B: ProcessBackend> ProcessBackend for DryRun<B> {
+  fn process_names(&self) -> Result<Vec<String>> {
+    self.inner.process_names()
+  }
+
+  fn cpu_times(&self) -> Result<CpuTimes> {
+    self.inner.cpu_times()
+  }
+}
+
+impl<B: ClockBackend> ClockBackend for DryRun<B> {
+  fn local_time(&self) -> Result<LocalTime> {
+    self.inner.local_time()
+  }
+
+  fn boot_time(&self) -> Result<u64> {
+    self.inner.boot_time()
+  }
+}
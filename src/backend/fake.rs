@@ -0,0 +1,362 @@
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+
+use super::{
+  types::{
+    AcLineState, AudioDevice, BatteryPresence, CpuTimes, DeviceType, LocalTime, PowerScheme,
+    StartupItem, SystemPowerStatus, WifiNetwork,
+  },
+  AudioBackend, ClockBackend, DisplayBackend, NetworkBackend, PowerBackend, ProcessBackend,
+  StartupBackend, WindowBackend,
+};
+
+/// Every state-changing call made against a `FakeBackend`, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeCall {
+  SetActivePowerScheme(String),
+  SetDefaultOutput(String),
+  SetWifiState(bool),
+  SetRefreshRate(u32),
+  TurnOffMonitor,
+  SetStartupItemState(String, bool),
+  SetTaskbarAutohide(bool),
+}
+
+/// The simulated machine. Every field can be changed between ticks to script a scenario.
+#[derive(Debug, Clone)]
+pub struct FakeState {
+  pub power_status: SystemPowerStatus,
+  pub power_schemes: Vec<PowerScheme>,
+  pub active_power_scheme: String,
+
+  pub output_devices: Vec<AudioDevice>,
+  pub input_devices: Vec<AudioDevice>,
+  pub default_output: String,
+  pub default_input: String,
+  pub input_applications: Vec<String>,
+  pub output_applications: Vec<String>,
+
+  pub ethernet: bool,
+  pub wifi: bool,
+  pub wifi_networks: Vec<WifiNetwork>,
+
+  pub refresh_rates: Vec<u32>,
+  pub refresh_rate: u32,
+
+  pub startup_items: Vec<StartupItem>,
+
+  pub maximized_windows: Vec<String>,
+  pub foreground_window: Option<String>,
+  pub taskbar_autohide: bool,
+
+  pub processes: Vec<String>,
+  pub cpu_times: CpuTimes,
+
+  pub local_time: LocalTime,
+  pub boot_time: u64,
+
+  /// Names of backend methods that should return an error
+  pub failing: Vec<&'static str>,
+}
+
+impl Default for FakeState {
+  fn default() -> Self {
+    let schemes = vec![
+      PowerScheme {
+        name: "POWERSAVER".to_string(),
+        guid: "A1841308-3541-4FAB-BC81-F71556F20B4A".to_string(),
+      },
+      PowerScheme {
+        name: "Ultra".to_string(),
+        guid: "E9A42B02-D5DF-448D-AA00-03F14749EB61".to_string(),
+      },
+      PowerScheme {
+        name: "Balanced".to_string(),
+        guid: "381B4222-F694-41F0-9685-FF5BB260DF2E".to_string(),
+      },
+    ];
+    let speakers = AudioDevice {
+      id: "speakers".to_string(),
+      kind: "Speakers".to_string(),
+      name: "Speakers".to_string(),
+    };
+    let headphones = AudioDevice {
+      id: "headphones".to_string(),
+      kind: "Headphones".to_string(),
+      name: "Headphones".to_string(),
+    };
+    let microphone = AudioDevice {
+      id: "microphone".to_string(),
+      kind: "Microphone".to_string(),
+      name: "Microphone".to_string(),
+    };
+
+    Self {
+      power_status: SystemPowerStatus {
+        ac_line: AcLineState::Online,
+        battery: BatteryPresence::Present,
+        is_charging: false,
+        is_battery_saver_enabled: false,
+        remaining_percentage: Some(100),
+        remaining_time: None,
+      },
+      active_power_scheme: schemes[1].guid.clone(),
+      power_schemes: schemes,
+
+      default_output: speakers.id.clone(),
+      output_devices: vec![speakers, headphones],
+      default_input: microphone.id.clone(),
+      input_devices: vec![microphone],
+      input_applications: Vec::new(),
+      output_applications: Vec::new(),
+
+      ethernet: false,
+      wifi: true,
+      wifi_networks: Vec::new(),
+
+      refresh_rates: vec![60, 144],
+      refresh_rate: 144,
+
+      startup_items: Vec::new(),
+
+      maximized_windows: Vec::new(),
+      foreground_window: None,
+      taskbar_autohide: false,
+
+      processes: Vec::new(),
+      cpu_times: CpuTimes::default(),
+
+      local_time: LocalTime {
+        hour: 12,
+        minute: 0,
+      },
+      boot_time: 0,
+
+      failing: Vec::new(),
+    }
+  }
+}
+
+/// An in-memory backend that records every call instead of touching the system
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+  state: Mutex<FakeState>,
+  calls: Mutex<Vec<FakeCall>>,
+}
+
+impl FakeBackend {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_state(state: FakeState) -> Self {
+    Self {
+      state: Mutex::new(state),
+      calls: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn state(&self) -> MutexGuard<'_, FakeState> {
+    self.state.lock().unwrap()
+  }
+
+  pub fn update(&self, f: impl FnOnce(&mut FakeState)) {
+    f(&mut self.state());
+  }
+
+  pub fn calls(&self) -> Vec<FakeCall> {
+    self.calls.lock().unwrap().clone()
+  }
+
+  pub fn take_calls(&self) -> Vec<FakeCall> {
+    std::mem::take(&mut *self.calls.lock().unwrap())
+  }
+
+  fn check(&self, method: &'static str) -> Result<MutexGuard<'_, FakeState>> {
+    let state = self.state();
+    if state.failing.contains(&method) {
+      return Err(anyhow!("{} failed", method));
+    }
+    Ok(state)
+  }
+
+  fn record(&self, call: FakeCall) {
+    self.calls.lock().unwrap().push(call);
+  }
+}
+
+impl PowerBackend for FakeBackend {
+  fn power_status(&self) -> Result<SystemPowerStatus> {
+    Ok(self.check("power_status")?.power_status)
+  }
+
+  fn power_schemes(&self) -> Result<Vec<PowerScheme>> {
+    Ok(self.check("power_schemes")?.power_schemes.clone())
+  }
+
+  fn active_power_scheme(&self) -> Result<PowerScheme> {
+    let state = self.check("active_power_scheme")?;
+    state
+      .power_schemes
+      .iter()
+      .find(|scheme| scheme.guid == state.active_power_scheme)
+      .cloned()
+      .ok_or_else(|| anyhow!("Unknown power scheme {}", state.active_power_scheme))
+  }
+
+  fn set_active_power_scheme(&self, scheme: &PowerScheme) -> Result<()> {
+    self.check("set_active_power_scheme")?.active_power_scheme = scheme.guid.clone();
+    self.record(FakeCall::SetActivePowerScheme(scheme.guid.clone()));
+    Ok(())
+  }
+}
+
+impl AudioBackend for FakeBackend {
+  fn audio_devices(&self, device_type: DeviceType) -> Result<Vec<AudioDevice>> {
+    let state = self.check("audio_devices")?;
+    Ok(match device_type {
+      DeviceType::Input => state.input_devices.clone(),
+      DeviceType::Output => state.output_devices.clone(),
+    })
+  }
+
+  fn default_audio_device(&self, device_type: DeviceType) -> Result<AudioDevice> {
+    let state = self.check("default_audio_device")?;
+    let (devices, id) = match device_type {
+      DeviceType::Input => (&state.input_devices, &state.default_input),
+      DeviceType::Output => (&state.output_devices, &state.default_output),
+    };
+    devices
+      .iter()
+      .find(|device| &device.id == id)
+      .cloned()
+      .ok_or_else(|| anyhow!("Unknown audio device {}", id))
+  }
+
+  fn active_audio_applications(&self, device_type: DeviceType) -> Result<Vec<String>> {
+    let state = self.check("active_audio_applications")?;
+    Ok(match device_type {
+      DeviceType::Input => state.input_applications.clone(),
+      DeviceType::Output => state.output_applications.clone(),
+    })
+  }
+
+  fn set_default_output(&self, device: &AudioDevice) -> Result<()> {
+    self.check("set_default_output")?.default_output = device.id.clone();
+    self.record(FakeCall::SetDefaultOutput(device.id.clone()));
+    Ok(())
+  }
+}
+
+impl NetworkBackend for FakeBackend {
+  fn is_ethernet_plugged_in(&self) -> Result<bool> {
+    Ok(self.check("is_ethernet_plugged_in")?.ethernet)
+  }
+
+  fn wifi_state(&self) -> Result<bool> {
+    Ok(self.check("wifi_state")?.wifi)
+  }
+
+  fn wifi_networks(&self) -> Result<Vec<WifiNetwork>> {
+    Ok(self.check("wifi_networks")?.wifi_networks.clone())
+  }
+
+  fn set_wifi_state(&self, on: bool) -> Result<()> {
+    self.check("set_wifi_state")?.wifi = on;
+    self.record(FakeCall::SetWifiState(on));
+    Ok(())
+  }
+}
+
+impl DisplayBackend for FakeBackend {
+  fn refresh_rates(&self) -> Result<Vec<u32>> {
+    Ok(self.check("refresh_rates")?.refresh_rates.clone())
+  }
+
+  fn refresh_rate(&self) -> Result<u32> {
+    Ok(self.check("refresh_rate")?.refresh_rate)
+  }
+
+  fn set_refresh_rate(&self, frequency: u32) -> Result<()> {
+    self.check("set_refresh_rate")?.refresh_rate = frequency;
+    self.record(FakeCall::SetRefreshRate(frequency));
+    Ok(())
+  }
+
+  fn turn_off_monitor(&self) -> Result<()> {
+    drop(self.check("turn_off_monitor")?);
+    self.record(FakeCall::TurnOffMonitor);
+    Ok(())
+  }
+}
+
+impl StartupBackend for FakeBackend {
+  fn startup_items(&self) -> Result<Vec<StartupItem>> {
+    Ok(self.check("startup_items")?.startup_items.clone())
+  }
+
+  fn set_startup_item_state(&self, name: &str, enabled: bool) -> Result<()> {
+    let mut state = self.check("set_startup_item_state")?;
+    let item = state
+      .startup_items
+      .iter_mut()
+      .find(|item| item.name == name)
+      .ok_or_else(|| anyhow!("Unknown startup item {}", name))?;
+    item.enabled = enabled;
+    drop(state);
+
+    self.record(FakeCall::SetStartupItemState(name.to_string(), enabled));
+    Ok(())
+  }
+}
+
+impl WindowBackend for FakeBackend {
+  fn maximized_window_processes(&self) -> Result<Vec<String>> {
+    Ok(
+      self
+        .check("maximized_window_processes")?
+        .maximized_windows
+        .clone(),
+    )
+  }
+
+  fn foreground_window_process(&self) -> Result<Option<String>> {
+    Ok(
+      self
+        .check("foreground_window_process")?
+        .foreground_window
+        .clone(),
+    )
+  }
+
+  fn taskbar_autohide(&self) -> Result<bool> {
+    Ok(self.check("taskbar_autohide")?.taskbar_autohide)
+  }
+
+  fn set_taskbar_autohide(&self, hide: bool) -> Result<()> {
+    self.check("set_taskbar_autohide")?.taskbar_autohide = hide;
+    self.record(FakeCall::SetTaskbarAutohide(hide));
+    Ok(())
+  }
+}
+
+impl ProcessBackend for FakeBackend {
+  fn process_names(&self) -> Result<Vec<String>> {
+    Ok(self.check("process_names")?.processes.clone())
+  }
+
+  fn cpu_times(&self) -> Result<CpuTimes> {
+    Ok(self.check("cpu_times")?.cpu_times)
+  }
+}
+
+impl ClockBackend for FakeBackend {
+  fn local_time(&self) -> Result<LocalTime> {
+    Ok(self.check("local_time")?.local_time)
+  }
+
+  fn boot_time(&self) -> Result<u64> {
+    Ok(self.check("boot_time")?.boot_time)
+  }
+}
@@ -0,0 +1,424 @@
+use std::{fmt, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+  types::{
+    AudioDevice, CpuTimes, DeviceType, LocalTime, PowerScheme, StartupItem, SystemPowerStatus,
+    WifiNetwork,
+  },
+  AudioBackend, Backend, ClockBackend, DisplayBackend, NetworkBackend, PowerBackend,
+  ProcessBackend, StartupBackend, WindowBackend,
+};
+use crate::log::{info, warning};
+
+/// A setting as it was before the app first changed it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "setting", content = "value", rename_all = "snake_case")]
+pub enum Original {
+  PowerScheme(PowerScheme),
+  DefaultOutput(AudioDevice),
+  Wifi(bool),
+  RefreshRate(u32),
+  StartupItem(StartupItem),
+  TaskbarAutohide(bool),
+}
+
+impl Original {
+  /// Which setting this is, whatever its value
+  fn key(&self) -> String {
+    match self {
+      Original::PowerScheme(_) => "power_scheme".to_string(),
+      Original::DefaultOutput(_) => "default_output".to_string(),
+      Original::Wifi(_) => "wifi".to_string(),
+      Original::RefreshRate(_) => "refresh_rate".to_string(),
+      Original::StartupItem(item) => format!("startup_item:{}", item.name),
+      Original::TaskbarAutohide(_) => "taskbar_autohide".to_string(),
+    }
+  }
+
+  fn apply(&self, backend: &impl Backend) -> Result<()> {
+    match self {
+      Original::PowerScheme(scheme) => backend.set_active_power_scheme(scheme),
+      Original::DefaultOutput(device) => backend.set_default_output(device),
+      Original::Wifi(on) => backend.set_wifi_state(*on),
+      Original::RefreshRate(frequency) => backend.set_refresh_rate(*frequency),
+      Original::StartupItem(item) => backend.set_startup_item_state(&item.name, item.enabled),
+      Original::TaskbarAutohide(hide) => backend.set_taskbar_autohide(*hide),
+    }
+  }
+}
+
+impl fmt::Display for Original {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Original::PowerScheme(scheme) => write!(f, "the power scheme {}", scheme.name),
+      Original::DefaultOutput(device) => write!(f, "{} as the default output", device.name),
+      Original::Wifi(on) => write!(f, "Wi-Fi {}", if *on { "on" } else { "off" }),
+      Original::RefreshRate(frequency) => write!(f, "the refresh rate of {} Hz", frequency),
+      Original::StartupItem(item) => write!(
+        f,
+        "{} {} at startup",
+        item.name,
+        if item.enabled { "enabled" } else { "disabled" }
+      ),
+      Original::TaskbarAutohide(hide) => {
+        write!(f, "the taskbar {}", if *hide { "hidden" } else { "shown" })
+      }
+    }
+  }
+}
+
+/// Passes everything to `inner`, remembering every setting as it was before its first change
+/// so that `revert_all` can put it back
+pub struct Journal<B> {
+  inner: B,
+  originals: Mutex<Vec<Original>>,
+}
+
+impl<B: Backend> Journal<B> {
+  pub const fn new(inner: B) -> Self {
+    Self {
+      inner,
+      originals: Mutex::new(Vec::new()),
+    }
+  }
+
+  /// Settings changed so far, in the order of their first change
+  pub fn originals(&self) -> Vec<Original> {
+    self.originals.lock().unwrap().clone()
+  }
+
+  /// Carries on with the originals of a previous launch, which the machine is not in anymore
+  pub fn set_originals(&self, originals: Vec<Original>) {
+    *self.originals.lock().unwrap() = originals;
+  }
+
+  /// Puts every changed setting back, most recent first, and forgets about them. What could
+  /// not be restored is kept for the next try and returned.
+  pub fn revert_all(&self) -> Vec<String> {
+    let originals = std::mem::take(&mut *self.originals.lock().unwrap());
+
+    let mut kept = Vec::new();
+    let mut failures = Vec::new();
+    for original in originals.into_iter().rev() {
+      match original.apply(&self.inner) {
+        Ok(()) => info!("Restored {}", original),
+        Err(e) => {
+          warning!("Cannot restore {}: {:#}", original, e);
+          failures.push(format!("Cannot restore {}: {:#}", original, e));
+          kept.push(original);
+        }
+      }
+    }
+
+    // Older than anything changed meanwhile, which started from a setting that was not restored
+    kept.reverse();
+    let mut originals = self.originals.lock().unwrap();
+    originals.retain(|original| kept.iter().all(|kept| kept.key() != original.key()));
+    kept.append(&mut originals);
+    *originals = kept;
+
+    failures
+  }
+
+  /// Runs `change` on the real backend, reading the original with `read` first when the
+  /// setting `key` was not changed before
+  fn change(
+    &self,
+    key: &str,
+    read: impl FnOnce(&B) -> Result<Original>,
+    change: impl FnOnce(&B) -> Result<()>,
+  ) -> Result<()> {
+    let mut originals = self.originals.lock().unwrap();
+    if !originals.iter().any(|original| original.key() == key) {
+      // A change that fails may still have gone through halfway, so it is remembered anyway
+      match read(&self.inner) {
+        Ok(original) => originals.push(original),
+        Err(e) => warning!("Cannot read {} before changing it: {:#}", key, e),
+      }
+    }
+    drop(originals);
+
+    change(&self.inner)
+  }
+}
+
+impl<B: Backend> PowerBackend for Journal<B> {
+  fn power_status(&self) -> Result<SystemPowerStatus> {
+    self.inner.power_status()
+  }
+
+  fn power_schemes(&self) -> Result<Vec<PowerScheme>> {
+    self.inner.power_schemes()
+  }
+
+  fn active_power_scheme(&self) -> Result<PowerScheme> {
+    self.inner.active_power_scheme()
+  }
+
+  fn set_active_power_scheme(&self, scheme: &PowerScheme) -> Result<()> {
+    self.change(
+      "power_scheme",
+      |inner| inner.active_power_scheme().map(Original::PowerScheme),
+      |inner| inner.set_active_power_scheme(scheme),
+    )
+  }
+}
+
+impl<B: Backend> AudioBackend for Journal<B> {
+  fn audio_devices(&self, device_type: DeviceType) -> Result<Vec<AudioDevice>> {
+    self.inner.audio_devices(device_type)
+  }
+
+  fn default_audio_device(&self, device_type: DeviceType) -> Result<AudioDevice> {
+    self.inner.default_audio_device(device_type)
+  }
+
+  fn active_audio_applications(&self, device_type: DeviceType) -> Result<Vec<String>> {
+    self.inner.active_audio_applications(device_type)
+  }
+
+  fn set_default_output(&self, device: &AudioDevice) -> Result<()> {
+    self.change(
+      "default_output",
+      |inner| {
+        inner
+          .default_audio_device(DeviceType::Output)
+          .map(Original::DefaultOutput)
+      },
+      |inner| inner.set_default_output(device),
+    )
+  }
+}
+
+impl<B: Backend> NetworkBackend for Journal<B> {
+  fn is_ethernet_plugged_in(&self) -> Result<bool> {
+    self.inner.is_ethernet_plugged_in()
+  }
+
+  fn wifi_state(&self) -> Result<bool> {
+    self.inner.wifi_state()
+  }
+
+  fn wifi_networks(&self) -> Result<Vec<WifiNetwork>> {
+    self.inner.wifi_networks()
+  }
+
+  fn set_wifi_state(&self, on: bool) -> Result<()> {
+    self.change(
+      "wifi",
+      |inner| inner.wifi_state().map(Original::Wifi),
+      |inner| inner.set_wifi_state(on),
+    )
+  }
+}
+
+impl<B: Backend> DisplayBackend for Journal<B> {
+  fn refresh_rates(&self) -> Result<Vec<u32>> {
+    self.inner.refresh_rates()
+  }
+
+  fn refresh_rate(&self) -> Result<u32> {
+    self.inner.refresh_rate()
+  }
+
+  fn set_refresh_rate(&self, frequency: u32) -> Result<()> {
+    self.change(
+      "refresh_rate",
+      |inner| inner.refresh_rate().map(Original::RefreshRate),
+      |inner| inner.set_refresh_rate(frequency),
+    )
+  }
+
+  fn turn_off_monitor(&self) -> Result<()> {
+    // The monitor comes back on by itself
+    self.inner.turn_off_monitor()
+  }
+}
+
+impl<B: Backend> StartupBackend for Journal<B> {
+  fn startup_items(&self) -> Result<Vec<StartupItem>> {
+    self.inner.startup_items()
+  }
+
+  fn set_startup_item_state(&self, name: &str, enabled: bool) -> Result<()> {
+    self.change(
+      &format!("startup_item:{}", name),
+      |inner| {
+        inner
+          .startup_items()?
+          .into_iter()
+          .find(|item| item.name == name)
+          .map(Original::StartupItem)
+          .ok_or_else(|| anyhow!("Cannot find startup item {}", name))
+      },
+      |inner| inner.set_startup_item_state(name, enabled),
+    )
+  }
+}
+
+impl<B: Backend> WindowBackend for Journal<B> {
+  fn maximized_window_processes(&self) -> Result<Vec<String>> {
+    self.inner.maximized_window_processes()
+  }
+
+  fn foreground_window_process(&self) -> Result<Option<String>> {
+    self.inner.foreground_window_process()
+  }
+
+  fn taskbar_autohide(&self) -> Result<bool> {
+    self.inner.taskbar_autohide()
+  }
+
+  fn set_taskbar_autohide(&self, hide: bool) -> Result<()> {
+    self.change(
+      "taskbar_autohide",
+      |inner| inner.taskbar_autohide().map(Original::TaskbarAutohide),
+      |inner| inner.set_taskbar_autohide(hide),
+    )
+  }
+}
+
+impl<B: Backend> ProcessBackend for Journal<B> {
+  fn process_names(&self) -> Result<Vec<String>> {
+    self.inner.process_names()
+  }
+
+  fn cpu_times(&self) -> Result<CpuTimes> {
+    self.inner.cpu_times()
+  }
+}
+
+impl<B: Backend> ClockBackend for Journal<B> {
+  fn local_time(&self) -> Result<LocalTime> {
+    self.inner.local_time()
+  }
+
+  fn boot_time(&self) -> Result<u64> {
+    self.inner.boot_time()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::fake::{FakeBackend, FakeCall};
+
+  const ULTRA: &str = "E9A42B02-D5DF-448D-AA00-03F14749EB61";
+
+  fn journal() -> Journal<FakeBackend> {
+    let backend = FakeBackend::new();
+    backend.update(|state| {
+      state.startup_items = vec![StartupItem {
+        name: "Discord".to_string(),
+        enabled: true,
+      }]
+    });
+    Journal::new(backend)
+  }
+
+  fn scheme(journal: &Journal<FakeBackend>, name: &str) -> PowerScheme {
+    journal
+      .power_schemes()
+      .unwrap()
+      .into_iter()
+      .find(|scheme| scheme.name == name)
+      .unwrap()
+  }
+
+  #[test]
+  fn remembers_a_setting_before_its_first_change_only() {
+    let journal = journal();
+    journal.set_wifi_state(false).unwrap();
+    journal.set_wifi_state(true).unwrap();
+    journal.set_wifi_state(false).unwrap();
+    journal
+      .set_active_power_scheme(&scheme(&journal, "POWERSAVER"))
+      .unwrap();
+    journal
+      .set_active_power_scheme(&scheme(&journal, "Balanced"))
+      .unwrap();
+
+    assert_eq!(
+      journal.originals(),
+      [
+        Original::Wifi(true),
+        Original::PowerScheme(scheme(&journal, "Ultra"))
+      ]
+    );
+  }
+
+  #[test]
+  fn a_failed_change_is_remembered_but_an_unreadable_setting_is_not() {
+    let journal = journal();
+    journal
+      .inner
+      .update(|state| state.failing = vec!["set_refresh_rate", "taskbar_autohide"]);
+
+    assert!(journal.set_refresh_rate(60).is_err());
+    journal.set_taskbar_autohide(true).unwrap();
+
+    assert_eq!(journal.originals(), [Original::RefreshRate(144)]);
+  }
+
+  #[test]
+  fn reverts_the_most_recent_change_first() {
+    let journal = journal();
+    journal.set_wifi_state(false).unwrap();
+    journal.set_refresh_rate(60).unwrap();
+    journal.set_startup_item_state("Discord", false).unwrap();
+    journal
+      .set_active_power_scheme(&scheme(&journal, "POWERSAVER"))
+      .unwrap();
+    journal.set_wifi_state(true).unwrap();
+    journal.inner.take_calls();
+
+    assert_eq!(journal.revert_all(), Vec::<String>::new());
+    assert_eq!(
+      journal.inner.take_calls(),
+      [
+        FakeCall::SetActivePowerScheme(ULTRA.to_string()),
+        FakeCall::SetStartupItemState("Discord".to_string(), true),
+        FakeCall::SetRefreshRate(144),
+        FakeCall::SetWifiState(true),
+      ]
+    );
+    assert!(journal.originals().is_empty());
+
+    // Nothing left to put back
+    assert_eq!(journal.revert_all(), Vec::<String>::new());
+    assert_eq!(journal.inner.take_calls(), []);
+  }
+
+  #[test]
+  fn what_cannot_be_restored_is_reported_and_tried_again() {
+    let journal = journal();
+    journal.set_wifi_state(false).unwrap();
+    journal.set_refresh_rate(60).unwrap();
+    journal.set_taskbar_autohide(true).unwrap();
+    journal.inner.update(|state| {
+      state.failing = vec!["set_wifi_state", "set_taskbar_autohide"];
+    });
+
+    assert_eq!(
+      journal.revert_all(),
+      [
+        "Cannot restore the taskbar shown: set_taskbar_autohide failed",
+        "Cannot restore Wi-Fi on: set_wifi_state failed",
+      ]
+    );
+    assert_eq!(
+      journal.originals(),
+      [Original::Wifi(true), Original::TaskbarAutohide(false)]
+    );
+    assert_eq!(journal.inner.state().refresh_rate, 144);
+
+    journal.inner.update(|state| state.failing.clear());
+    assert_eq!(journal.revert_all(), Vec::<String>::new());
+    assert!(journal.originals().is_empty());
+    assert!(journal.inner.state().wifi);
+    assert!(!journal.inner.state().taskbar_autohide);
+  }
+}
@@ -0,0 +1,97 @@
+pub mod dry_run;
+pub mod fake;
+pub mod journal;
+pub mod types;
+#[cfg(windows)]
+pub mod win32;
+
+use anyhow::Result;
+use types::{
+  AudioDevice, CpuTimes, DeviceType, LocalTime, PowerScheme, StartupItem, SystemPowerStatus,
+  WifiNetwork,
+};
+
+pub trait PowerBackend {
+  fn power_status(&self) -> Result<SystemPowerStatus>;
+  fn power_schemes(&self) -> Result<Vec<PowerScheme>>;
+  fn active_power_scheme(&self) -> Result<PowerScheme>;
+  fn set_active_power_scheme(&self, scheme: &PowerScheme) -> Result<()>;
+}
+
+pub trait AudioBackend {
+  fn audio_devices(&self, device_type: DeviceType) -> Result<Vec<AudioDevice>>;
+  fn default_audio_device(&self, device_type: DeviceType) -> Result<AudioDevice>;
+  fn active_audio_applications(&self, device_type: DeviceType) -> Result<Vec<String>>;
+  fn set_default_output(&self, device: &AudioDevice) -> Result<()>;
+}
+
+pub trait NetworkBackend {
+  fn is_ethernet_plugged_in(&self) -> Result<bool>;
+  /// Whether the Wi-Fi radio is on
+  fn wifi_state(&self) -> Result<bool>;
+  fn wifi_networks(&self) -> Result<Vec<WifiNetwork>>;
+  fn set_wifi_state(&self, on: bool) -> Result<()>;
+}
+
+pub trait DisplayBackend {
+  fn refresh_rates(&self) -> Result<Vec<u32>>;
+  fn refresh_rate(&self) -> Result<u32>;
+  fn set_refresh_rate(&self, frequency: u32) -> Result<()>;
+  fn turn_off_monitor(&self) -> Result<()>;
+}
+
+pub trait StartupBackend {
+  fn startup_items(&self) -> Result<Vec<StartupItem>>;
+  fn set_startup_item_state(&self, name: &str, enabled: bool) -> Result<()>;
+}
+
+pub trait WindowBackend {
+  /// Executable names (lowercase, with extension) of every visible maximized window
+  fn maximized_window_processes(&self) -> Result<Vec<String>>;
+  /// Executable name (lowercase, with extension) of the window in the foreground, if any
+  fn foreground_window_process(&self) -> Result<Option<String>>;
+  fn taskbar_autohide(&self) -> Result<bool>;
+  fn set_taskbar_autohide(&self, hide: bool) -> Result<()>;
+}
+
+pub trait ProcessBackend {
+  /// Executable names (lowercase, without extension) of every running process
+  fn process_names(&self) -> Result<Vec<String>>;
+  /// Processor time since boot, which gives the load between two reads
+  fn cpu_times(&self) -> Result<CpuTimes>;
+}
+
+pub trait ClockBackend {
+  fn local_time(&self) -> Result<LocalTime>;
+  /// When the machine started, in seconds since the Unix epoch
+  fn boot_time(&self) -> Result<u64>;
+}
+
+/// Everything the automation workers need from the operating system
+pub trait Backend:
+  PowerBackend
+  + AudioBackend
+  + NetworkBackend
+  + DisplayBackend
+  + StartupBackend
+  + WindowBackend
+  + ProcessBackend
+  + ClockBackend
+  + Send
+  + Sync
+{
+}
+
+impl<T> Backend for T where
+  T: PowerBackend
+    + AudioBackend
+    + NetworkBackend
+    + DisplayBackend
+    + StartupBackend
+    + WindowBackend
+    + ProcessBackend
+    + ClockBackend
+    + Send
+    + Sync
+{
+}
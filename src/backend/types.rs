@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AcLineState {
+  Online,
+  Offline,
+  /// Windows does not know, which is not the same as running on battery
+  #[default]
+  Unknown,
+}
+
+impl AcLineState {
+  /// `None` when unknown
+  pub fn is_plugged_in(self) -> Option<bool> {
+    match self {
+      AcLineState::Online => Some(true),
+      AcLineState::Offline => Some(false),
+      AcLineState::Unknown => None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryPresence {
+  Present,
+  /// Desktops, or a laptop with its battery removed
+  Absent,
+  #[default]
+  Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SystemPowerStatus {
+  pub ac_line: AcLineState,
+  pub battery: BatteryPresence,
+  pub is_charging: bool,
+  pub is_battery_saver_enabled: bool,
+  /// `None` when unknown or without a battery
+  pub remaining_percentage: Option<u32>,
+  /// Seconds left on battery as the firmware estimates them, `None` when unknown or on AC
+  pub remaining_time: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PowerScheme {
+  pub name: String,
+  pub guid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+  Input,
+  Output,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AudioDevice {
+  pub id: String,
+  pub kind: String,
+  pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WifiNetwork {
+  pub name: String,
+  /// 0 to 100
+  pub signal_quality: u32,
+  pub authentication: String,
+  pub connected: bool,
+  /// Frequency bands in GHz
+  pub bands: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StartupItem {
+  pub name: String,
+  pub enabled: bool,
+}
+
+/// Processor time since boot across every core, in 100 ns units
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuTimes {
+  pub idle: u64,
+  pub total: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LocalTime {
+  pub hour: u32,
+  pub minute: u32,
+}
+
+/// Process names compared without case or `.exe`, so `Discord.exe` matches `discord`
+pub fn process_name(name: &str) -> String {
+  let name = name.to_lowercase();
+  name.strip_suffix(".exe").unwrap_or(&name).to_string()
+}
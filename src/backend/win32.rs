@@ -0,0 +1,269 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use windows::Win32::{
+  Foundation::WIN32_ERROR,
+  System::SystemInformation::{GetLocalTime, GetTickCount64},
+};
+
+use super::{
+  types::{
+    AcLineState, AudioDevice, BatteryPresence, CpuTimes, DeviceType, LocalTime, PowerScheme,
+    StartupItem, SystemPowerStatus, WifiNetwork,
+  },
+  AudioBackend, ClockBackend, DisplayBackend, NetworkBackend, PowerBackend, ProcessBackend,
+  StartupBackend, WindowBackend,
+};
+use crate::mods::{
+  connection, display, media,
+  media::types::device::{Device, DeviceType as MediaDeviceType},
+  power, process,
+  startup::registry,
+  taskbar,
+};
+
+/// The real backend, calling straight into the `mods::*` Win32 wrappers
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32Backend;
+
+fn win32_error(error: WIN32_ERROR) -> anyhow::Error {
+  anyhow::Error::msg(error.to_hresult().message())
+}
+
+fn to_media_device_type(device_type: DeviceType) -> MediaDeviceType {
+  match device_type {
+    DeviceType::Input => MediaDeviceType::Input,
+    DeviceType::Output => MediaDeviceType::Output,
+  }
+}
+
+fn to_audio_device(device: &Device) -> Result<AudioDevice> {
+  Ok(AudioDevice {
+    id: unsafe { device.device_id.to_string() }?,
+    kind: device.device_type.clone(),
+    name: device.device_name.clone(),
+  })
+}
+
+fn to_power_scheme(scheme: &power::types::PowerScheme) -> PowerScheme {
+  PowerScheme {
+    name: scheme.name.clone(),
+    guid: format!("{:?}", scheme.guid),
+  }
+}
+
+impl PowerBackend for Win32Backend {
+  fn power_status(&self) -> Result<SystemPowerStatus> {
+    let status = power::get_power_status()?;
+
+    Ok(SystemPowerStatus {
+      ac_line: match status.is_plugged_in {
+        Some(true) => AcLineState::Online,
+        Some(false) => AcLineState::Offline,
+        None => AcLineState::Unknown,
+      },
+      battery: match status.has_battery {
+        Some(true) => BatteryPresence::Present,
+        Some(false) => BatteryPresence::Absent,
+        None => BatteryPresence::Unknown,
+      },
+      is_charging: status.is_charging,
+      is_battery_saver_enabled: status.is_battery_saver_enabled,
+      remaining_percentage: status.remaining_percentage,
+      remaining_time: status.remaining_time,
+    })
+  }
+
+  fn power_schemes(&self) -> Result<Vec<PowerScheme>> {
+    Ok(
+      power::get_all_power_schemes()
+        .map_err(win32_error)?
+        .iter()
+        .map(to_power_scheme)
+        .collect(),
+    )
+  }
+
+  fn active_power_scheme(&self) -> Result<PowerScheme> {
+    Ok(to_power_scheme(
+      &power::get_active_power_scheme().map_err(win32_error)?,
+    ))
+  }
+
+  fn set_active_power_scheme(&self, scheme: &PowerScheme) -> Result<()> {
+    let all_power_schemes = power::get_all_power_schemes().map_err(win32_error)?;
+    let target = all_power_schemes
+      .iter()
+      .find(|s| format!("{:?}", s.guid) == scheme.guid)
+      .ok_or_else(|| anyhow!("Unknown power scheme {}", scheme.guid))?;
+
+    power::set_active_power_scheme(&target.guid).map_err(win32_error)
+  }
+}
+
+impl AudioBackend for Win32Backend {
+  fn audio_devices(&self, device_type: DeviceType) -> Result<Vec<AudioDevice>> {
+    media::init()?;
+
+    media::enumerate_audio_devices(&to_media_device_type(device_type))?
+      .iter()
+      .map(to_audio_device)
+      .collect()
+  }
+
+  fn default_audio_device(&self, device_type: DeviceType) -> Result<AudioDevice> {
+    media::init()?;
+
+    to_audio_device(&media::get_default_device(&to_media_device_type(
+      device_type,
+    ))?)
+  }
+
+  fn active_audio_applications(&self, device_type: DeviceType) -> Result<Vec<String>> {
+    media::init()?;
+
+    Ok(media::get_active_audio_applications(
+      &to_media_device_type(device_type),
+    )?)
+  }
+
+  fn set_default_output(&self, device: &AudioDevice) -> Result<()> {
+    media::init()?;
+
+    for output in media::enumerate_audio_devices(&MediaDeviceType::Output)? {
+      if to_audio_device(&output)?.id == device.id {
+        return Ok(media::change_default_output(output.device_id)?);
+      }
+    }
+
+    Err(anyhow!("Unknown audio device {}", device.id))
+  }
+}
+
+impl NetworkBackend for Win32Backend {
+  fn is_ethernet_plugged_in(&self) -> Result<bool> {
+    Ok(connection::is_ethernet_plugged_in())
+  }
+
+  fn wifi_state(&self) -> Result<bool> {
+    Ok(connection::get_wifi_state()?)
+  }
+
+  fn wifi_networks(&self) -> Result<Vec<WifiNetwork>> {
+    Ok(
+      connection::get_available_networks()?
+        .into_iter()
+        .map(|network| WifiNetwork {
+          connected: network.flags == "Connected",
+          name: network.name,
+          signal_quality: network.signal_quality,
+          authentication: network.authentication,
+          bands: network.bands,
+        })
+        .collect(),
+    )
+  }
+
+  fn set_wifi_state(&self, on: bool) -> Result<()> {
+    Ok(connection::set_wifi_state(on)?)
+  }
+}
+
+impl DisplayBackend for Win32Backend {
+  fn refresh_rates(&self) -> Result<Vec<u32>> {
+    Ok(display::get_all_frequencies())
+  }
+
+  fn refresh_rate(&self) -> Result<u32> {
+    Ok(display::get_current_frequency())
+  }
+
+  fn set_refresh_rate(&self, frequency: u32) -> Result<()> {
+    display::set_new_frequency(frequency);
+    Ok(())
+  }
+
+  fn turn_off_monitor(&self) -> Result<()> {
+    display::turn_off_monitor();
+    Ok(())
+  }
+}
+
+impl StartupBackend for Win32Backend {
+  fn startup_items(&self) -> Result<Vec<StartupItem>> {
+    Ok(
+      registry::get_all_startup_items()?
+        .iter()
+        .map(|item| StartupItem {
+          name: item.name.clone(),
+          enabled: item.state,
+        })
+        .collect(),
+    )
+  }
+
+  fn set_startup_item_state(&self, name: &str, enabled: bool) -> Result<()> {
+    let items = registry::get_all_startup_items()?;
+    let matching = items
+      .iter()
+      .filter(|item| item.name == name)
+      .collect::<Vec<_>>();
+    if matching.is_empty() {
+      return Err(anyhow!("Unknown startup item {}", name));
+    }
+
+    for item in matching {
+      registry::set_startup_item_state(item, enabled)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl WindowBackend for Win32Backend {
+  fn maximized_window_processes(&self) -> Result<Vec<String>> {
+    Ok(taskbar::get_maximized_window_processes())
+  }
+
+  fn foreground_window_process(&self) -> Result<Option<String>> {
+    Ok(taskbar::get_foreground_window_process())
+  }
+
+  fn taskbar_autohide(&self) -> Result<bool> {
+    Ok(taskbar::is_taskbar_autohide())
+  }
+
+  fn set_taskbar_autohide(&self, hide: bool) -> Result<()> {
+    taskbar::hide_taskbar(hide);
+    Ok(())
+  }
+}
+
+impl ProcessBackend for Win32Backend {
+  fn process_names(&self) -> Result<Vec<String>> {
+    process::get_processes_exec_name()
+  }
+
+  fn cpu_times(&self) -> Result<CpuTimes> {
+    let (idle, total) = process::get_system_times()?;
+    Ok(CpuTimes { idle, total })
+  }
+}
+
+impl ClockBackend for Win32Backend {
+  fn local_time(&self) -> Result<LocalTime> {
+    let time = unsafe { GetLocalTime() };
+
+    Ok(LocalTime {
+      hour: time.wHour as u32,
+      minute: time.wMinute as u32,
+    })
+  }
+
+  fn boot_time(&self) -> Result<u64> {
+    let uptime = Duration::from_millis(unsafe { GetTickCount64() });
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+    Ok(now.saturating_sub(uptime).as_secs())
+  }
+}
@@ -0,0 +1,403 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, Write},
+  path::{Path, PathBuf},
+  time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{log::timestamp, rules::runtime::unix_now};
+
+pub const HISTORY_FILE: &str = "battery.csv";
+
+/// Readings older than this are dropped when the history is opened
+pub const MAX_HISTORY_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The history moves to `battery.csv.1` once it would grow past this, replacing the one before
+pub const MAX_HISTORY_SIZE: u64 = 512 * 1024;
+
+/// The estimate only looks at this much of the current charge or discharge
+pub const ESTIMATE_WINDOW: Duration = Duration::from_secs(30 * 60);
+
+/// A rate over less than this is mostly noise from the 1% steps
+pub const MIN_ESTIMATE_SPAN: Duration = Duration::from_secs(3 * 60);
+
+/// The battery at `at`, in seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+  pub at: u64,
+  pub percentage: u32,
+  pub plugged_in: bool,
+}
+
+impl Reading {
+  /// `1700000000,54,0`, as one line of the history file
+  fn to_line(self) -> String {
+    format!(
+      "{},{},{}",
+      self.at,
+      self.percentage,
+      u8::from(self.plugged_in)
+    )
+  }
+
+  fn from_line(line: &str) -> Option<Self> {
+    let mut fields = line.split(',');
+    let reading = Reading {
+      at: fields.next()?.trim().parse().ok()?,
+      percentage: fields.next()?.trim().parse().ok()?,
+      plugged_in: fields.next()?.trim() == "1",
+    };
+    fields.next().is_none().then_some(reading)
+  }
+}
+
+/// How fast the battery is going, from the readings rather than the firmware
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+  /// Percent per hour, negative while draining
+  pub rate: f64,
+  pub time_to_empty: Option<Duration>,
+  pub time_to_full: Option<Duration>,
+}
+
+impl Estimate {
+  /// Percent per hour while draining, rounded to a whole percent
+  pub fn drain_rate(&self) -> Option<u32> {
+    (self.rate < 0.0).then(|| (-self.rate).round() as u32)
+  }
+}
+
+/// `2h 05m`
+pub fn format_duration(duration: Duration) -> String {
+  let minutes = duration.as_secs() / 60;
+  format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+/// Fits a line through the readings of the current charge or discharge within
+/// `ESTIMATE_WINDOW` of `now`, the last level being assumed to still hold at `now`. The last
+/// change is always part of it, being all there is to go on while the level barely moves.
+pub fn estimate(readings: &[Reading], now: u64) -> Option<Estimate> {
+  let last = readings.last()?;
+  let window_start = now.saturating_sub(ESTIMATE_WINDOW.as_secs());
+
+  let mut points: Vec<(f64, f64)> = readings
+    .iter()
+    .rev()
+    .take_while(|reading| reading.plugged_in == last.plugged_in)
+    .enumerate()
+    .take_while(|(index, reading)| *index < 2 || reading.at >= window_start)
+    .map(|(_, reading)| (reading.at as f64, reading.percentage as f64))
+    .collect();
+  if now > last.at {
+    points.push((now as f64, last.percentage as f64));
+  }
+
+  let first = points.iter().map(|(at, _)| *at).fold(f64::MAX, f64::min);
+  let span = points.iter().map(|(at, _)| *at).fold(f64::MIN, f64::max) - first;
+  if points.len() < 2 || span < MIN_ESTIMATE_SPAN.as_secs_f64() {
+    return None;
+  }
+
+  // Least squares slope, in percent per second
+  let count = points.len() as f64;
+  let mean_at = points.iter().map(|(at, _)| at - first).sum::<f64>() / count;
+  let mean_level = points.iter().map(|(_, level)| level).sum::<f64>() / count;
+  let (covariance, variance) =
+    points
+      .iter()
+      .fold((0.0, 0.0), |(covariance, variance), (at, level)| {
+        let dx = at - first - mean_at;
+        (covariance + dx * (level - mean_level), variance + dx * dx)
+      });
+  let rate = covariance / variance * 3600.0;
+
+  let hours = |percent: f64| Duration::from_secs_f64((percent / rate.abs() * 3600.0).max(0.0));
+  Some(Estimate {
+    rate,
+    time_to_empty: (rate < 0.0 && !last.plugged_in).then(|| hours(last.percentage as f64)),
+    time_to_full: (rate > 0.0 && last.plugged_in).then(|| hours(100.0 - last.percentage as f64)),
+  })
+}
+
+/// The battery history next to the per-user config at `config_path`
+pub fn history_path(config_path: &Path) -> PathBuf {
+  config_path
+    .parent()
+    .unwrap_or(Path::new("."))
+    .join(HISTORY_FILE)
+}
+
+/// Where the history at `path` goes when it is rotated
+fn rotated_path(path: &Path) -> PathBuf {
+  let mut name = path.as_os_str().to_os_string();
+  name.push(".1");
+  PathBuf::from(name)
+}
+
+/// Reads the history at `path` and the one rotated before it, skipping lines that cannot be
+/// read
+pub fn load(path: &Path) -> Result<Vec<Reading>> {
+  let mut readings = load_file(&rotated_path(path))?;
+  readings.extend(load_file(path)?);
+  Ok(readings)
+}
+
+fn load_file(path: &Path) -> Result<Vec<Reading>> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(anyhow!(e).context(format!("Cannot open {}", path.display()))),
+  };
+
+  let mut readings = Vec::new();
+  for line in BufReader::new(file).lines() {
+    readings.extend(Reading::from_line(&line?));
+  }
+  Ok(readings)
+}
+
+/// `readings` as CSV with a header, for spreadsheets
+pub fn to_csv(readings: &[Reading]) -> String {
+  let mut csv = String::from("time,percentage,plugged_in");
+  for reading in readings {
+    let time = UNIX_EPOCH + Duration::from_secs(reading.at);
+    csv.push_str(&format!(
+      "\n{},{},{}",
+      timestamp(time),
+      reading.percentage,
+      reading.plugged_in
+    ));
+  }
+  csv
+}
+
+/// Appends a reading to the history file every time the battery level or the power source
+/// changes, keeping the recent ones in memory for the estimate
+pub struct History {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  readings: Vec<Reading>,
+}
+
+impl History {
+  /// Opens the history at `path`, dropping readings older than `MAX_HISTORY_AGE`
+  pub fn open(path: &Path) -> Result<Self> {
+    if let Some(directory) = path.parent() {
+      std::fs::create_dir_all(directory)?;
+    }
+
+    let cutoff = unix_now()
+      .as_secs()
+      .saturating_sub(MAX_HISTORY_AGE.as_secs());
+    let mut current = load_file(path)?;
+    let before = current.len();
+    current.retain(|reading| reading.at >= cutoff);
+    if current.len() != before {
+      let lines: String = current
+        .iter()
+        .map(|reading| reading.to_line() + "\n")
+        .collect();
+      std::fs::write(path, lines).with_context(|| format!("Cannot write {}", path.display()))?;
+    }
+
+    // The rotated history goes as a whole once the newest reading in it is too old
+    let rotated = rotated_path(path);
+    if load_file(&rotated)?
+      .last()
+      .is_some_and(|reading| reading.at < cutoff)
+    {
+      std::fs::remove_file(&rotated)
+        .with_context(|| format!("Cannot remove {}", rotated.display()))?;
+    }
+
+    let mut history = Self::append_to(path)?;
+    history.readings = load(path)?;
+    history.readings.retain(|reading| reading.at >= cutoff);
+    Ok(history)
+  }
+
+  fn append_to(path: &Path) -> Result<Self> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .with_context(|| format!("Cannot open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok(Self {
+      path: path.to_path_buf(),
+      file,
+      size,
+      readings: Vec::new(),
+    })
+  }
+
+  /// Replaces the rotated history with the current one and starts an empty one, like the log
+  fn rotate(&mut self) -> Result<()> {
+    std::fs::rename(&self.path, rotated_path(&self.path))
+      .with_context(|| format!("Cannot rotate {}", self.path.display()))?;
+
+    let readings = std::mem::take(&mut self.readings);
+    *self = Self::append_to(&self.path)?;
+    self.readings = readings;
+    Ok(())
+  }
+
+  pub fn readings(&self) -> &[Reading] {
+    &self.readings
+  }
+
+  /// Returns whether the reading was new
+  pub fn record(&mut self, reading: Reading) -> Result<bool> {
+    let unchanged = self.readings.last().is_some_and(|last| {
+      last.percentage == reading.percentage && last.plugged_in == reading.plugged_in
+    });
+    if unchanged {
+      return Ok(false);
+    }
+
+    let line = reading.to_line() + "\n";
+    if self.size > 0 && self.size + line.len() as u64 > MAX_HISTORY_SIZE {
+      self.rotate()?;
+    }
+    self.file.write_all(line.as_bytes())?;
+    self.size += line.len() as u64;
+    self.readings.push(reading);
+
+    // Only the estimate window and the last change are needed in memory, the file keeps the rest
+    let cutoff = reading.at.saturating_sub(ESTIMATE_WINDOW.as_secs());
+    let old = self
+      .readings
+      .iter()
+      .take_while(|old| old.at < cutoff)
+      .count();
+    self
+      .readings
+      .drain(..old.min(self.readings.len().saturating_sub(2)));
+    Ok(true)
+  }
+
+  pub fn estimate(&self, now: u64) -> Option<Estimate> {
+    estimate(&self.readings, now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const START: u64 = 1_700_000_000;
+
+  /// One reading every `step` seconds from `START`
+  fn series(levels: &[u32], step: u64, plugged_in: bool) -> Vec<Reading> {
+    levels
+      .iter()
+      .enumerate()
+      .map(|(i, percentage)| Reading {
+        at: START + i as u64 * step,
+        percentage: *percentage,
+        plugged_in,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn a_steady_drain() {
+    // 1% every 6 minutes is 10% an hour
+    let readings = series(&[80, 79, 78, 77, 76], 360, false);
+    let estimate = estimate(&readings, START + 4 * 360).unwrap();
+    assert!((estimate.rate + 10.0).abs() < 0.01, "{}", estimate.rate);
+    assert_eq!(estimate.drain_rate(), Some(10));
+    let left = estimate.time_to_empty.unwrap().as_secs();
+    assert!(left.abs_diff(76 * 360) < 60, "{left}");
+    assert_eq!(estimate.time_to_full, None);
+  }
+
+  #[test]
+  fn charging() {
+    // 1% a minute is 60% an hour
+    let readings = series(&[50, 51, 52, 53, 54, 55], 60, true);
+    let estimate = estimate(&readings, START + 5 * 60).unwrap();
+    assert!((estimate.rate - 60.0).abs() < 0.01, "{}", estimate.rate);
+    assert_eq!(estimate.drain_rate(), None);
+    assert_eq!(estimate.time_to_empty, None);
+    let left = estimate.time_to_full.unwrap().as_secs();
+    assert!(left.abs_diff(45 * 60) < 60, "{left}");
+  }
+
+  #[test]
+  fn too_few_samples() {
+    assert_eq!(estimate(&[], START), None);
+
+    let one = series(&[80], 60, false);
+    assert_eq!(estimate(&one, START), None);
+
+    // Two readings, but over less than `MIN_ESTIMATE_SPAN`
+    let short = series(&[80, 79], 60, false);
+    assert_eq!(estimate(&short, START + 60), None);
+  }
+
+  #[test]
+  fn only_the_current_discharge_counts() {
+    let mut readings = series(&[90, 95, 100], 600, true);
+    readings.extend(series(&[99], 60, false).into_iter().map(|reading| Reading {
+      at: START + 1260,
+      ..reading
+    }));
+    assert_eq!(estimate(&readings, START + 1320), None);
+  }
+
+  #[test]
+  fn a_flat_series() {
+    let readings = series(&[64, 64, 64, 64], 300, false);
+    let estimate = estimate(&readings, START + 1200).unwrap();
+    assert_eq!(estimate.rate, 0.0);
+    assert_eq!(estimate.drain_rate(), None);
+    assert_eq!(estimate.time_to_empty, None);
+    assert_eq!(estimate.time_to_full, None);
+  }
+
+  #[test]
+  fn the_last_level_holds_until_now() {
+    // Nothing new for a while means the drain slowed down
+    let readings = series(&[80, 79, 78, 77], 360, false);
+    let fresh = estimate(&readings, START + 3 * 360).unwrap();
+    let stale = estimate(&readings, START + 5 * 360).unwrap();
+    assert!(stale.rate > fresh.rate, "{} {}", stale.rate, fresh.rate);
+  }
+
+  #[test]
+  fn a_slow_drain() {
+    // 1% every 40 minutes leaves nothing but the last change in the window
+    let readings = series(&[80, 79, 78], 2400, false);
+    let estimate = estimate(&readings, START + 2 * 2400 + 35 * 60).unwrap();
+    assert!(estimate.rate < 0.0, "{}", estimate.rate);
+    assert!(estimate.time_to_empty.is_some());
+  }
+
+  #[test]
+  fn the_history_is_rotated() {
+    let path = crate::testing::temp_dir("battery_rotate").join(HISTORY_FILE);
+    let start = unix_now().as_secs() - 24 * 60 * 60;
+    let mut history = History::open(&path).unwrap();
+
+    let mut written = Vec::new();
+    while !rotated_path(&path).exists() {
+      let reading = Reading {
+        at: start + written.len() as u64,
+        percentage: 50 + written.len() as u32 % 2,
+        plugged_in: false,
+      };
+      assert!(history.record(reading).unwrap());
+      written.push(reading);
+    }
+
+    assert!(std::fs::metadata(rotated_path(&path)).unwrap().len() <= MAX_HISTORY_SIZE);
+    assert_eq!(load(&path).unwrap(), written);
+    drop(history);
+    assert_eq!(History::open(&path).unwrap().readings, written);
+  }
+}
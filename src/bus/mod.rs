@@ -0,0 +1,134 @@
+pub mod sampler;
+pub mod state;
+
+use std::sync::{
+  mpsc::{channel, Receiver, Sender},
+  Mutex,
+};
+
+use serde::Serialize;
+use state::MachineState;
+
+use crate::{
+  backend::types::{AudioDevice, BatteryPresence, DeviceType, LocalTime, PowerScheme},
+  config::Config,
+  log::trace,
+};
+
+/// Everything that can happen on the machine or to the app. Process names are normalized by
+/// `process_name`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  // Power
+  PowerSourceChanged {
+    plugged_in: bool,
+  },
+  BatteryLevelChanged {
+    percentage: u32,
+  },
+  BatterySaverChanged {
+    enabled: bool,
+  },
+  BatteryPresenceChanged {
+    presence: BatteryPresence,
+  },
+  PowerSchemeChanged {
+    scheme: PowerScheme,
+  },
+  /// From the battery history, `None` while there is not enough of it
+  BatteryEstimateChanged {
+    drain_rate: Option<u32>,
+    minutes_to_empty: Option<u32>,
+    minutes_to_full: Option<u32>,
+  },
+
+  // Audio
+  AudioSessionStarted {
+    device: DeviceType,
+    app: String,
+  },
+  AudioSessionEnded {
+    device: DeviceType,
+    app: String,
+  },
+  DefaultDeviceChanged {
+    device_type: DeviceType,
+    device: AudioDevice,
+  },
+
+  // Network
+  NetworkAdapterChanged {
+    ethernet: bool,
+  },
+
+  // Windows and processes
+  ForegroundWindowChanged {
+    process: Option<String>,
+  },
+  MaximizedWindowsChanged {
+    processes: Vec<String>,
+  },
+  ProcessStarted {
+    name: String,
+  },
+  ProcessStopped {
+    name: String,
+  },
+  CpuLoadChanged {
+    percentage: u32,
+  },
+
+  // Time
+  ClockChanged {
+    time: LocalTime,
+  },
+
+  // App
+  ConfigChanged(Box<Config>),
+  /// Every setting the app changed was put back
+  ChangesReverted,
+}
+
+/// Fans every published event out to the subscribers and keeps the resulting machine state,
+/// so a late subscriber starts from the same picture as everyone else
+pub struct EventBus {
+  state: Mutex<MachineState>,
+  subscribers: Mutex<Vec<Sender<Event>>>,
+}
+
+impl EventBus {
+  pub const fn new() -> Self {
+    Self {
+      state: Mutex::new(MachineState::new()),
+      subscribers: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn state(&self) -> MachineState {
+    self.state.lock().unwrap().clone()
+  }
+
+  /// The current state and every event published after it
+  pub fn subscribe(&self) -> (MachineState, Receiver<Event>) {
+    let state = self.state.lock().unwrap();
+
+    let (sender, receiver) = channel();
+    self.subscribers.lock().unwrap().push(sender);
+
+    (state.clone(), receiver)
+  }
+
+  pub fn publish(&self, event: Event) {
+    trace!("{:?}", event);
+
+    let mut state = self.state.lock().unwrap();
+    state.apply(&event);
+
+    self
+      .subscribers
+      .lock()
+      .unwrap()
+      .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+  }
+}
@@ -0,0 +1,455 @@
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::Result;
+
+use super::{state::MachineState, Event};
+use crate::{
+  backend::{
+    types::{process_name, BatteryPresence, CpuTimes, DeviceType},
+    Backend,
+  },
+  log::warning,
+};
+
+/// How often the backend is read for changes
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The processor load is averaged over this many samples, so a short spike does not count
+pub const CPU_LOAD_SAMPLES: usize = 10;
+
+/// The processor load is rounded down to a multiple of this, so it does not change every sample
+pub const CPU_LOAD_STEP: u32 = 5;
+
+/// The parts of the machine a sampler can read, each behind its own backend calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+  Power,
+  Audio,
+  Network,
+  Windows,
+  Processes,
+  Cpu,
+  Clock,
+}
+
+pub const ALL_SOURCES: [Source; 7] = [
+  Source::Power,
+  Source::Audio,
+  Source::Network,
+  Source::Windows,
+  Source::Processes,
+  Source::Cpu,
+  Source::Clock,
+];
+
+/// A producer that reads the machine through the backend and turns every difference from the
+/// last read into events
+pub struct Sampler {
+  last: MachineState,
+  sources: Vec<Source>,
+  errors: Vec<String>,
+  cpu_times: Option<CpuTimes>,
+  cpu_loads: VecDeque<u32>,
+}
+
+impl Default for Sampler {
+  fn default() -> Self {
+    Self::new(MachineState::default())
+  }
+}
+
+impl Sampler {
+  pub fn new(state: MachineState) -> Self {
+    Self::with_sources(state, &ALL_SOURCES)
+  }
+
+  /// A sampler that only reads `sources` and leaves the rest of `state` alone
+  pub fn with_sources(state: MachineState, sources: &[Source]) -> Self {
+    Self {
+      last: state,
+      sources: sources.to_vec(),
+      errors: Vec::new(),
+      cpu_times: None,
+      cpu_loads: VecDeque::new(),
+    }
+  }
+
+  pub fn state(&self) -> &MachineState {
+    &self.last
+  }
+
+  /// Reads everything again. A value that cannot be read keeps its last known state.
+  fn read(&mut self, backend: &impl Backend) -> MachineState {
+    let mut state = self.last.clone();
+    let mut errors = Vec::new();
+    let reads = |source| self.sources.contains(&source);
+
+    let mut keep = |result: Result<()>| {
+      if let Err(e) = result {
+        errors.push(format!("{:#}", e));
+      }
+    };
+    let names = |names: Vec<String>| names.iter().map(|name| process_name(name)).collect();
+
+    if reads(Source::Power) {
+      keep(backend.power_status().map(|status| {
+        // Unknown values keep the last known ones
+        let plugged_in = status.ac_line.is_plugged_in();
+        if let Some(plugged_in) = plugged_in {
+          state.plugged_in = plugged_in;
+        }
+        if let Some(percentage) = status.remaining_percentage {
+          state.battery_percentage = percentage;
+        }
+        state.battery_saver = status.is_battery_saver_enabled;
+
+        state.battery = match status.battery {
+          BatteryPresence::Absent => {
+            // Without a battery the machine can only run on AC
+            state.plugged_in = true;
+            BatteryPresence::Absent
+          }
+          BatteryPresence::Present
+            if plugged_in.is_some() && status.remaining_percentage.is_some() =>
+          {
+            BatteryPresence::Present
+          }
+          _ => BatteryPresence::Unknown,
+        };
+      }));
+      keep(
+        backend
+          .active_power_scheme()
+          .map(|scheme| state.power_scheme = Some(scheme)),
+      );
+    }
+
+    if reads(Source::Audio) {
+      keep(
+        backend
+          .default_audio_device(DeviceType::Output)
+          .map(|device| state.default_output = Some(device)),
+      );
+      keep(
+        backend
+          .default_audio_device(DeviceType::Input)
+          .map(|device| state.default_input = Some(device)),
+      );
+      keep(
+        backend
+          .active_audio_applications(DeviceType::Input)
+          .map(|apps| state.input_applications = names(apps)),
+      );
+      keep(
+        backend
+          .active_audio_applications(DeviceType::Output)
+          .map(|apps| state.output_applications = names(apps)),
+      );
+    }
+
+    if reads(Source::Network) {
+      keep(
+        backend
+          .is_ethernet_plugged_in()
+          .map(|ethernet| state.ethernet = ethernet),
+      );
+    }
+
+    if reads(Source::Windows) {
+      keep(
+        backend
+          .foreground_window_process()
+          .map(|process| state.foreground_window = process.as_deref().map(process_name)),
+      );
+      keep(
+        backend
+          .maximized_window_processes()
+          .map(|processes| state.maximized_windows = names(processes)),
+      );
+    }
+
+    if reads(Source::Processes) {
+      keep(
+        backend
+          .process_names()
+          .map(|processes| state.processes = names(processes)),
+      );
+    }
+
+    if reads(Source::Clock) {
+      keep(backend.local_time().map(|time| state.local_time = time));
+    }
+
+    if self.sources.contains(&Source::Cpu) {
+      match backend.cpu_times() {
+        Ok(times) => state.cpu_load = self.cpu_load(times).unwrap_or(state.cpu_load),
+        Err(e) => errors.push(format!("{:#}", e)),
+      }
+    }
+
+    // Reported once, a broken source would otherwise repeat every second
+    for error in &errors {
+      if !self.errors.contains(error) {
+        warning!("Cannot read the machine state: {}", error);
+      }
+    }
+    self.errors = errors;
+
+    state
+  }
+
+  /// The average load up to `times`, once there is a read before it to compare with
+  fn cpu_load(&mut self, times: CpuTimes) -> Option<u32> {
+    let previous = self.cpu_times.replace(times)?;
+    let total = times.total.checked_sub(previous.total)?;
+    let idle = times.idle.checked_sub(previous.idle)?;
+    if total == 0 {
+      return None;
+    }
+
+    if self.cpu_loads.len() == CPU_LOAD_SAMPLES {
+      self.cpu_loads.pop_front();
+    }
+    self
+      .cpu_loads
+      .push_back((100 - idle.min(total) * 100 / total) as u32);
+
+    let average = self.cpu_loads.iter().sum::<u32>() / self.cpu_loads.len() as u32;
+    Some(average / CPU_LOAD_STEP * CPU_LOAD_STEP)
+  }
+
+  /// Reads the machine and returns what changed since the last call
+  pub fn sample(&mut self, backend: &impl Backend) -> Vec<Event> {
+    let state = self.read(backend);
+    let events = self.last.changes(&state);
+    self.last = state;
+    events
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend::{
+      fake::{FakeBackend, FakeCall},
+      types::AcLineState,
+      PowerBackend,
+    },
+    config::Config,
+    rules::{schemes, RuleEngine},
+  };
+
+  const POWER_SAVER: &str = "A1841308-3541-4FAB-BC81-F71556F20B4A";
+  const BALANCED: &str = "381B4222-F694-41F0-9685-FF5BB260DF2E";
+
+  /// The sampler and the rules thread, with the bus between them reduced to `apply`
+  struct Harness {
+    sampler: Sampler,
+    machine: MachineState,
+    engine: RuleEngine,
+  }
+
+  impl Harness {
+    fn new(config: &Config, backend: &FakeBackend) -> Self {
+      let mut engine = RuleEngine::new();
+      engine.configure(&schemes::with_schemes(
+        config,
+        &backend.power_schemes().unwrap(),
+      ));
+      Self {
+        sampler: Sampler::default(),
+        machine: MachineState::new(),
+        engine,
+      }
+    }
+
+    /// Samples `backend` and runs the rules at `secs`, returning the rules that fired or exited
+    fn step(&mut self, backend: &FakeBackend, secs: u64) -> Vec<(String, bool)> {
+      for event in self.sampler.sample(backend) {
+        self.machine.apply(&event);
+      }
+      assert_eq!(&self.machine, self.sampler.state());
+
+      self
+        .engine
+        .tick(&self.machine, backend, Duration::from_secs(secs))
+        .into_iter()
+        .map(|firing| (firing.rule, firing.entered))
+        .collect()
+    }
+  }
+
+  fn power_config() -> Config {
+    let mut config = Config::new();
+    config.power.enabled = true;
+    config.power.timer = 300;
+    config.power.percentage = 40;
+    config
+  }
+
+  fn unplug(backend: &FakeBackend, percentage: u32) {
+    backend.update(|state| {
+      state.power_status.ac_line = AcLineState::Offline;
+      state.power_status.remaining_percentage = Some(percentage);
+    });
+  }
+
+  fn fired(rule: &str) -> (String, bool) {
+    (rule.to_string(), true)
+  }
+
+  fn exited(rule: &str) -> (String, bool) {
+    (rule.to_string(), false)
+  }
+
+  #[test]
+  fn only_changes_become_events() {
+    let backend = FakeBackend::new();
+    let mut sampler = Sampler::default();
+    sampler.sample(&backend);
+    assert_eq!(sampler.sample(&backend), []);
+
+    unplug(&backend, 90);
+    assert_eq!(
+      sampler.sample(&backend),
+      [
+        Event::PowerSourceChanged { plugged_in: false },
+        Event::BatteryLevelChanged { percentage: 90 },
+      ]
+    );
+    assert_eq!(sampler.sample(&backend), []);
+  }
+
+  #[test]
+  fn a_failing_read_keeps_the_last_state() {
+    let backend = FakeBackend::new();
+    let mut sampler = Sampler::default();
+    sampler.sample(&backend);
+
+    backend.update(|state| state.failing = vec!["power_status"]);
+    unplug(&backend, 90);
+    assert_eq!(sampler.sample(&backend), []);
+    assert!(sampler.state().plugged_in);
+
+    backend.update(|state| state.failing.clear());
+    assert_eq!(sampler.sample(&backend).len(), 2);
+  }
+
+  #[test]
+  fn unplugging_on_a_low_battery_and_plugging_back_in() {
+    let backend = FakeBackend::new();
+    let mut harness = Harness::new(&power_config(), &backend);
+    assert_eq!(harness.step(&backend, 0), []);
+
+    unplug(&backend, 30);
+    assert_eq!(
+      harness.step(&backend, 1),
+      [
+        fired("Power saver on low battery"),
+        fired("Heavy startup apps off on battery"),
+      ]
+    );
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(POWER_SAVER.to_string())]
+    );
+    // The scheme the rule switched to comes back as a change, which fires nothing
+    assert_eq!(harness.step(&backend, 2), []);
+
+    // The low battery rule has no exit actions, so leaving it is not reported
+    backend.update(|state| state.power_status.ac_line = AcLineState::Online);
+    assert_eq!(
+      harness.step(&backend, 3),
+      [
+        fired("Performance when plugged in"),
+        exited("Heavy startup apps off on battery"),
+      ]
+    );
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(BALANCED.to_string())]
+    );
+  }
+
+  #[test]
+  fn the_battery_timer_runs_between_samples() {
+    let backend = FakeBackend::new();
+    let mut harness = Harness::new(&power_config(), &backend);
+    harness.step(&backend, 0);
+
+    unplug(&backend, 90);
+    assert_eq!(
+      harness.step(&backend, 10),
+      [fired("Heavy startup apps off on battery")]
+    );
+    assert_eq!(harness.step(&backend, 310), []);
+    assert_eq!(
+      harness.step(&backend, 311),
+      [fired("Power saver after a while on battery")]
+    );
+  }
+
+  #[test]
+  fn an_unknown_power_source_holds_the_power_rules() {
+    let backend = FakeBackend::new();
+    let mut harness = Harness::new(&power_config(), &backend);
+    harness.step(&backend, 0);
+
+    backend.update(|state| {
+      state.power_status.ac_line = AcLineState::Unknown;
+      state.power_status.remaining_percentage = Some(30);
+    });
+    assert_eq!(harness.step(&backend, 1), []);
+    assert_eq!(harness.machine.battery, BatteryPresence::Unknown);
+    assert!(harness.machine.plugged_in);
+
+    unplug(&backend, 30);
+    assert_eq!(
+      harness.step(&backend, 2),
+      [
+        fired("Power saver on low battery"),
+        fired("Heavy startup apps off on battery"),
+      ]
+    );
+  }
+
+  #[test]
+  fn a_call_switches_to_the_headphones_and_back() {
+    let backend = FakeBackend::new();
+    let mut config = Config::new();
+    config.microphone.enabled = true;
+    config.microphone.apps = vec!["discord.exe".to_string()];
+    let mut harness = Harness::new(&config, &backend);
+    harness.step(&backend, 0);
+
+    backend.update(|state| state.input_applications = vec!["Discord.exe".to_string()]);
+    assert_eq!(
+      harness.step(&backend, 1),
+      [fired("Headphones while using the microphone")]
+    );
+    assert_eq!(harness.machine.input_applications, ["discord".to_string()]);
+    assert_eq!(harness.step(&backend, 2), []);
+    assert_eq!(
+      harness
+        .machine
+        .default_output
+        .as_ref()
+        .map(|d| d.id.as_str()),
+      Some("headphones")
+    );
+
+    backend.update(|state| state.input_applications.clear());
+    assert_eq!(
+      harness.step(&backend, 3),
+      [exited("Headphones while using the microphone")]
+    );
+    assert_eq!(
+      backend.take_calls(),
+      [
+        FakeCall::SetDefaultOutput("headphones".to_string()),
+        FakeCall::SetDefaultOutput("speakers".to_string()),
+      ]
+    );
+  }
+}
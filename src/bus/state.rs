@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+
+use super::Event;
+use crate::backend::types::{AudioDevice, BatteryPresence, DeviceType, LocalTime, PowerScheme};
+
+/// The machine as last reported on the bus. Process names are kept as `process_name` returns
+/// them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MachineState {
+  pub plugged_in: bool,
+  pub battery_percentage: u32,
+  pub battery_saver: bool,
+  /// `Unknown` while Windows cannot tell the power source or the battery level, which holds
+  /// every decision about power. `plugged_in` and `battery_percentage` are then the last known.
+  pub battery: BatteryPresence,
+  pub power_scheme: Option<PowerScheme>,
+  /// Percent per hour while draining, estimated from the battery history
+  pub drain_rate: Option<u32>,
+  pub minutes_to_empty: Option<u32>,
+  pub minutes_to_full: Option<u32>,
+
+  pub default_output: Option<AudioDevice>,
+  pub default_input: Option<AudioDevice>,
+  pub input_applications: Vec<String>,
+  pub output_applications: Vec<String>,
+
+  pub ethernet: bool,
+
+  pub foreground_window: Option<String>,
+  pub maximized_windows: Vec<String>,
+  pub processes: Vec<String>,
+  /// Average processor load over the last few samples, in steps of `CPU_LOAD_STEP`
+  pub cpu_load: u32,
+
+  pub local_time: LocalTime,
+}
+
+impl Default for MachineState {
+  fn default() -> Self {
+    MachineState::new()
+  }
+}
+
+impl MachineState {
+  pub const fn new() -> Self {
+    MachineState {
+      plugged_in: true,
+      battery_percentage: 100,
+      battery_saver: false,
+      battery: BatteryPresence::Present,
+      power_scheme: None,
+      drain_rate: None,
+      minutes_to_empty: None,
+      minutes_to_full: None,
+
+      default_output: None,
+      default_input: None,
+      input_applications: Vec::new(),
+      output_applications: Vec::new(),
+
+      ethernet: false,
+
+      foreground_window: None,
+      maximized_windows: Vec::new(),
+      processes: Vec::new(),
+      cpu_load: 0,
+
+      local_time: LocalTime { hour: 0, minute: 0 },
+    }
+  }
+
+  /// Whether decisions about power can be made, the power source and battery level being known
+  pub fn power_known(&self) -> bool {
+    self.battery != BatteryPresence::Unknown
+  }
+
+  /// Whether the machine has a battery and its level is known
+  pub fn has_battery(&self) -> bool {
+    self.battery == BatteryPresence::Present
+  }
+
+  pub fn audio_applications(&self, device: DeviceType) -> &[String] {
+    match device {
+      DeviceType::Input => &self.input_applications,
+      DeviceType::Output => &self.output_applications,
+    }
+  }
+
+  pub fn apply(&mut self, event: &Event) {
+    match event {
+      Event::PowerSourceChanged { plugged_in } => self.plugged_in = *plugged_in,
+      Event::BatteryLevelChanged { percentage } => self.battery_percentage = *percentage,
+      Event::BatterySaverChanged { enabled } => self.battery_saver = *enabled,
+      Event::BatteryPresenceChanged { presence } => self.battery = *presence,
+      Event::PowerSchemeChanged { scheme } => self.power_scheme = Some(scheme.clone()),
+      Event::BatteryEstimateChanged {
+        drain_rate,
+        minutes_to_empty,
+        minutes_to_full,
+      } => {
+        self.drain_rate = *drain_rate;
+        self.minutes_to_empty = *minutes_to_empty;
+        self.minutes_to_full = *minutes_to_full;
+      }
+
+      Event::AudioSessionStarted { device, app } => {
+        let applications = match device {
+          DeviceType::Input => &mut self.input_applications,
+          DeviceType::Output => &mut self.output_applications,
+        };
+        if !applications.contains(app) {
+          applications.push(app.clone());
+        }
+      }
+      Event::AudioSessionEnded { device, app } => match device {
+        DeviceType::Input => self.input_applications.retain(|a| a != app),
+        DeviceType::Output => self.output_applications.retain(|a| a != app),
+      },
+      Event::DefaultDeviceChanged {
+        device_type,
+        device,
+      } => match device_type {
+        DeviceType::Input => self.default_input = Some(device.clone()),
+        DeviceType::Output => self.default_output = Some(device.clone()),
+      },
+
+      Event::NetworkAdapterChanged { ethernet } => self.ethernet = *ethernet,
+
+      Event::ForegroundWindowChanged { process } => self.foreground_window = process.clone(),
+      Event::MaximizedWindowsChanged { processes } => self.maximized_windows = processes.clone(),
+      Event::ProcessStarted { name } => {
+        if !self.processes.contains(name) {
+          self.processes.push(name.clone());
+        }
+      }
+      Event::ProcessStopped { name } => self.processes.retain(|p| p != name),
+      Event::CpuLoadChanged { percentage } => self.cpu_load = *percentage,
+
+      Event::ClockChanged { time } => self.local_time = *time,
+
+      Event::ConfigChanged(_) | Event::ChangesReverted => {}
+    }
+  }
+
+  /// The events that turn `self` into `new`
+  pub fn changes(&self, new: &MachineState) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    if self.plugged_in != new.plugged_in {
+      events.push(Event::PowerSourceChanged {
+        plugged_in: new.plugged_in,
+      });
+    }
+    if self.battery_percentage != new.battery_percentage {
+      events.push(Event::BatteryLevelChanged {
+        percentage: new.battery_percentage,
+      });
+    }
+    if self.battery_saver != new.battery_saver {
+      events.push(Event::BatterySaverChanged {
+        enabled: new.battery_saver,
+      });
+    }
+    if self.battery != new.battery {
+      events.push(Event::BatteryPresenceChanged {
+        presence: new.battery,
+      });
+    }
+    if let Some(scheme) = new
+      .power_scheme
+      .as_ref()
+      .filter(|s| self.power_scheme.as_ref() != Some(s))
+    {
+      events.push(Event::PowerSchemeChanged {
+        scheme: scheme.clone(),
+      });
+    }
+    if (self.drain_rate, self.minutes_to_empty, self.minutes_to_full)
+      != (new.drain_rate, new.minutes_to_empty, new.minutes_to_full)
+    {
+      events.push(Event::BatteryEstimateChanged {
+        drain_rate: new.drain_rate,
+        minutes_to_empty: new.minutes_to_empty,
+        minutes_to_full: new.minutes_to_full,
+      });
+    }
+
+    for device in [DeviceType::Input, DeviceType::Output] {
+      let (old_apps, new_apps) = (
+        self.audio_applications(device),
+        new.audio_applications(device),
+      );
+      for app in new_apps.iter().filter(|app| !old_apps.contains(app)) {
+        events.push(Event::AudioSessionStarted {
+          device,
+          app: app.clone(),
+        });
+      }
+      for app in old_apps.iter().filter(|app| !new_apps.contains(app)) {
+        events.push(Event::AudioSessionEnded {
+          device,
+          app: app.clone(),
+        });
+      }
+    }
+    for (device_type, old, new) in [
+      (DeviceType::Input, &self.default_input, &new.default_input),
+      (
+        DeviceType::Output,
+        &self.default_output,
+        &new.default_output,
+      ),
+    ] {
+      if let Some(device) = new.as_ref().filter(|d| old.as_ref() != Some(d)) {
+        events.push(Event::DefaultDeviceChanged {
+          device_type,
+          device: device.clone(),
+        });
+      }
+    }
+
+    if self.ethernet != new.ethernet {
+      events.push(Event::NetworkAdapterChanged {
+        ethernet: new.ethernet,
+      });
+    }
+
+    if self.foreground_window != new.foreground_window {
+      events.push(Event::ForegroundWindowChanged {
+        process: new.foreground_window.clone(),
+      });
+    }
+    if self.maximized_windows != new.maximized_windows {
+      events.push(Event::MaximizedWindowsChanged {
+        processes: new.maximized_windows.clone(),
+      });
+    }
+    for name in new.processes.iter().filter(|p| !self.processes.contains(p)) {
+      events.push(Event::ProcessStarted { name: name.clone() });
+    }
+    for name in self.processes.iter().filter(|p| !new.processes.contains(p)) {
+      events.push(Event::ProcessStopped { name: name.clone() });
+    }
+
+    if self.cpu_load != new.cpu_load {
+      events.push(Event::CpuLoadChanged {
+        percentage: new.cpu_load,
+      });
+    }
+
+    if self.local_time != new.local_time {
+      events.push(Event::ClockChanged {
+        time: new.local_time,
+      });
+    }
+
+    events
+  }
+}
@@ -0,0 +1,768 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+  backend::{
+    dry_run::Planned,
+    types::{AcLineState, BatteryPresence, DeviceType},
+    Backend,
+  },
+  battery::{self, format_duration, history_path},
+  config::{
+    layers::{self, LayeredConfig},
+    Config,
+  },
+  log::timestamp,
+  rules::runtime::unix_now,
+};
+
+/// The first argument of every command, anything else starts the tray app
+pub const COMMANDS: [&str; 11] = [
+  "status", "power", "audio", "display", "wifi", "startup", "config", "toggle", "profile",
+  "reload", "dry-run",
+];
+
+/// The features `toggle` can switch
+pub const FEATURES: [&str; 6] = [
+  "microphone",
+  "power",
+  "ethernet",
+  "taskbar",
+  "autostart",
+  "dry-run",
+];
+
+pub const EXIT_OK: i32 = 0;
+/// The command ran into an error
+pub const EXIT_FAILED: i32 = 1;
+/// The command line itself is wrong
+pub const EXIT_USAGE: i32 = 2;
+
+const USAGE: &str = "Usage: PwccaAuto [--json] [--dry-run] <command>
+
+Commands:
+  status
+  power list | set <name or guid> | history [--csv]
+  audio list | set-default <id or name>
+  display rates | set <hz>
+  wifi scan | on | off
+  startup list | enable <name> | disable <name>
+  config get <key> | set <key> <value>
+  toggle microphone | power | ethernet | taskbar | autostart | dry-run
+  profile <name>
+  reload
+  dry-run";
+
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}\n\n{}", self.0, USAGE)
+  }
+}
+
+impl std::error::Error for UsageError {}
+
+fn usage(message: impl Into<String>) -> anyhow::Error {
+  UsageError(message.into()).into()
+}
+
+/// The running tray app, for the commands that change it rather than just the files
+pub trait Instance {
+  fn config(&self) -> Result<Config>;
+  /// Applies `f` to the running config, keeping it unchanged if `f` fails
+  fn update(&self, f: &mut dyn FnMut(&mut Config) -> Result<()>) -> Result<Config>;
+  /// Reads the config files again. Returns whether anything changed.
+  fn reload(&self) -> Result<bool>;
+  /// The changes a dry run kept from happening
+  fn planned(&self) -> Result<Vec<Planned>>;
+}
+
+/// Stands in for the tray app when it is not running by changing the per-user file directly
+pub struct Offline<'a>(pub &'a LayeredConfig);
+
+impl Instance for Offline<'_> {
+  fn config(&self) -> Result<Config> {
+    Ok(self.0.resolve()?.config)
+  }
+
+  fn update(&self, f: &mut dyn FnMut(&mut Config) -> Result<()>) -> Result<Config> {
+    let mut config = self.0.resolve()?.config;
+    f(&mut config)?;
+    self.0.write(&config)?;
+    Ok(config)
+  }
+
+  fn reload(&self) -> Result<bool> {
+    Err(anyhow!("PwccaAuto is not running"))
+  }
+
+  fn planned(&self) -> Result<Vec<Planned>> {
+    Err(anyhow!("PwccaAuto is not running"))
+  }
+}
+
+/// Everything a command printed and its exit code, as sent back to a forwarding launch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+  pub code: i32,
+  pub stdout: String,
+  pub stderr: String,
+}
+
+impl Output {
+  pub fn print(&self) {
+    if !self.stdout.is_empty() {
+      println!("{}", self.stdout);
+    }
+    if !self.stderr.is_empty() {
+      eprintln!("{}", self.stderr);
+    }
+  }
+
+  /// Adds what a dry run kept the command from doing, on stderr so `--json` output stays valid
+  pub fn note_planned(&mut self, planned: &[Planned]) {
+    for planned in planned {
+      if !self.stderr.is_empty() {
+        self.stderr.push('\n');
+      }
+      self.stderr.push_str(&planned.to_string());
+    }
+  }
+}
+
+/// What a command prints: `value` with `--json`, `text` otherwise
+struct Reply {
+  value: Value,
+  text: String,
+}
+
+impl Reply {
+  fn new(value: Value, text: impl Into<String>) -> Self {
+    Self {
+      value,
+      text: text.into(),
+    }
+  }
+}
+
+fn on_off(on: bool) -> &'static str {
+  if on {
+    "on"
+  } else {
+    "off"
+  }
+}
+
+/// Runs one command. `backend` is `None` where the machine cannot be controlled, leaving only
+/// the config commands.
+pub fn execute(
+  args: &[String],
+  layers: &LayeredConfig,
+  backend: Option<&dyn Backend>,
+  instance: &dyn Instance,
+) -> Output {
+  let json = args.iter().any(|arg| arg == "--json");
+  let args = args
+    .iter()
+    .filter(|arg| *arg != "--json")
+    .map(String::as_str)
+    .collect::<Vec<_>>();
+
+  match dispatch(&args, layers, backend, instance) {
+    Ok(reply) => Output {
+      code: EXIT_OK,
+      stdout: if json {
+        format!("{:#}", reply.value)
+      } else {
+        reply.text
+      },
+      stderr: String::new(),
+    },
+    Err(e) => {
+      let code = if is_usage_error(&e) {
+        EXIT_USAGE
+      } else {
+        EXIT_FAILED
+      };
+
+      if json {
+        Output {
+          code,
+          stdout: format!("{:#}", json!({ "error": format!("{:#}", e) })),
+          stderr: String::new(),
+        }
+      } else {
+        Output {
+          code,
+          stdout: String::new(),
+          stderr: format!("{:#}", e),
+        }
+      }
+    }
+  }
+}
+
+/// What a dry run says a command was for
+pub fn reason(args: &[String]) -> String {
+  let command = args
+    .iter()
+    .filter(|arg| *arg != "--json")
+    .map(String::as_str)
+    .collect::<Vec<_>>();
+  format!("of the command `{}`", command.join(" "))
+}
+
+/// Runs one command and returns what `--json` would print
+pub fn evaluate(
+  args: &[&str],
+  layers: &LayeredConfig,
+  backend: Option<&dyn Backend>,
+  instance: &dyn Instance,
+) -> Result<Value> {
+  Ok(dispatch(args, layers, backend, instance)?.value)
+}
+
+/// Whether `e` is about the command line rather than the machine
+pub fn is_usage_error(e: &anyhow::Error) -> bool {
+  e.is::<UsageError>()
+}
+
+fn dispatch(
+  args: &[&str],
+  layers: &LayeredConfig,
+  backend: Option<&dyn Backend>,
+  instance: &dyn Instance,
+) -> Result<Reply> {
+  match args {
+    ["config", rest @ ..] => config(rest, instance),
+    ["toggle", feature] => toggle(instance, feature),
+    ["profile", name] => profile(instance, name),
+    ["reload"] => reload(instance),
+    ["dry-run"] => dry_run(instance),
+    ["power", "history", flags @ ..] => power_history(layers, flags),
+    [] => Err(usage("Missing command")),
+    _ => match backend {
+      Some(backend) => machine(args, backend, instance),
+      None => Err(anyhow!("{} is only available on Windows", args[0])),
+    },
+  }
+}
+
+fn toggle(instance: &dyn Instance, feature: &str) -> Result<Reply> {
+  let config = instance.update(&mut |config| {
+    match feature {
+      "microphone" => config.toggle_microphone(),
+      "power" => config.toggle_power(),
+      "ethernet" => config.toggle_ethernet(),
+      "taskbar" => config.toggle_taskbar(),
+      "autostart" => config.toggle_autostart(),
+      "dry-run" => config.toggle_dry_run(),
+      _ => {
+        return Err(usage(format!(
+          "Unknown feature {}, expected one of {}",
+          feature,
+          FEATURES.join(", ")
+        )))
+      }
+    }
+    Ok(())
+  })?;
+
+  let enabled = match feature {
+    "microphone" => config.microphone.enabled,
+    "power" => config.power.enabled,
+    "ethernet" => config.ethernet,
+    "taskbar" => config.taskbar.enabled,
+    "autostart" => config.autostart.enabled,
+    _ => config.dry_run,
+  };
+  Ok(Reply::new(
+    json!({ "feature": feature, "enabled": enabled }),
+    format!("{} {}", feature, on_off(enabled)),
+  ))
+}
+
+fn reload(instance: &dyn Instance) -> Result<Reply> {
+  let changed = instance.reload()?;
+  Ok(Reply::new(
+    json!({ "changed": changed }),
+    if changed {
+      "Reloaded the config"
+    } else {
+      "The config is unchanged"
+    },
+  ))
+}
+
+fn dry_run(instance: &dyn Instance) -> Result<Reply> {
+  let enabled = instance.config()?.dry_run;
+  let planned = instance.planned()?;
+
+  let mut text = vec![format!("Dry run {}", on_off(enabled))];
+  text.extend(
+    planned
+      .iter()
+      .map(|planned| format!("{} {}", planned.time, planned)),
+  );
+  Ok(Reply::new(
+    json!({ "enabled": enabled, "planned": planned }),
+    text.join("\n"),
+  ))
+}
+
+fn profile(instance: &dyn Instance, name: &str) -> Result<Reply> {
+  instance.update(&mut |config| config.switch_profile(name))?;
+  Ok(Reply::new(
+    json!({ "profile": name }),
+    format!("Switched to {}", name),
+  ))
+}
+
+fn machine(args: &[&str], backend: &dyn Backend, instance: &dyn Instance) -> Result<Reply> {
+  match args {
+    ["status"] => status(backend, instance),
+
+    ["power", "list"] => power_list(backend),
+    ["power", "set", wanted] => power_set(backend, wanted),
+
+    ["audio", "list"] => audio_list(backend),
+    ["audio", "set-default", wanted] => audio_set_default(backend, wanted),
+
+    ["display", "rates"] => display_rates(backend),
+    ["display", "set", hz] => display_set(backend, hz),
+
+    ["wifi", "scan"] => wifi_scan(backend),
+    ["wifi", on @ ("on" | "off")] => {
+      backend.set_wifi_state(*on == "on")?;
+      Ok(Reply::new(
+        json!({ "wifi": *on == "on" }),
+        format!("Wi-Fi {}", on),
+      ))
+    }
+
+    ["startup", "list"] => startup_list(backend),
+    ["startup", state @ ("enable" | "disable"), name] => {
+      let enabled = *state == "enable";
+      backend.set_startup_item_state(name, enabled)?;
+      Ok(Reply::new(
+        json!({ "name": name, "enabled": enabled }),
+        format!("{} {}d", name, state),
+      ))
+    }
+
+    _ => Err(usage(format!("Unknown command {}", args.join(" ")))),
+  }
+}
+
+fn status(backend: &dyn Backend, instance: &dyn Instance) -> Result<Reply> {
+  let power = backend.power_status()?;
+  let scheme = backend.active_power_scheme()?;
+  let output = backend.default_audio_device(DeviceType::Output)?;
+  let input = backend.default_audio_device(DeviceType::Input)?;
+  let ethernet = backend.is_ethernet_plugged_in()?;
+  let wifi = backend.wifi_state()?;
+  let refresh_rate = backend.refresh_rate()?;
+  let profile = instance.config()?.profile;
+
+  let text = [
+    format!(
+      "Power:        {}, {}, battery saver {}",
+      match power.ac_line {
+        AcLineState::Online => "plugged in",
+        AcLineState::Offline => "on battery",
+        AcLineState::Unknown => "power source unknown",
+      },
+      match (power.battery, power.remaining_percentage) {
+        (BatteryPresence::Absent, _) => "no battery".to_string(),
+        (BatteryPresence::Present, Some(percentage)) if power.is_charging => {
+          format!("{}% charging", percentage)
+        }
+        (BatteryPresence::Present, Some(percentage)) => format!("{}%", percentage),
+        _ => "battery unknown".to_string(),
+      },
+      on_off(power.is_battery_saver_enabled)
+    ),
+    format!("Scheme:       {} {}", scheme.name, scheme.guid),
+    format!("Output:       {} ({})", output.name, output.kind),
+    format!("Input:        {} ({})", input.name, input.kind),
+    format!(
+      "Ethernet:     {}",
+      if ethernet {
+        "connected"
+      } else {
+        "disconnected"
+      }
+    ),
+    format!("Wi-Fi:        {}", on_off(wifi)),
+    format!("Refresh rate: {} Hz", refresh_rate),
+    format!("Profile:      {}", profile),
+  ]
+  .join("\n");
+
+  Ok(Reply::new(
+    json!({
+      "power": power,
+      "power_scheme": scheme,
+      "default_output": output,
+      "default_input": input,
+      "ethernet": ethernet,
+      "wifi": wifi,
+      "refresh_rate": refresh_rate,
+      "profile": profile,
+    }),
+    text,
+  ))
+}
+
+fn power_list(backend: &dyn Backend) -> Result<Reply> {
+  let schemes = backend.power_schemes()?;
+  let active = backend.active_power_scheme()?;
+
+  let text = schemes
+    .iter()
+    .map(|scheme| {
+      let marker = if scheme.guid == active.guid { "*" } else { " " };
+      format!("{} {} {}", marker, scheme.guid, scheme.name)
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+  let value = schemes
+    .iter()
+    .map(|scheme| {
+      json!({
+        "name": scheme.name,
+        "guid": scheme.guid,
+        "active": scheme.guid == active.guid,
+      })
+    })
+    .collect();
+
+  Ok(Reply::new(value, text))
+}
+
+fn power_set(backend: &dyn Backend, wanted: &str) -> Result<Reply> {
+  let schemes = backend.power_schemes()?;
+  let scheme = schemes
+    .iter()
+    .find(|scheme| scheme.name == wanted || scheme.guid.eq_ignore_ascii_case(wanted))
+    .ok_or_else(|| anyhow!("Cannot find power scheme {}", wanted))?;
+
+  backend.set_active_power_scheme(scheme)?;
+  Ok(Reply::new(
+    json!(scheme),
+    format!("Power scheme set to {}", scheme.name),
+  ))
+}
+
+/// Reads the battery history the tray app keeps, so it works whether the app runs or not
+fn power_history(layers: &LayeredConfig, flags: &[&str]) -> Result<Reply> {
+  let csv = match flags {
+    [] => false,
+    ["--csv"] => true,
+    _ => return Err(usage(format!("Unknown option {}", flags.join(" ")))),
+  };
+
+  let readings = battery::load(&history_path(&layers.user))?;
+  let estimate = battery::estimate(&readings, unix_now().as_secs());
+  let minutes = |left: Option<std::time::Duration>| left.map(|left| left.as_secs() / 60);
+
+  let value = json!({
+    "readings": readings,
+    "estimate": estimate.map(|estimate| json!({
+      "rate": estimate.rate,
+      "minutes_to_empty": minutes(estimate.time_to_empty),
+      "minutes_to_full": minutes(estimate.time_to_full),
+    })),
+  });
+  if csv {
+    return Ok(Reply::new(value, battery::to_csv(&readings)));
+  }
+
+  let mut text = readings
+    .iter()
+    .map(|reading| {
+      format!(
+        "{} {:>3}% {}",
+        timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_secs(reading.at)),
+        reading.percentage,
+        if reading.plugged_in {
+          "plugged in"
+        } else {
+          "on battery"
+        }
+      )
+    })
+    .collect::<Vec<_>>();
+  text.push(match estimate {
+    Some(estimate) => {
+      let left = match (estimate.time_to_empty, estimate.time_to_full) {
+        (Some(left), _) => format!(", {} left", format_duration(left)),
+        (_, Some(left)) => format!(", full in {}", format_duration(left)),
+        _ => String::new(),
+      };
+      format!("Rate: {:+.1}% per hour{}", estimate.rate, left)
+    }
+    None => "Rate: not enough recent history".to_string(),
+  });
+  Ok(Reply::new(value, text.join("\n")))
+}
+
+fn audio_list(backend: &dyn Backend) -> Result<Reply> {
+  let mut value = json!({});
+  let mut text = Vec::new();
+
+  for (device_type, key) in [
+    (DeviceType::Output, "outputs"),
+    (DeviceType::Input, "inputs"),
+  ] {
+    let devices = backend.audio_devices(device_type)?;
+    let default = backend.default_audio_device(device_type)?;
+
+    text.push(format!("{:?}s:", device_type));
+    for device in &devices {
+      let marker = if device.id == default.id { "*" } else { " " };
+      text.push(format!(
+        "{} {} ({}) {}",
+        marker, device.name, device.kind, device.id
+      ));
+    }
+
+    value[key] = devices
+      .iter()
+      .map(|device| {
+        json!({
+          "id": device.id,
+          "kind": device.kind,
+          "name": device.name,
+          "default": device.id == default.id,
+        })
+      })
+      .collect();
+  }
+
+  Ok(Reply::new(value, text.join("\n")))
+}
+
+fn audio_set_default(backend: &dyn Backend, wanted: &str) -> Result<Reply> {
+  let outputs = backend.audio_devices(DeviceType::Output)?;
+  let device = outputs
+    .iter()
+    .find(|device| device.id == wanted)
+    .or_else(|| outputs.iter().find(|device| device.name == wanted))
+    .ok_or_else(|| anyhow!("Cannot find audio output {}", wanted))?;
+
+  backend.set_default_output(device)?;
+  Ok(Reply::new(
+    json!(device),
+    format!("Default output set to {}", device.name),
+  ))
+}
+
+fn display_rates(backend: &dyn Backend) -> Result<Reply> {
+  let rates = backend.refresh_rates()?;
+  let current = backend.refresh_rate()?;
+
+  let text = rates
+    .iter()
+    .map(|rate| {
+      let marker = if *rate == current { "*" } else { " " };
+      format!("{} {} Hz", marker, rate)
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  Ok(Reply::new(
+    json!({ "rates": rates, "current": current }),
+    text,
+  ))
+}
+
+fn display_set(backend: &dyn Backend, hz: &str) -> Result<Reply> {
+  let hz = hz
+    .parse::<u32>()
+    .map_err(|_| usage(format!("{} is not a refresh rate", hz)))?;
+  if !backend.refresh_rates()?.contains(&hz) {
+    return Err(anyhow!("The display does not support {} Hz", hz));
+  }
+
+  backend.set_refresh_rate(hz)?;
+  Ok(Reply::new(
+    json!({ "refresh_rate": hz }),
+    format!("Refresh rate set to {} Hz", hz),
+  ))
+}
+
+fn wifi_scan(backend: &dyn Backend) -> Result<Reply> {
+  let networks = backend.wifi_networks()?;
+
+  let text = networks
+    .iter()
+    .map(|network| {
+      let marker = if network.connected { "*" } else { " " };
+      format!(
+        "{} {:>3}% {:<24} {}",
+        marker, network.signal_quality, network.name, network.authentication
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  Ok(Reply::new(json!(networks), text))
+}
+
+fn startup_list(backend: &dyn Backend) -> Result<Reply> {
+  let items = backend.startup_items()?;
+
+  let text = items
+    .iter()
+    .map(|item| format!("[{}] {}", if item.enabled { "x" } else { " " }, item.name))
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  Ok(Reply::new(json!(items), text))
+}
+
+fn config(args: &[&str], instance: &dyn Instance) -> Result<Reply> {
+  match args {
+    ["get", key] => {
+      // What the running tray app uses, which the files may not show yet
+      let value = layers::key_value(&instance.config()?, key)?
+        .ok_or_else(|| anyhow!("{} is not set", key))?;
+      let text = match &value {
+        Value::String(text) => text.clone(),
+        value => format!("{:#}", value),
+      };
+      Ok(Reply::new(value, text))
+    }
+    ["set", key, value] => {
+      // Through the instance, so a running tray app applies and saves it like any other change
+      let config = instance.update(&mut |config| {
+        *config = layers::with_key(config, key, layers::parse_value(value))?;
+        Ok(())
+      })?;
+      let value = layers::key_value(&config, key)?.unwrap_or(Value::Null);
+      Ok(Reply::new(
+        json!({ "key": key, "value": value }),
+        format!("{} = {}", key, value),
+      ))
+    }
+    _ => Err(usage(format!("Unknown command config {}", args.join(" ")))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend::fake::FakeBackend,
+    config::{
+      layers::{Layer, Override},
+      store::ConfigStore,
+    },
+    testing::{temp_dir, Running},
+  };
+
+  fn layers(name: &str) -> LayeredConfig {
+    let dir = temp_dir(name);
+    LayeredConfig {
+      machine: dir.join("machine.json"),
+      user: dir.join("user.json"),
+      overrides: Vec::new(),
+    }
+  }
+
+  fn run(args: &[&str], layers: &LayeredConfig, instance: &dyn Instance) -> Output {
+    let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    execute(&args, layers, None, instance)
+  }
+
+  #[test]
+  fn config_set_changes_the_running_config() {
+    let layers = layers("cli-set-running");
+    let running = Running(ConfigStore::new(Config::new()));
+    let changes = running.0.subscribe();
+
+    let output = run(&["config", "set", "power.timer", "120"], &layers, &running);
+    assert_eq!(output.code, EXIT_OK, "{}", output.stderr);
+    assert_eq!(output.stdout, "power.timer = 120");
+    assert_eq!(running.0.snapshot().power.timer, 120);
+    assert_eq!(changes.try_recv().unwrap().power.timer, 120);
+
+    // Saving is left to the running app
+    assert!(!layers.user.exists());
+  }
+
+  #[test]
+  fn config_get_and_status_show_the_running_config() {
+    let layers = layers("cli-get-running");
+    std::fs::write(
+      &layers.user,
+      r#"{"profile": "Home", "power": {"timer": 60}}"#,
+    )
+    .unwrap();
+    let mut config = layers.resolve().unwrap().config;
+    config.profile = "Work".to_string();
+    config.power.timer = 120;
+    let running = Running(ConfigStore::new(config));
+
+    let output = run(&["config", "get", "power.timer"], &layers, &running);
+    assert_eq!((output.code, output.stdout.as_str()), (EXIT_OK, "120"));
+
+    let output = run(&["config", "get", "profile"], &layers, &Offline(&layers));
+    assert_eq!((output.code, output.stdout.as_str()), (EXIT_OK, "Home"));
+
+    let backend = FakeBackend::new();
+    let args = ["status".to_string(), "--json".to_string()];
+    let output = execute(&args, &layers, Some(&backend), &running);
+    assert_eq!(output.code, EXIT_OK, "{}", output.stderr);
+    let status: Value = serde_json::from_str(&output.stdout).unwrap();
+    assert_eq!(status["profile"], "Work");
+  }
+
+  #[test]
+  fn config_set_writes_the_user_file_when_not_running() {
+    let layers = layers("cli-set-offline");
+
+    let output = run(
+      &["config", "set", "power.timer", "120"],
+      &layers,
+      &Offline(&layers),
+    );
+    assert_eq!(output.code, EXIT_OK, "{}", output.stderr);
+    assert_eq!(layers.resolve().unwrap().config.power.timer, 120);
+  }
+
+  #[test]
+  fn config_set_names_the_override_that_blocks_it() {
+    let mut layers = layers("cli-set-overridden");
+    layers.overrides = vec![Override::parse(Layer::Env, "power.timer=60").unwrap()];
+
+    let output = run(
+      &["config", "set", "power.timer", "120"],
+      &layers,
+      &Offline(&layers),
+    );
+    assert_eq!(output.code, EXIT_FAILED);
+    assert!(
+      output.stderr.contains("PWCCA_POWER__TIMER"),
+      "{}",
+      output.stderr
+    );
+    assert!(!layers.user.exists());
+  }
+
+  #[test]
+  fn config_set_keeps_the_config_on_an_invalid_value() {
+    let layers = layers("cli-set-invalid");
+    let running = Running(ConfigStore::new(Config::new()));
+
+    let output = run(&["config", "set", "power.timer", "soon"], &layers, &running);
+    assert_eq!(output.code, EXIT_FAILED);
+    assert_eq!(running.0.snapshot(), Config::new());
+  }
+}
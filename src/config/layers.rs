@@ -0,0 +1,733 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt::Display,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+
+use super::{
+  migrate::{self, Coverage, CONFIG_VERSION},
+  persist,
+  validate::{self, ConfigError, Diagnostic, JsonPath, Segment},
+  Config,
+};
+use crate::log::warning;
+
+/// Where a config value came from, lowest priority first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+  Default,
+  Machine,
+  User,
+  Env,
+  Cli,
+}
+
+impl Display for Layer {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let name = match self {
+      Layer::Default => "default",
+      Layer::Machine => "machine",
+      Layer::User => "user",
+      Layer::Env => "env",
+      Layer::Cli => "cli",
+    };
+    f.pad(name)
+  }
+}
+
+/// Top level keys a layer replaces as a whole instead of merging, so entries can be removed
+const REPLACED_KEYS: [&str; 1] = ["profiles"];
+
+/// `PWCCA_POWER__TIMER=120` overrides `power.timer`
+pub const ENV_PREFIX: &str = "PWCCA_";
+
+/// Files that could not be upgraded in place, only upgraded in memory from then on
+static NOT_UPGRADED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// A single value set from the command line or the environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+  pub layer: Layer,
+  pub path: JsonPath,
+  pub value: Value,
+}
+
+impl Override {
+  /// Parses `power.timer=120`. The value is read as JSON, anything else is taken as a string.
+  pub fn parse(layer: Layer, assignment: &str) -> Result<Self> {
+    let (key, value) = assignment
+      .split_once('=')
+      .ok_or_else(|| anyhow!("Expected key=value, got {}", assignment))?;
+
+    Ok(Self {
+      layer,
+      path: parse_key(key)?,
+      value: parse_value(value),
+    })
+  }
+
+  /// The override alone, as a layer
+  fn to_layer(&self) -> Value {
+    let mut value = Value::Object(Map::new());
+    set(&mut value, &self.path, self.value.clone());
+    value
+  }
+}
+
+impl Display for Override {
+  /// Where the override was set, `PWCCA_POWER__TIMER` or `--set power.timer`
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.layer {
+      Layer::Env => {
+        let keys = self
+          .path
+          .0
+          .iter()
+          .map(|segment| match segment {
+            Segment::Key(key) => key.to_uppercase(),
+            Segment::Index(index) => index.to_string(),
+          })
+          .collect::<Vec<_>>();
+        write!(f, "{}{}", ENV_PREFIX, keys.join("__"))
+      }
+      _ => write!(f, "--set {}", self.path),
+    }
+  }
+}
+
+fn parse_key(key: &str) -> Result<JsonPath> {
+  if key.split('.').any(|segment| segment.trim().is_empty()) {
+    return Err(anyhow!("Invalid config key {}", key));
+  }
+
+  Ok(JsonPath(
+    key
+      .split('.')
+      .map(|segment| Segment::Key(segment.trim().to_string()))
+      .collect(),
+  ))
+}
+
+/// `value` as JSON, or as a string when it is not valid JSON
+pub fn parse_value(value: &str) -> Value {
+  serde_json::from_str(value).unwrap_or_else(|_| Value::from(value))
+}
+
+/// Every `PWCCA_*` variable in `vars` as an override, `__` separating nested keys
+pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Result<Vec<Override>> {
+  vars
+    .filter_map(|(name, value)| {
+      let key = name
+        .strip_prefix(ENV_PREFIX)?
+        .to_lowercase()
+        .replace("__", ".");
+      Some((key, value))
+    })
+    .map(|(key, value)| {
+      Ok(Override {
+        layer: Layer::Env,
+        path: parse_key(&key)?,
+        value: parse_value(&value),
+      })
+    })
+    .collect()
+}
+
+/// The per-user config under the app-data directory
+pub fn user_path() -> Result<PathBuf> {
+  #[cfg(windows)]
+  let base = std::env::var_os("APPDATA").map(PathBuf::from);
+  #[cfg(not(windows))]
+  let base = std::env::var_os("XDG_CONFIG_HOME")
+    .filter(|base| !base.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+  let base = base.ok_or_else(|| anyhow!("Cannot find the user's app-data directory"))?;
+  Ok(base.join("PwccaAuto").join("config.json"))
+}
+
+fn get<'a>(value: &'a Value, path: &JsonPath) -> Option<&'a Value> {
+  value.pointer(&path.to_pointer())
+}
+
+fn set(value: &mut Value, path: &JsonPath, new: Value) {
+  let Some((last, parents)) = path.0.split_last() else {
+    *value = new;
+    return;
+  };
+
+  let mut current = value;
+  for segment in parents {
+    current = match segment {
+      Segment::Key(key) => {
+        if !current.is_object() {
+          *current = Value::Object(Map::new());
+        }
+        current
+          .as_object_mut()
+          .unwrap()
+          .entry(key.clone())
+          .or_insert_with(|| Value::Object(Map::new()))
+      }
+      Segment::Index(index) => match current.get_mut(*index) {
+        Some(element) => element,
+        None => return,
+      },
+    };
+  }
+
+  match (last, current) {
+    (Segment::Key(key), Value::Object(object)) => {
+      object.insert(key.clone(), new);
+    }
+    (Segment::Key(key), current) => {
+      *current = Value::Object(Map::from_iter([(key.clone(), new)]));
+    }
+    (Segment::Index(index), Value::Array(array)) if *index < array.len() => array[*index] = new,
+    _ => {}
+  }
+}
+
+fn remove(value: &mut Value, path: &JsonPath) {
+  let Some((Segment::Key(key), parents)) = path.0.split_last() else {
+    return;
+  };
+
+  if let Some(Value::Object(parent)) = value.pointer_mut(&JsonPath(parents.to_vec()).to_pointer()) {
+    parent.remove(key);
+  }
+}
+
+/// Lays `layer` over `base`. Objects are merged key by key, everything else is replaced.
+fn merge(base: &mut Value, layer: Value, root: bool) {
+  match (base, layer) {
+    (Value::Object(base), Value::Object(layer)) => {
+      for (key, value) in layer {
+        match base.get_mut(&key) {
+          Some(existing) if !(root && REPLACED_KEYS.contains(&key.as_str())) => {
+            merge(existing, value, false)
+          }
+          _ => {
+            base.insert(key, value);
+          }
+        }
+      }
+    }
+    (base, layer) => *base = layer,
+  }
+}
+
+/// The smallest layer that turns `base` into `target` when merged over it
+fn diff(base: &Value, target: &Value, root: bool) -> Option<Value> {
+  match (base, target) {
+    (Value::Object(base), Value::Object(target)) => {
+      let changes = target
+        .iter()
+        .filter_map(|(key, value)| match base.get(key) {
+          Some(existing) if root && REPLACED_KEYS.contains(&key.as_str()) => {
+            (existing != value).then(|| (key.clone(), value.clone()))
+          }
+          Some(existing) => Some((key.clone(), diff(existing, value, false)?)),
+          None => Some((key.clone(), value.clone())),
+        })
+        .collect::<Map<String, Value>>();
+
+      (!changes.is_empty()).then_some(Value::Object(changes))
+    }
+    (base, target) => (base != target).then(|| target.clone()),
+  }
+}
+
+/// Calls `f` for every value that is not a non-empty object
+fn leaves(value: &Value, path: &JsonPath, f: &mut impl FnMut(&JsonPath, &Value)) {
+  match value {
+    Value::Object(object) if !object.is_empty() => {
+      for (key, value) in object {
+        leaves(value, &path.key(key), f);
+      }
+    }
+    value => f(path, value),
+  }
+}
+
+/// `config` with `key`, such as `power.timer`, set to `value`. The result has to be a valid
+/// config.
+pub fn with_key(config: &Config, key: &str, value: Value) -> Result<Config> {
+  let mut updated = serde_json::to_value(config)?;
+  set(&mut updated, &parse_key(key)?, value);
+
+  let diagnostics = validate::check_value(&updated);
+  if diagnostics.iter().any(Diagnostic::is_error) {
+    return Err(ConfigError { diagnostics }.into());
+  }
+
+  Ok(serde_json::from_value(updated)?)
+}
+
+/// The value at `key` in `config`
+pub fn key_value(config: &Config, key: &str) -> Result<Option<Value>> {
+  Ok(get(&serde_json::to_value(config)?, &parse_key(key)?).cloned())
+}
+
+/// The version a file of `layer` is assumed to be without one of its own, and what it holds.
+/// The machine-wide file predates versioning and layers, the per-user file does not.
+fn file_format(layer: Layer) -> (u32, Coverage) {
+  if layer == Layer::Machine {
+    (0, Coverage::Full)
+  } else {
+    (CONFIG_VERSION, Coverage::Partial)
+  }
+}
+
+/// The merged config, the JSON it was built from and the layer each value came from
+#[derive(Debug, Clone)]
+pub struct Resolved {
+  pub config: Config,
+  pub value: Value,
+  pub origins: BTreeMap<String, Layer>,
+}
+
+/// Builds the running config from the built-in defaults, the machine-wide file next to the
+/// executable, the per-user file and the environment/command line, in that order
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+  pub machine: PathBuf,
+  pub user: PathBuf,
+  pub overrides: Vec<Override>,
+}
+
+impl LayeredConfig {
+  pub fn new(overrides: Vec<Override>) -> Result<Self> {
+    Ok(Self {
+      machine: Config::get_path()?,
+      user: user_path()?,
+      overrides,
+    })
+  }
+
+  pub fn files(&self) -> [(Layer, &Path); 2] {
+    [(Layer::Machine, &self.machine), (Layer::User, &self.user)]
+  }
+
+  /// Reads one config file. The machine-wide file predates versioning and is upgraded like
+  /// before, the per-user file is assumed to be current. With `fallback` a corrupt file is
+  /// replaced by its newest intact backup.
+  fn read_layer(layer: Layer, path: &Path, fallback: bool) -> Result<Option<Value>> {
+    let contents = if fallback {
+      persist::read_or_backup(path)?
+    } else if path.exists() {
+      Some(std::fs::read_to_string(path)?)
+    } else {
+      None
+    };
+    let Some(contents) = contents else {
+      return Ok(None);
+    };
+
+    let (default_version, coverage) = file_format(layer);
+    let diagnostics = validate::check_layer(&contents, default_version, coverage);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+      return Err(ConfigError { diagnostics }.into());
+    }
+
+    let mut value: Value = serde_json::from_str(&contents)?;
+    let Some(version) = migrate::migrate_layer(&mut value, default_version, coverage)? else {
+      return Ok(Some(value));
+    };
+
+    // The machine-wide file is not always writable, it is then upgraded in memory on every
+    // read without trying again
+    if NOT_UPGRADED.lock().unwrap().contains(path) {
+      return Ok(Some(value));
+    }
+    let upgrade = std::fs::write(migrate::backup_path(path, version), &contents)
+      .and_then(|_| persist::write_atomic(path, &serde_json::to_string_pretty(&value)?));
+    if let Err(e) = upgrade {
+      warning!("Cannot upgrade {}: {}", path.display(), e);
+      NOT_UPGRADED.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    Ok(Some(value))
+  }
+
+  /// Every layer that is present, lowest priority first
+  fn layers(&self, fallback: bool) -> Result<Vec<(Layer, Value)>> {
+    let mut layers = vec![(Layer::Default, serde_json::to_value(Config::new())?)];
+
+    for (layer, path) in self.files() {
+      let value = LayeredConfig::read_layer(layer, path, fallback)
+        .with_context(|| format!("Invalid config {}", path.display()))?;
+      if let Some(value) = value {
+        layers.push((layer, value));
+      }
+    }
+
+    for o in &self.overrides {
+      layers.push((o.layer, o.to_layer()));
+    }
+
+    Ok(layers)
+  }
+
+  /// Merges every layer as it is on disk. A corrupt file is an error here, the running config
+  /// is kept instead of going back to an older backup.
+  pub fn resolve(&self) -> Result<Resolved> {
+    self.resolve_layers(self.layers(false)?)
+  }
+
+  fn resolve_layers(&self, layers: Vec<(Layer, Value)>) -> Result<Resolved> {
+    let mut value = Value::Null;
+    for (_, layer) in &layers {
+      merge(&mut value, layer.clone(), true);
+    }
+
+    let diagnostics = validate::check_value(&value);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+      return Err(ConfigError { diagnostics }.into());
+    }
+    let config: Config = serde_json::from_value(value.clone())?;
+
+    let mut origins = BTreeMap::new();
+    leaves(&value, &JsonPath::root(), &mut |path, _| {
+      let origin = layers
+        .iter()
+        .rev()
+        .find(|(_, layer)| get(layer, path).is_some())
+        .map_or(Layer::Default, |(layer, _)| *layer);
+      origins.insert(path.to_string(), origin);
+    });
+
+    Ok(Resolved {
+      config,
+      value,
+      origins,
+    })
+  }
+
+  /// Resolves the running config at startup, using backups for corrupt files. With no config
+  /// file anywhere yet, a per-user one is created with the default profiles.
+  pub fn read(&self) -> Result<Config> {
+    if self.files().iter().all(|(_, path)| !path.exists()) {
+      self.save(&Config::with_default_profiles())?;
+    }
+
+    Ok(self.resolve_layers(self.layers(true)?)?.config)
+  }
+
+  /// Fails when `config` changes a value an override sets, since the override would undo it
+  /// on the next read
+  pub fn check_overrides(&self, config: &Config) -> Result<()> {
+    let target = serde_json::to_value(config)?;
+    let mut pinned = target.clone();
+    for o in &self.overrides {
+      merge(&mut pinned, o.to_layer(), true);
+    }
+
+    let blocking = self
+      .overrides
+      .iter()
+      .rev()
+      .find(|o| get(&pinned, &o.path) != get(&target, &o.path));
+    match blocking {
+      Some(o) => Err(anyhow!(
+        "{} is set by {} and cannot be changed here",
+        o.path,
+        o
+      )),
+      None => Ok(()),
+    }
+  }
+
+  /// Saves what differs from the defaults and the machine-wide file to the per-user file.
+  /// Changing a value an override sets is an error, see `check_overrides`.
+  pub fn write(&self, config: &Config) -> Result<()> {
+    self.check_overrides(config)?;
+    self.save(config)
+  }
+
+  /// Values set by an override are not the user's choice and keep their saved value
+  fn save(&self, config: &Config) -> Result<()> {
+    let mut base = serde_json::to_value(Config::new())?;
+    if let Some(machine) = LayeredConfig::read_layer(Layer::Machine, &self.machine, true)? {
+      merge(&mut base, machine, true);
+    }
+
+    let saved = LayeredConfig::read_layer(Layer::User, &self.user, true)?;
+
+    let mut target = serde_json::to_value(config)?;
+    if !self.overrides.is_empty() {
+      let mut unoverridden = base.clone();
+      if let Some(saved) = saved.clone() {
+        merge(&mut unoverridden, saved, true);
+      }
+
+      for o in &self.overrides {
+        match get(&unoverridden, &o.path) {
+          Some(value) => set(&mut target, &o.path, value.clone()),
+          None => remove(&mut target, &o.path),
+        }
+      }
+    }
+
+    let mut user = diff(&base, &target, true).unwrap_or_else(|| Value::Object(Map::new()));
+    user["version"] = Value::from(CONFIG_VERSION);
+    if saved.as_ref() == Some(&user) {
+      return Ok(());
+    }
+
+    if let Some(parent) = self.user.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    persist::write_atomic(&self.user, &serde_json::to_string_pretty(&user)?)?;
+
+    Ok(())
+  }
+
+  /// Checks every config file that exists
+  pub fn check_files(&self) -> Result<Vec<(PathBuf, Vec<Diagnostic>)>> {
+    self
+      .files()
+      .iter()
+      .filter(|(_, path)| path.exists())
+      .map(|(layer, path)| {
+        let (default_version, coverage) = file_format(*layer);
+        let contents = std::fs::read_to_string(path)?;
+        Ok((
+          path.to_path_buf(),
+          validate::check_layer(&contents, default_version, coverage),
+        ))
+      })
+      .collect()
+  }
+
+  /// The effective config, one value per line with the layer it came from
+  pub fn report(&self, resolved: &Resolved) -> String {
+    let describe = |path: &Path| {
+      if path.exists() {
+        path.display().to_string()
+      } else {
+        format!("{} (missing)", path.display())
+      }
+    };
+
+    let mut report = String::from("Layers, lowest priority first:");
+    report += &format!("\n  {:<8} built in", Layer::Default);
+    report += &format!("\n  {:<8} {}", Layer::Machine, describe(&self.machine));
+    report += &format!("\n  {:<8} {}", Layer::User, describe(&self.user));
+    report += &format!("\n  {:<8} {}* variables", Layer::Env, ENV_PREFIX);
+    report += &format!("\n  {:<8} --set key=value", Layer::Cli);
+
+    report += "\n\nEffective config:";
+    leaves(&resolved.value, &JsonPath::root(), &mut |path, value| {
+      let origin = resolved
+        .origins
+        .get(&path.to_string())
+        .copied()
+        .unwrap_or(Layer::Default);
+      report += &format!("\n  {} = {}  [{}]", path, value, origin);
+    });
+
+    report
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::temp_dir;
+
+  fn layers(name: &str, machine: &str, user: &str) -> LayeredConfig {
+    let dir = temp_dir(name);
+    let layers = LayeredConfig {
+      machine: dir.join("machine.json"),
+      user: dir.join("user.json"),
+      overrides: Vec::new(),
+    };
+    for (path, contents) in [(&layers.machine, machine), (&layers.user, user)] {
+      if !contents.is_empty() {
+        std::fs::write(path, contents).unwrap();
+      }
+    }
+    layers
+  }
+
+  fn user_file(layers: &LayeredConfig) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(&layers.user).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn later_layers_win() {
+    let mut layers = layers(
+      "layers-order",
+      r#"{"version": 3, "startup": true, "power": {"timer": 100, "percentage": 50}}"#,
+      r#"{"power": {"timer": 200, "cpu_threshold": 80}}"#,
+    );
+    layers.overrides = env_overrides(
+      [
+        ("PWCCA_POWER__PERCENTAGE".to_string(), "40".to_string()),
+        ("PWCCA_POWER__CPU_THRESHOLD".to_string(), "70".to_string()),
+        ("PATH".to_string(), "/usr/bin".to_string()),
+      ]
+      .into_iter(),
+    )
+    .unwrap();
+    layers
+      .overrides
+      .push(Override::parse(Layer::Cli, "power.cpu_threshold=60").unwrap());
+
+    let resolved = layers.resolve().unwrap();
+    let power = &resolved.config.power;
+    assert!(resolved.config.startup);
+    assert_eq!(
+      (power.timer, power.percentage, power.cpu_threshold),
+      (200, 40, 60)
+    );
+
+    let origin = |key: &str| resolved.origins[key];
+    assert_eq!(origin("dry_run"), Layer::Default);
+    assert_eq!(origin("startup"), Layer::Machine);
+    assert_eq!(origin("power.timer"), Layer::User);
+    assert_eq!(origin("power.percentage"), Layer::Env);
+    assert_eq!(origin("power.cpu_threshold"), Layer::Cli);
+  }
+
+  #[test]
+  fn profiles_are_replaced_instead_of_merged() {
+    let layers = layers(
+      "layers-profiles",
+      r#"{"version": 3, "profiles": {"Home": {}, "Work": {"ethernet": true}}}"#,
+      r#"{"profiles": {"Travel": {}}}"#,
+    );
+
+    let config = layers.resolve().unwrap().config;
+    assert_eq!(config.profiles.keys().collect::<Vec<_>>(), ["Travel"]);
+  }
+
+  #[test]
+  fn only_the_difference_is_saved_for_the_user() {
+    let layers = layers(
+      "layers-diff",
+      r#"{"version": 3, "power": {"timer": 100}}"#,
+      "",
+    );
+
+    let mut config = layers.resolve().unwrap().config;
+    layers.write(&config).unwrap();
+    assert_eq!(user_file(&layers), serde_json::json!({"version": 3}));
+
+    config.power.percentage = 10;
+    for name in ["Home", "Work"] {
+      config
+        .profiles
+        .insert(name.to_string(), config.active_profile());
+    }
+    layers.write(&config).unwrap();
+    let user = user_file(&layers);
+    assert_eq!(user["power"], serde_json::json!({"percentage": 10}));
+    assert!(user["profiles"]["Work"].is_object());
+
+    // Removing a profile is saved too, even though layers merge
+    config.profiles.remove("Work");
+    layers.write(&config).unwrap();
+    let profiles = user_file(&layers)["profiles"].clone();
+    assert_eq!(profiles.as_object().unwrap().len(), 1);
+    assert_eq!(layers.resolve().unwrap().config, config);
+  }
+
+  #[test]
+  fn an_overridden_value_cannot_be_changed() {
+    let mut layers = layers("layers-overridden", "", r#"{"power": {"timer": 200}}"#);
+    layers.overrides = vec![
+      Override::parse(Layer::Env, "power.timer=120").unwrap(),
+      Override::parse(Layer::Cli, "startup=true").unwrap(),
+    ];
+    let config = layers.resolve().unwrap().config;
+    assert_eq!(config.power.timer, 120);
+
+    let mut changed = config.clone();
+    changed.power.timer = 30;
+    let error = layers.write(&changed).unwrap_err().to_string();
+    assert!(error.contains("PWCCA_POWER__TIMER"), "{}", error);
+
+    changed = config.clone();
+    changed.startup = false;
+    let error = layers.write(&changed).unwrap_err().to_string();
+    assert!(error.contains("--set startup"), "{}", error);
+    assert_eq!(
+      user_file(&layers),
+      serde_json::json!({"power": {"timer": 200}})
+    );
+
+    // Anything else is saved, the overridden values keeping what the user had
+    changed = config.clone();
+    changed.power.percentage = 10;
+    layers.write(&changed).unwrap();
+    assert_eq!(
+      user_file(&layers)["power"],
+      serde_json::json!({"timer": 200, "percentage": 10})
+    );
+    assert!(user_file(&layers).get("startup").is_none());
+  }
+
+  #[test]
+  fn the_last_override_of_a_key_counts() {
+    let mut layers = layers("layers-last-override", "", "");
+    layers.overrides = vec![
+      Override::parse(Layer::Env, "power.timer=120").unwrap(),
+      Override::parse(Layer::Cli, "power.timer=60").unwrap(),
+    ];
+
+    let config = layers.resolve().unwrap().config;
+    assert_eq!(config.power.timer, 60);
+    layers.check_overrides(&config).unwrap();
+  }
+
+  #[test]
+  fn overrides_are_read_as_json_or_strings() {
+    let timer = Override::parse(Layer::Cli, "power.timer=120").unwrap();
+    assert_eq!(timer.path.to_string(), "power.timer");
+    assert_eq!(timer.value, Value::from(120));
+
+    let profile = Override::parse(Layer::Cli, "profile=Work").unwrap();
+    assert_eq!(profile.value, Value::from("Work"));
+
+    assert!(Override::parse(Layer::Cli, "power.timer").is_err());
+    assert!(Override::parse(Layer::Cli, "power..timer=1").is_err());
+  }
+
+  #[test]
+  fn an_older_user_file_keeps_the_machine_schemes() {
+    let layers = layers(
+      "layers-user-v2",
+      r#"{"power": {"ac_scheme": "Balanced"}}"#,
+      r#"{"version": 2, "power": {"timer": 60}}"#,
+    );
+
+    let power = layers.resolve().unwrap().config.power;
+    assert_eq!(power.timer, 60);
+    assert_eq!(power.ac_scheme, "Balanced");
+    // Filled in for the unversioned machine file, which always used it
+    assert_eq!(power.battery_scheme, "POWERSAVER");
+    assert_eq!(user_file(&layers)["version"], CONFIG_VERSION);
+    assert!(user_file(&layers)["power"].get("ac_scheme").is_none());
+  }
+
+  #[test]
+  fn a_machine_file_that_cannot_be_upgraded_is_only_tried_once() {
+    let layers = layers("layers-upgrade", r#"{"power": {"timer": 100}}"#, "");
+    // A directory in the way of the backup keeps the file from being upgraded
+    std::fs::create_dir(migrate::backup_path(&layers.machine, 0)).unwrap();
+
+    assert_eq!(layers.resolve().unwrap().config.power.timer, 100);
+    assert!(NOT_UPGRADED.lock().unwrap().contains(&layers.machine));
+    assert_eq!(layers.resolve().unwrap().config.power.timer, 100);
+
+    let machine = std::fs::read_to_string(&layers.machine).unwrap();
+    assert_eq!(machine, r#"{"power": {"timer": 100}}"#);
+  }
+}
@@ -0,0 +1,282 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+use super::DEFAULT_PROFILES;
+
+/// Version written by this build. Bump it together with a new entry in `MIGRATIONS`.
+pub const CONFIG_VERSION: u32 = 3;
+
+/// What a config file holds, which decides whether a migration may fill in missing settings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coverage {
+  /// Every setting, a missing one meaning its built-in default: the machine-wide file, or one
+  /// from before the layers
+  Full,
+  /// Only what differs from the layers below it, like the per-user file
+  Partial,
+}
+
+type Migration = fn(&mut Map<String, Value>, Coverage);
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to version `n + 1`
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_to_v1, v1_to_v2, v2_to_v3];
+
+/// Files written before the config was versioned
+fn v0_to_v1(_config: &mut Map<String, Value>, _coverage: Coverage) {}
+
+/// Profiles: the existing settings become the first default profile and seed the others
+fn v1_to_v2(config: &mut Map<String, Value>, _coverage: Coverage) {
+  let profile = ["ethernet", "microphone", "power", "autostart", "taskbar"]
+    .iter()
+    .filter_map(|key| Some((key.to_string(), config.get(*key)?.clone())))
+    .collect::<Map<String, Value>>();
+
+  let profiles = DEFAULT_PROFILES[1..]
+    .iter()
+    .map(|name| (name.to_string(), Value::Object(profile.clone())))
+    .collect::<Map<String, Value>>();
+
+  config
+    .entry("profile")
+    .or_insert_with(|| Value::from(DEFAULT_PROFILES[0]));
+  config
+    .entry("profiles")
+    .or_insert_with(|| Value::Object(profiles));
+}
+
+/// Power schemes: files from before they could be set keep the schemes the app always used.
+/// A partial layer leaves them to the layers below it.
+fn v2_to_v3(config: &mut Map<String, Value>, coverage: Coverage) {
+  if coverage == Coverage::Partial {
+    return;
+  }
+
+  fn keep_legacy_schemes(settings: &mut Map<String, Value>) {
+    let power = settings
+      .entry("power")
+      .or_insert_with(|| Value::Object(Map::new()));
+    if let Some(power) = power.as_object_mut() {
+      for (key, name) in [("battery_scheme", "POWERSAVER"), ("ac_scheme", "Ultra")] {
+        power.entry(key).or_insert_with(|| Value::from(name));
+      }
+    }
+  }
+
+  keep_legacy_schemes(config);
+  if let Some(profiles) = config.get_mut("profiles").and_then(Value::as_object_mut) {
+    profiles
+      .values_mut()
+      .filter_map(Value::as_object_mut)
+      .for_each(keep_legacy_schemes);
+  }
+}
+
+fn version_of(config: &Map<String, Value>, default_version: u32) -> Result<u32> {
+  match config.get("version") {
+    None => Ok(default_version),
+    Some(version) => version
+      .as_u64()
+      .and_then(|version| u32::try_from(version).ok())
+      .ok_or_else(|| anyhow!("version must be a positive integer, got {}", version)),
+  }
+}
+
+/// Upgrades `value` step by step to `CONFIG_VERSION`.
+///
+/// Returns the version the file was written with if anything had to be migrated.
+pub fn migrate(value: &mut Value) -> Result<Option<u32>> {
+  migrate_layer(value, 0, Coverage::Full)
+}
+
+/// Like `migrate`, for a file that is assumed to be `default_version` when it has no
+/// version of its own
+pub fn migrate_layer(
+  value: &mut Value,
+  default_version: u32,
+  coverage: Coverage,
+) -> Result<Option<u32>> {
+  let config = value
+    .as_object_mut()
+    .ok_or_else(|| anyhow!("The config must be a JSON object"))?;
+
+  let version = version_of(config, default_version)?;
+  if version > CONFIG_VERSION {
+    return Err(anyhow!(
+      "The config was written by a newer version (version {}, this build supports {})",
+      version,
+      CONFIG_VERSION
+    ));
+  }
+  if version == CONFIG_VERSION {
+    return Ok(None);
+  }
+
+  for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+    migration(config, coverage);
+    config.insert("version".to_string(), Value::from(from as u32 + 1));
+  }
+
+  Ok(Some(version))
+}
+
+/// Where the untouched file is kept before a migration overwrites it
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(format!(".v{}.bak", version));
+  path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{config::Config, testing::temp_dir};
+
+  /// A config as the first release wrote it, without a version
+  const V0: &str = r#"{
+  "startup": true,
+  "ethernet": true,
+  "microphone": { "enabled": true, "apps": ["discord.exe"] },
+  "power": { "enabled": true, "timer": 120, "percentage": 30 },
+  "autostart": { "enabled": false, "apps": [] },
+  "taskbar": { "enabled": true, "apps": ["code.exe"] }
+}"#;
+
+  const V1: &str = r#"{
+  "version": 1,
+  "startup": false,
+  "ethernet": false,
+  "microphone": { "enabled": false, "apps": [] },
+  "power": { "enabled": true, "timer": 600, "percentage": 20 },
+  "autostart": { "enabled": true, "apps": ["Steam"] },
+  "taskbar": { "enabled": false, "apps": [] }
+}"#;
+
+  #[test]
+  fn migrates_an_unversioned_config() {
+    let config = Config::parse(V0).unwrap();
+
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert!(config.startup && config.ethernet);
+    assert_eq!(config.microphone.apps, ["discord.exe"]);
+    assert_eq!((config.power.timer, config.power.percentage), (120, 30));
+    assert_eq!(config.taskbar.apps, ["code.exe"]);
+  }
+
+  #[test]
+  fn v1_settings_become_every_default_profile() {
+    let config = Config::parse(V1).unwrap();
+
+    assert_eq!(config.profile, DEFAULT_PROFILES[0]);
+    let mut names = DEFAULT_PROFILES[1..].to_vec();
+    names.sort();
+    assert_eq!(config.profiles.keys().collect::<Vec<_>>(), names);
+    for profile in config.profiles.values() {
+      assert_eq!(*profile, config.active_profile());
+    }
+    assert!(config.power.enabled);
+    assert_eq!(config.autostart.apps, ["Steam"]);
+  }
+
+  #[test]
+  fn keeps_existing_profiles() {
+    let mut value: Value = serde_json::from_str(V1).unwrap();
+    value["profile"] = Value::from("Desk");
+    value["profiles"] = serde_json::json!({ "Couch": {} });
+
+    assert_eq!(migrate(&mut value).unwrap(), Some(1));
+    assert_eq!(value["profile"], "Desk");
+    assert_eq!(
+      value["profiles"]["Couch"],
+      serde_json::json!({ "power": { "battery_scheme": "POWERSAVER", "ac_scheme": "Ultra" } })
+    );
+    assert_eq!(value["version"], CONFIG_VERSION);
+  }
+
+  #[test]
+  fn older_files_keep_the_schemes_they_always_used() {
+    let config = Config::parse(V0).unwrap();
+    assert_eq!(config.power.battery_scheme, "POWERSAVER");
+    assert_eq!(config.power.ac_scheme, "Ultra");
+    for profile in config.profiles.values() {
+      assert_eq!(profile.power.battery_scheme, "POWERSAVER");
+      assert_eq!(profile.power.ac_scheme, "Ultra");
+    }
+
+    // Only where nothing was chosen yet
+    let mut value: Value = serde_json::from_str(V1).unwrap();
+    value["version"] = Value::from(2);
+    value["power"]["ac_scheme"] = Value::from("Balanced");
+    assert_eq!(migrate(&mut value).unwrap(), Some(2));
+    assert_eq!(value["power"]["battery_scheme"], "POWERSAVER");
+    assert_eq!(value["power"]["ac_scheme"], "Balanced");
+  }
+
+  #[test]
+  fn a_partial_layer_leaves_the_schemes_to_the_layers_below() {
+    let mut value = serde_json::json!({
+      "version": 2,
+      "power": { "timer": 60 },
+      "profiles": { "Work": { "ethernet": true } }
+    });
+
+    assert_eq!(
+      migrate_layer(&mut value, CONFIG_VERSION, Coverage::Partial).unwrap(),
+      Some(2)
+    );
+    assert_eq!(
+      value,
+      serde_json::json!({
+        "version": CONFIG_VERSION,
+        "power": { "timer": 60 },
+        "profiles": { "Work": { "ethernet": true } }
+      })
+    );
+  }
+
+  #[test]
+  fn new_files_use_the_built_in_schemes() {
+    let config = Config::with_default_profiles();
+    assert_eq!(config.power.battery_scheme, "");
+    assert_eq!(config.power.ac_scheme, "");
+  }
+
+  #[test]
+  fn current_version_is_left_alone() {
+    let mut value = serde_json::to_value(Config::with_default_profiles()).unwrap();
+    let before = value.clone();
+
+    assert_eq!(migrate(&mut value).unwrap(), None);
+    assert_eq!(value, before);
+  }
+
+  #[test]
+  fn rejects_a_newer_version() {
+    let mut value = serde_json::json!({ "version": CONFIG_VERSION + 1 });
+    let error = migrate(&mut value).unwrap_err();
+    assert!(error.to_string().contains("newer version"), "{}", error);
+
+    let newer = V1.replace("\"version\": 1", "\"version\": 99");
+    assert!(Config::parse(&newer).is_err());
+  }
+
+  #[test]
+  fn rejects_an_invalid_version() {
+    let mut value = serde_json::json!({ "version": "two" });
+    assert!(migrate(&mut value).is_err());
+  }
+
+  #[test]
+  fn reading_an_old_file_upgrades_it_and_keeps_the_original() {
+    let dir = temp_dir("migrate");
+    let path = dir.join("config.json");
+    std::fs::write(&path, V1).unwrap();
+
+    let config = Config::read_from(&path).unwrap();
+
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(std::fs::read_to_string(backup_path(&path, 1)).unwrap(), V1);
+    assert_eq!(Config::read_from(&path).unwrap(), config);
+  }
+}
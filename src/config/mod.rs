@@ -0,0 +1,388 @@
+// Mew was here
+#![allow(dead_code)]
+
+pub mod layers;
+pub mod migrate;
+pub mod persist;
+pub mod store;
+pub mod validate;
+pub mod watch;
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use migrate::CONFIG_VERSION;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use validate::{ConfigError, Diagnostic};
+
+use crate::{
+  log::Level,
+  rules::{tiers::BatteryTier, types::Rule},
+};
+
+/// Fields this version does not know about, kept so they survive a round trip
+pub type Extra = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct MicrophoneConfig {
+  pub enabled: bool,
+  pub apps: Vec<String>,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PowerConfig {
+  pub enabled: bool,
+  pub timer: u32,
+  pub percentage: u32,
+  /// Name or GUID of the scheme used on battery. Power saver when empty or missing.
+  pub battery_scheme: String,
+  /// Name or GUID of the scheme used when plugged in. High performance when empty or missing.
+  pub ac_scheme: String,
+  /// Battery ranges with settings of their own, in any order
+  pub tiers: Vec<BatteryTier>,
+  /// Processes that keep the power scheme from going down while they run, like games
+  pub exempt_apps: Vec<String>,
+  /// Processor load in percent that keeps the power scheme from going down, 0 to disable it
+  pub cpu_threshold: u32,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+impl Default for PowerConfig {
+  fn default() -> Self {
+    Config::new().power
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct AutoStartConfig {
+  pub enabled: bool,
+  pub apps: Vec<String>,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct TaskbarConfig {
+  pub enabled: bool,
+  pub apps: Vec<String>,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+/// The level applies to every module without an entry in `targets`, such as `"rules": "debug"`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogConfig {
+  pub level: Level,
+  pub targets: BTreeMap<String, Level>,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    Config::new().log
+  }
+}
+
+/// Profiles created for a fresh config, all starting from the same settings
+pub const DEFAULT_PROFILES: [&str; 4] = ["Home", "Work", "Gaming", "Travel"];
+
+/// The settings a profile switches. Running with Windows stays the same for every profile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Profile {
+  pub ethernet: bool,
+
+  pub microphone: MicrophoneConfig,
+  pub power: PowerConfig,
+  pub autostart: AutoStartConfig,
+  pub taskbar: TaskbarConfig,
+}
+
+impl Default for Profile {
+  fn default() -> Self {
+    Config::new().active_profile()
+  }
+}
+
+/// The top level settings belong to the active profile, `profiles` holds the others until
+/// they are switched to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Config {
+  pub version: u32,
+
+  // Profiles
+  pub profile: String,
+  pub profiles: BTreeMap<String, Profile>,
+
+  // Toggles
+  pub startup: bool,
+  pub ethernet: bool,
+  /// Record what would change instead of changing it
+  pub dry_run: bool,
+
+  // Configs
+  pub microphone: MicrophoneConfig,
+  pub power: PowerConfig,
+  pub autostart: AutoStartConfig,
+  pub taskbar: TaskbarConfig,
+
+  // Rules, on top of the built-in ones
+  pub rules: Vec<Rule>,
+
+  pub log: LogConfig,
+
+  #[serde(flatten)]
+  pub extra: Extra,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config::new()
+  }
+}
+
+impl Config {
+  pub const fn new() -> Self {
+    Config {
+      version: CONFIG_VERSION,
+
+      // Profiles
+      profile: String::new(),
+      profiles: BTreeMap::new(),
+
+      // Toggles
+      startup: false,
+      ethernet: false,
+      dry_run: false,
+
+      // Configs
+      microphone: MicrophoneConfig {
+        enabled: false,
+        apps: Vec::new(),
+        extra: BTreeMap::new(),
+      },
+      power: PowerConfig {
+        enabled: false,
+        timer: 300,
+        percentage: 60,
+        battery_scheme: String::new(),
+        ac_scheme: String::new(),
+        tiers: Vec::new(),
+        exempt_apps: Vec::new(),
+        cpu_threshold: 0,
+        extra: BTreeMap::new(),
+      },
+      autostart: AutoStartConfig {
+        enabled: false,
+        apps: Vec::new(),
+        extra: BTreeMap::new(),
+      },
+      taskbar: TaskbarConfig {
+        enabled: false,
+        apps: Vec::new(),
+        extra: BTreeMap::new(),
+      },
+
+      rules: Vec::new(),
+
+      log: LogConfig {
+        level: Level::Info,
+        targets: BTreeMap::new(),
+        extra: BTreeMap::new(),
+      },
+
+      extra: BTreeMap::new(),
+    }
+  }
+
+  /// The machine-wide config next to the executable
+  pub fn get_path() -> Result<std::path::PathBuf> {
+    let exe_path = std::env::current_exe()?;
+    let config_path = std::path::Path::new(exe_path.parent().unwrap()).join("config.json");
+    Ok(config_path)
+  }
+
+  /// A fresh config, with the active profile named and the other default profiles ready
+  pub fn with_default_profiles() -> Self {
+    let mut config = Config::new();
+    config.profile = DEFAULT_PROFILES[0].to_string();
+    for name in &DEFAULT_PROFILES[1..] {
+      config
+        .profiles
+        .insert(name.to_string(), config.active_profile());
+    }
+    config
+  }
+
+  // Profiles
+  pub fn active_profile(&self) -> Profile {
+    Profile {
+      ethernet: self.ethernet,
+
+      microphone: self.microphone.clone(),
+      power: self.power.clone(),
+      autostart: self.autostart.clone(),
+      taskbar: self.taskbar.clone(),
+    }
+  }
+
+  fn apply_profile(&mut self, profile: Profile) {
+    self.ethernet = profile.ethernet;
+
+    self.microphone = profile.microphone;
+    self.power = profile.power;
+    self.autostart = profile.autostart;
+    self.taskbar = profile.taskbar;
+  }
+
+  /// The active profile and every stored one, sorted by name
+  pub fn profile_names(&self) -> Vec<String> {
+    let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+    if !self.profile.is_empty() && !self.profiles.contains_key(&self.profile) {
+      names.push(self.profile.clone());
+    }
+    names.sort();
+    names
+  }
+
+  /// Stores the active settings under the current profile name and loads `name` in their place
+  pub fn switch_profile(&mut self, name: &str) -> Result<()> {
+    if name == self.profile {
+      return Ok(());
+    }
+
+    if self.profile.is_empty() {
+      return Err(anyhow!("The active profile has no name"));
+    }
+
+    let next = self
+      .profiles
+      .remove(name)
+      .ok_or_else(|| anyhow!("Unknown profile {}", name))?;
+    let previous = std::mem::replace(&mut self.profile, name.to_string());
+    self.profiles.insert(previous, self.active_profile());
+    self.apply_profile(next);
+
+    Ok(())
+  }
+
+  // Toggles
+  pub fn toggle_startup(&mut self) {
+    self.startup = !self.startup;
+  }
+
+  pub fn toggle_ethernet(&mut self) {
+    self.ethernet = !self.ethernet;
+  }
+
+  pub fn toggle_dry_run(&mut self) {
+    self.dry_run = !self.dry_run;
+  }
+
+  // Configs
+  pub fn toggle_microphone(&mut self) {
+    self.microphone.enabled = !self.microphone.enabled;
+  }
+
+  pub fn toggle_power(&mut self) {
+    self.power.enabled = !self.power.enabled;
+  }
+
+  pub fn toggle_autostart(&mut self) {
+    self.autostart.enabled = !self.autostart.enabled;
+  }
+
+  pub fn toggle_taskbar(&mut self) {
+    self.taskbar.enabled = !self.taskbar.enabled;
+  }
+
+  pub fn set_log_level(&mut self, level: Level) {
+    self.log.level = level;
+  }
+
+  pub fn set_power(&mut self, timer: u32, percentage: u32) {
+    self.power.timer = timer;
+    self.power.percentage = percentage;
+  }
+
+  pub fn write(&self) -> Result<Self> {
+    self.write_to(&Config::get_path()?)
+  }
+
+  pub fn write_to(&self, path: &std::path::Path) -> Result<Self> {
+    persist::write_atomic(path, &self.stringify()?)?;
+    Ok(self.clone())
+  }
+
+  pub fn read() -> Result<Self> {
+    let path = Config::get_path()?;
+    if path.exists() {
+      Config::read_from(&path)
+    } else {
+      let config = Config::with_default_profiles();
+      config.write()?;
+      Ok(config)
+    }
+  }
+
+  /// Reads and migrates a config file, or its newest intact backup if it is corrupt. A file
+  /// written by an older version is upgraded in place after the original is saved next to it.
+  pub fn read_from(path: &std::path::Path) -> Result<Self> {
+    let contents =
+      persist::read_or_backup(path)?.ok_or_else(|| anyhow!("{} does not exist", path.display()))?;
+    let (config, migrated_from) = Config::parse_and_migrate(&contents)?;
+
+    if let Some(version) = migrated_from {
+      std::fs::write(migrate::backup_path(path, version), &contents)?;
+      config.write_to(path)?;
+    }
+
+    Ok(config)
+  }
+
+  pub fn parse(contents: &str) -> Result<Self> {
+    Ok(Config::parse_and_migrate(contents)?.0)
+  }
+
+  fn parse_and_migrate(contents: &str) -> Result<(Self, Option<u32>)> {
+    let diagnostics = validate::check(contents);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+      return Err(ConfigError { diagnostics }.into());
+    }
+
+    let mut value: Value = serde_json::from_str(contents)?;
+    let migrated_from = migrate::migrate(&mut value)?;
+    let config: Config = serde_json::from_value(value)?;
+
+    Ok((config, migrated_from))
+  }
+
+  pub fn validate(&self) -> Result<()> {
+    let diagnostics = validate::validate(self);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+      return Err(ConfigError { diagnostics }.into());
+    }
+
+    Ok(())
+  }
+
+  pub fn stringify(&self) -> Result<String> {
+    Ok(serde_json::to_string_pretty(self)?)
+  }
+}
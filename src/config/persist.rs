@@ -0,0 +1,174 @@
+use std::{
+  io::Write,
+  path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::log::warning;
+
+/// How many previous versions of a config file are kept next to it
+pub const BACKUPS: usize = 5;
+
+/// `config.json.1.bak` is the newest backup, `config.json.5.bak` the oldest
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(format!(".{}.bak", n));
+  path.with_file_name(file_name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+  let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+  file_name.push(".tmp");
+  path.with_file_name(file_name)
+}
+
+/// Whether `contents` is a complete JSON document, as opposed to a file cut short by a crash
+fn is_intact(contents: &str) -> bool {
+  serde_json::from_str::<Value>(contents).is_ok()
+}
+
+fn rotate(path: &Path) -> std::io::Result<()> {
+  for n in (1..BACKUPS).rev() {
+    let from = backup_path(path, n);
+    if from.exists() {
+      std::fs::rename(from, backup_path(path, n + 1))?;
+    }
+  }
+
+  std::fs::copy(path, backup_path(path, 1))?;
+  Ok(())
+}
+
+/// Replaces `path` with `contents` through a temp file, so a crash leaves either the old or
+/// the new file but never half of one. Keeps no backups, for files rewritten all the time.
+pub fn replace(path: &Path, contents: &str) -> std::io::Result<()> {
+  let temp = temp_path(path);
+  let mut file = std::fs::File::create(&temp)?;
+  file.write_all(contents.as_bytes())?;
+  file.sync_all()?;
+  drop(file);
+
+  std::fs::rename(temp, path)
+}
+
+/// Like `replace`, the previous version becoming the newest backup
+pub fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+  let current = std::fs::read_to_string(path).ok();
+  if current.as_deref() == Some(contents) {
+    return Ok(());
+  }
+
+  // A corrupt file would only push a good backup out
+  if current.as_deref().is_some_and(is_intact) {
+    rotate(path)?;
+  }
+
+  replace(path, contents)
+}
+
+/// Reads `path`, falling back to the newest intact backup when it is corrupt.
+///
+/// A corrupt file without any usable backup is returned as is so its errors can be reported.
+/// Returns `None` when `path` does not exist.
+pub fn read_or_backup(path: &Path) -> std::io::Result<Option<String>> {
+  let primary = match std::fs::read_to_string(path) {
+    Ok(contents) if is_intact(&contents) => return Ok(Some(contents)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    primary => primary,
+  };
+
+  for n in 1..=BACKUPS {
+    let backup = backup_path(path, n);
+    if let Ok(contents) = std::fs::read_to_string(&backup) {
+      if is_intact(&contents) {
+        warning!(
+          "{} is corrupt, using {} instead",
+          path.display(),
+          backup.display()
+        );
+        return Ok(Some(contents));
+      }
+    }
+  }
+
+  primary.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::temp_dir;
+
+  fn json(n: usize) -> String {
+    format!("{{\"n\": {}}}", n)
+  }
+
+  fn read(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+  }
+
+  #[test]
+  fn keeps_the_previous_versions_as_backups() {
+    let path = temp_dir("persist-rotate").join("config.json");
+
+    for n in 1..=7 {
+      write_atomic(&path, &json(n)).unwrap();
+    }
+
+    assert_eq!(read(&path), Some(json(7)));
+    for n in 1..=BACKUPS {
+      assert_eq!(read(&backup_path(&path, n)), Some(json(7 - n)));
+    }
+    assert_eq!(read(&backup_path(&path, BACKUPS + 1)), None);
+    assert_eq!(read(&temp_path(&path)), None);
+  }
+
+  #[test]
+  fn writing_the_same_contents_keeps_the_backups() {
+    let path = temp_dir("persist-same").join("config.json");
+
+    write_atomic(&path, &json(1)).unwrap();
+    write_atomic(&path, &json(2)).unwrap();
+    write_atomic(&path, &json(2)).unwrap();
+
+    assert_eq!(read(&backup_path(&path, 1)), Some(json(1)));
+    assert_eq!(read(&backup_path(&path, 2)), None);
+  }
+
+  #[test]
+  fn a_corrupt_file_is_not_backed_up() {
+    let path = temp_dir("persist-corrupt-write").join("config.json");
+
+    write_atomic(&path, &json(1)).unwrap();
+    write_atomic(&path, &json(2)).unwrap();
+    std::fs::write(&path, "{\"n\": ").unwrap();
+    write_atomic(&path, &json(3)).unwrap();
+
+    assert_eq!(read(&backup_path(&path, 1)), Some(json(1)));
+    assert_eq!(read(&path), Some(json(3)));
+  }
+
+  #[test]
+  fn reads_the_newest_intact_backup_when_the_file_is_corrupt() {
+    let path = temp_dir("persist-corrupt-read").join("config.json");
+
+    for n in 1..=3 {
+      write_atomic(&path, &json(n)).unwrap();
+    }
+    std::fs::write(&path, "{\"n\": ").unwrap();
+    std::fs::write(backup_path(&path, 1), "").unwrap();
+
+    assert_eq!(read_or_backup(&path).unwrap(), Some(json(1)));
+  }
+
+  #[test]
+  fn a_corrupt_file_without_backups_is_returned_as_is() {
+    let path = temp_dir("persist-no-backup").join("config.json");
+
+    assert_eq!(read_or_backup(&path).unwrap(), None);
+
+    std::fs::write(&path, "{\"n\": ").unwrap();
+    assert_eq!(read_or_backup(&path).unwrap(), Some("{\"n\": ".to_string()));
+  }
+}
@@ -0,0 +1,133 @@
+use std::sync::{
+  mpsc::{channel, Receiver, Sender},
+  Mutex, RwLock,
+};
+
+use super::Config;
+
+/// The running configuration, shared between every thread.
+///
+/// Readers take a snapshot, writers go through `update`/`replace`, and every subscriber gets
+/// the new config whenever it actually changes.
+pub struct ConfigStore {
+  config: RwLock<Config>,
+  subscribers: Mutex<Vec<Sender<Config>>>,
+}
+
+impl ConfigStore {
+  pub const fn new(config: Config) -> Self {
+    Self {
+      config: RwLock::new(config),
+      subscribers: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn snapshot(&self) -> Config {
+    self.config.read().unwrap().clone()
+  }
+
+  pub fn subscribe(&self) -> Receiver<Config> {
+    let (sender, receiver) = channel();
+    self.subscribers.lock().unwrap().push(sender);
+    receiver
+  }
+
+  /// Applies `f` to the config and returns the result
+  pub fn update(&self, f: impl FnOnce(&mut Config)) -> Config {
+    let mut config = self.config.write().unwrap();
+    let previous = config.clone();
+    f(&mut config);
+
+    // Notify while still holding the lock so subscribers see changes in order
+    if *config != previous {
+      self.notify(&config);
+    }
+
+    config.clone()
+  }
+
+  pub fn replace(&self, new_config: Config) -> Config {
+    self.update(|config| *config = new_config)
+  }
+
+  fn notify(&self, config: &Config) {
+    self
+      .subscribers
+      .lock()
+      .unwrap()
+      .retain(|subscriber| subscriber.send(config.clone()).is_ok());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn subscribers_get_every_change_in_order() {
+    let store = ConfigStore::new(Config::new());
+    let changes = store.subscribe();
+
+    store.update(|config| config.power.timer = 60);
+    store.update(|config| config.power.timer = 120);
+
+    let timers = changes
+      .try_iter()
+      .map(|config| config.power.timer)
+      .collect::<Vec<_>>();
+    assert_eq!(timers, [60, 120]);
+    assert_eq!(store.snapshot().power.timer, 120);
+  }
+
+  #[test]
+  fn no_notification_without_a_change() {
+    let store = ConfigStore::new(Config::new());
+    let changes = store.subscribe();
+
+    store.update(|_| {});
+    store.replace(Config::new());
+
+    assert!(changes.try_recv().is_err());
+  }
+
+  #[test]
+  fn every_subscriber_is_notified() {
+    let store = ConfigStore::new(Config::new());
+    let first = store.subscribe();
+    let second = store.subscribe();
+
+    let mut config = Config::new();
+    config.power.enabled = !config.power.enabled;
+    let replaced = store.replace(config.clone());
+
+    assert_eq!(replaced, config);
+    assert_eq!(first.try_recv().unwrap(), config);
+    assert_eq!(second.try_recv().unwrap(), config);
+  }
+
+  #[test]
+  fn dropped_subscribers_are_forgotten() {
+    let store = ConfigStore::new(Config::new());
+    drop(store.subscribe());
+    let kept = store.subscribe();
+
+    store.update(|config| config.power.timer = 60);
+
+    assert_eq!(store.subscribers.lock().unwrap().len(), 1);
+    assert_eq!(kept.try_recv().unwrap().power.timer, 60);
+  }
+
+  #[test]
+  fn shared_between_threads() {
+    let store = std::sync::Arc::new(ConfigStore::new(Config::new()));
+    let changes = store.subscribe();
+
+    let writer = std::thread::spawn({
+      let store = store.clone();
+      move || store.update(|config| config.power.timer = 90)
+    });
+
+    assert_eq!(changes.recv().unwrap().power.timer, 90);
+    assert_eq!(writer.join().unwrap().power.timer, 90);
+  }
+}
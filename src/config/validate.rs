@@ -0,0 +1,940 @@
+use std::fmt::Display;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{
+  migrate::{self, Coverage},
+  Config, Profile,
+};
+use crate::{
+  log::Level,
+  rules::{
+    tiers::BatteryTier,
+    types::{parse_clock, Action, Condition, Rule},
+  },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+  Key(String),
+  Index(usize),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JsonPath(pub Vec<Segment>);
+
+impl JsonPath {
+  pub fn root() -> Self {
+    Self::default()
+  }
+
+  pub fn key(&self, key: &str) -> Self {
+    let mut path = self.clone();
+    path.0.push(Segment::Key(key.to_string()));
+    path
+  }
+
+  pub fn index(&self, index: usize) -> Self {
+    let mut path = self.clone();
+    path.0.push(Segment::Index(index));
+    path
+  }
+
+  /// The same path as a JSON pointer, for `Value::pointer`
+  pub fn to_pointer(&self) -> String {
+    self
+      .0
+      .iter()
+      .map(|segment| match segment {
+        Segment::Key(key) => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+        Segment::Index(index) => format!("/{}", index),
+      })
+      .collect()
+  }
+}
+
+impl Display for JsonPath {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.0.is_empty() {
+      return write!(f, "<root>");
+    }
+
+    for (i, segment) in self.0.iter().enumerate() {
+      match segment {
+        Segment::Key(key) if i == 0 => write!(f, "{}", key)?,
+        Segment::Key(key) => write!(f, ".{}", key)?,
+        Segment::Index(index) => write!(f, "[{}]", index)?,
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  pub path: JsonPath,
+  pub message: String,
+  /// 1-based line and column, when the problem could be located in the file
+  pub position: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+  pub fn error(path: JsonPath, message: impl Into<String>) -> Self {
+    Self {
+      severity: Severity::Error,
+      path,
+      message: message.into(),
+      position: None,
+    }
+  }
+
+  pub fn warning(path: JsonPath, message: impl Into<String>) -> Self {
+    Self {
+      severity: Severity::Warning,
+      ..Self::error(path, message)
+    }
+  }
+
+  pub fn is_error(&self) -> bool {
+    self.severity == Severity::Error
+  }
+}
+
+impl Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let severity = match self.severity {
+      Severity::Error => "error",
+      Severity::Warning => "warning",
+    };
+
+    match self.position {
+      Some((line, column)) => write!(
+        f,
+        "{} at line {}, column {}: {}: {}",
+        severity, line, column, self.path, self.message
+      ),
+      None => write!(f, "{}: {}: {}", severity, self.path, self.message),
+    }
+  }
+}
+
+/// Every problem found in a config file, returned when it cannot be loaded
+#[derive(Debug)]
+pub struct ConfigError {
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let errors = self.diagnostics.iter().filter(|d| d.is_error()).count();
+    write!(f, "The config has {} error(s)", errors)?;
+    for diagnostic in &self.diagnostics {
+      write!(f, "\n  {}", diagnostic)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn json_type(value: &Value) -> &'static str {
+  match value {
+    Value::Null => "null",
+    Value::Bool(_) => "a boolean",
+    Value::Number(n) if n.is_u64() => "a positive integer",
+    Value::Number(_) => "a number",
+    Value::String(_) => "a string",
+    Value::Array(_) => "an array",
+    Value::Object(_) => "an object",
+  }
+}
+
+fn same_type(expected: &Value, actual: &Value) -> bool {
+  match (expected, actual) {
+    (Value::Number(e), Value::Number(a)) if e.is_u64() => a.is_u64(),
+    (Value::Number(_), Value::Number(_)) => true,
+    _ => std::mem::discriminant(expected) == std::mem::discriminant(actual),
+  }
+}
+
+fn closest_key<'a>(key: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+  // Levenshtein distance, good enough to catch typos like "powr" or "microphones"
+  fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+      let mut previous = row[0];
+      row[0] = i + 1;
+      for (j, cb) in b.iter().enumerate() {
+        let current = row[j + 1];
+        row[j + 1] = if ca == *cb {
+          previous
+        } else {
+          1 + previous.min(row[j]).min(row[j + 1])
+        };
+        previous = current;
+      }
+    }
+    row[b.len()]
+  }
+
+  candidates
+    .map(|candidate| (distance(key, candidate), candidate))
+    .filter(|(d, _)| *d <= 2)
+    .min_by_key(|(d, _)| *d)
+    .map(|(_, candidate)| candidate)
+}
+
+/// Compares the shape of `actual` against the built-in defaults, which double as the schema
+fn check_shape(expected: &Value, actual: &Value, path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  if !same_type(expected, actual) {
+    out.push(Diagnostic::error(
+      path.clone(),
+      format!(
+        "expected {}, found {}",
+        json_type(expected),
+        json_type(actual)
+      ),
+    ));
+    return;
+  }
+
+  match (expected, actual) {
+    // A map of named entries, each checked against the "*" entry
+    (Value::Object(expected), Value::Object(actual)) if expected.contains_key("*") => {
+      for (key, value) in actual {
+        check_shape(&expected["*"], value, &path.key(key), out);
+      }
+    }
+    (Value::Object(expected), Value::Object(actual)) => {
+      for (key, value) in actual {
+        match expected.get(key) {
+          Some(expected) => check_shape(expected, value, &path.key(key), out),
+          None => out.push(Diagnostic::warning(
+            path.key(key),
+            match closest_key(key, expected.keys()) {
+              Some(candidate) => format!("unknown field, did you mean `{}`?", candidate),
+              None => "unknown field, it will be kept but ignored".to_string(),
+            },
+          )),
+        }
+      }
+    }
+    (Value::Array(expected), Value::Array(actual)) => {
+      if let Some(expected) = expected.first() {
+        for (i, value) in actual.iter().enumerate() {
+          check_shape(expected, value, &path.index(i), out);
+        }
+      } else {
+        // Every list in the config is a list of names
+        for (i, value) in actual.iter().enumerate() {
+          if !value.is_string() {
+            out.push(Diagnostic::error(
+              path.index(i),
+              format!("expected a string, found {}", json_type(value)),
+            ));
+          }
+        }
+      }
+    }
+    _ => {}
+  }
+}
+
+fn check_executable_names(apps: &[String], path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  for (i, app) in apps.iter().enumerate() {
+    let path = path.index(i);
+
+    if app.trim().is_empty() {
+      out.push(Diagnostic::error(path, "must not be empty"));
+    } else if app.contains(['\\', '/']) {
+      out.push(Diagnostic::error(
+        path,
+        format!("`{}` must be an executable name, not a path", app),
+      ));
+    } else if !app.ends_with(".exe") {
+      out.push(Diagnostic::error(
+        path,
+        format!("`{}` must be an executable name like `discord.exe`", app),
+      ));
+    } else if app.to_lowercase() != *app {
+      out.push(Diagnostic::error(
+        path,
+        format!(
+          "`{}` must be lowercase, process names are compared as `{}`",
+          app,
+          app.to_lowercase()
+        ),
+      ));
+    } else if apps[..i].contains(app) {
+      out.push(Diagnostic::warning(
+        path,
+        format!("`{}` is listed twice", app),
+      ));
+    }
+  }
+}
+
+fn validate_profile(profile: &Profile, path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  if profile.power.percentage > 100 {
+    out.push(Diagnostic::error(
+      path.key("power").key("percentage"),
+      format!(
+        "must be between 1 and 100, or 0 to disable it, got {}",
+        profile.power.percentage
+      ),
+    ));
+  }
+
+  check_executable_names(
+    &profile.microphone.apps,
+    &path.key("microphone").key("apps"),
+    out,
+  );
+  check_executable_names(&profile.taskbar.apps, &path.key("taskbar").key("apps"), out);
+  check_executable_names(
+    &profile.power.exempt_apps,
+    &path.key("power").key("exempt_apps"),
+    out,
+  );
+  if profile.power.cpu_threshold > 100 {
+    out.push(Diagnostic::error(
+      path.key("power").key("cpu_threshold"),
+      format!(
+        "must be between 1 and 100, or 0 to disable it, got {}",
+        profile.power.cpu_threshold
+      ),
+    ));
+  }
+
+  for (i, app) in profile.autostart.apps.iter().enumerate() {
+    if app.trim().is_empty() {
+      out.push(Diagnostic::error(
+        path.key("autostart").key("apps").index(i),
+        "must not be empty",
+      ));
+    }
+  }
+
+  validate_tiers(&profile.power.tiers, &path.key("power").key("tiers"), out);
+}
+
+fn validate_tiers(tiers: &[BatteryTier], path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  for (i, tier) in tiers.iter().enumerate() {
+    let path = path.index(i);
+
+    if tier.name.trim().is_empty() {
+      out.push(Diagnostic::error(path.key("name"), "must not be empty"));
+    } else if tiers[..i].iter().any(|other| other.name == tier.name) {
+      out.push(Diagnostic::warning(
+        path.key("name"),
+        format!("`{}` is used by another tier", tier.name),
+      ));
+    }
+
+    if tier.enter_at == 0 || tier.enter_at > 100 {
+      out.push(Diagnostic::error(
+        path.key("enter_at"),
+        format!("must be between 1 and 100, got {}", tier.enter_at),
+      ));
+    } else if tiers[..i]
+      .iter()
+      .any(|other| other.enter_at == tier.enter_at)
+    {
+      out.push(Diagnostic::warning(
+        path.key("enter_at"),
+        "is the same as another tier, only one of them is ever entered",
+      ));
+    }
+
+    if tier.exit_at > 100 {
+      out.push(Diagnostic::error(
+        path.key("exit_at"),
+        format!("must be between 0 and 100, got {}", tier.exit_at),
+      ));
+    } else if tier.exit_at != 0 && tier.exit_at < tier.enter_at {
+      out.push(Diagnostic::warning(
+        path.key("exit_at"),
+        format!(
+          "is below `enter_at`, the tier is left as soon as the battery charges above {}",
+          tier.enter_at
+        ),
+      ));
+    }
+
+    check_actions(&tier.actions, &path.key("actions"), out);
+    check_actions(&tier.exit_actions, &path.key("exit_actions"), out);
+  }
+}
+
+fn check_condition(condition: &Condition, path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  match condition {
+    Condition::All(conditions) | Condition::Any(conditions) => {
+      let key = if matches!(condition, Condition::All(_)) {
+        "all"
+      } else {
+        "any"
+      };
+      for (i, condition) in conditions.iter().enumerate() {
+        check_condition(condition, &path.key(key).index(i), out);
+      }
+    }
+    Condition::Not(condition) => check_condition(condition, &path.key("not"), out),
+    Condition::BatteryBelow(percentage) if *percentage > 100 => out.push(Diagnostic::error(
+      path.key("battery_below"),
+      format!("must be between 0 and 100, got {}", percentage),
+    )),
+    Condition::CpuLoadAbove(percentage) if *percentage > 100 => out.push(Diagnostic::error(
+      path.key("cpu_load_above"),
+      format!("must be between 0 and 100, got {}", percentage),
+    )),
+    Condition::TimeBetween { from, to } => {
+      for (key, time) in [("from", from), ("to", to)] {
+        if parse_clock(time).is_none() {
+          out.push(Diagnostic::error(
+            path.key("time_between").key(key),
+            format!("`{}` must be a time of day like `07:30`", time),
+          ));
+        }
+      }
+    }
+    _ => {}
+  }
+}
+
+fn check_actions(actions: &[Action], path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  for (i, action) in actions.iter().enumerate() {
+    match action {
+      Action::SetRefreshRate(0) => out.push(Diagnostic::error(
+        path.index(i).key("set_refresh_rate"),
+        "must be a refresh rate in Hz",
+      )),
+      Action::SetPowerScheme(name) | Action::SetDefaultOutput(name) if name.trim().is_empty() => {
+        out.push(Diagnostic::error(path.index(i), "must not be empty"))
+      }
+      _ => {}
+    }
+  }
+}
+
+fn validate_rules(rules: &[Rule], path: &JsonPath, out: &mut Vec<Diagnostic>) {
+  for (i, rule) in rules.iter().enumerate() {
+    let path = path.index(i);
+
+    if rule.name.trim().is_empty() {
+      out.push(Diagnostic::error(path.key("name"), "must not be empty"));
+    } else if rules[..i].iter().any(|other| other.name == rule.name) {
+      out.push(Diagnostic::warning(
+        path.key("name"),
+        format!("`{}` is used by another rule", rule.name),
+      ));
+    }
+
+    check_condition(&rule.trigger, &path.key("trigger"), out);
+    for (j, condition) in rule.conditions.iter().enumerate() {
+      check_condition(condition, &path.key("conditions").index(j), out);
+    }
+
+    if rule.actions.is_empty() && rule.exit_actions.is_empty() {
+      out.push(Diagnostic::warning(path.clone(), "has no actions"));
+    }
+    check_actions(&rule.actions, &path.key("actions"), out);
+    check_actions(&rule.exit_actions, &path.key("exit_actions"), out);
+  }
+}
+
+/// Checks the values of an already parsed config
+pub fn validate(config: &Config) -> Vec<Diagnostic> {
+  let mut out = Vec::new();
+  let root = JsonPath::root();
+
+  validate_profile(&config.active_profile(), &root, &mut out);
+
+  for (name, profile) in &config.profiles {
+    let path = root.key("profiles").key(name);
+
+    if name.trim().is_empty() {
+      out.push(Diagnostic::error(path.clone(), "must have a name"));
+    } else if *name == config.profile {
+      out.push(Diagnostic::warning(
+        path.clone(),
+        "has the same name as the active profile, the top level settings are used instead",
+      ));
+    }
+
+    validate_profile(profile, &path, &mut out);
+  }
+
+  validate_rules(&config.rules, &root.key("rules"), &mut out);
+
+  out
+}
+
+fn take_tiers(config: &mut Value) -> Option<Value> {
+  config.get_mut("power")?.as_object_mut()?.remove("tiers")
+}
+
+/// Parses every entry of `list` on its own
+fn check_entries<T: DeserializeOwned>(
+  list: Option<Value>,
+  path: &JsonPath,
+  out: &mut Vec<Diagnostic>,
+) {
+  match list {
+    Some(Value::Array(entries)) => {
+      for (i, entry) in entries.into_iter().enumerate() {
+        if let Err(e) = serde_json::from_value::<T>(entry) {
+          out.push(Diagnostic::error(path.index(i), e.to_string()));
+        }
+      }
+    }
+    Some(list) => out.push(Diagnostic::error(
+      path.clone(),
+      format!("expected an array, found {}", json_type(&list)),
+    )),
+    None => {}
+  }
+}
+
+/// Checks the shape and values of an already migrated config
+pub fn check_value(value: &Value) -> Vec<Diagnostic> {
+  let mut out = Vec::new();
+
+  let mut defaults = serde_json::to_value(Config::new()).unwrap_or_default();
+  defaults["profiles"] =
+    serde_json::json!({ "*": serde_json::to_value(Profile::default()).unwrap_or_default() });
+  defaults["log"]["targets"] = serde_json::json!({ "*": Level::Info });
+
+  // Rules and battery tiers are too free-form for the defaults to describe, each one is parsed
+  // on its own
+  let root = JsonPath::root();
+  let mut shape = value.clone();
+  let rules = shape
+    .as_object_mut()
+    .and_then(|config| config.remove("rules"));
+  let mut tiers = vec![(root.key("power").key("tiers"), take_tiers(&mut shape))];
+  if let Some(profiles) = shape.get_mut("profiles").and_then(Value::as_object_mut) {
+    for (name, profile) in profiles {
+      let path = root.key("profiles").key(name).key("power").key("tiers");
+      tiers.push((path, take_tiers(profile)));
+    }
+  }
+  check_shape(&defaults, &shape, &root, &mut out);
+
+  check_entries::<Rule>(rules, &root.key("rules"), &mut out);
+  for (path, tiers) in tiers {
+    check_entries::<BatteryTier>(tiers, &path, &mut out);
+  }
+
+  if !out.iter().any(Diagnostic::is_error) {
+    match serde_json::from_value::<Config>(value.clone()) {
+      Ok(config) => out.append(&mut validate(&config)),
+      Err(e) => out.push(Diagnostic::error(JsonPath::root(), e.to_string())),
+    }
+  }
+
+  out
+}
+
+/// Checks the raw contents of a config file, reporting every problem at once with its
+/// position in the file
+pub fn check(contents: &str) -> Vec<Diagnostic> {
+  check_layer(contents, 0, Coverage::Full)
+}
+
+/// Like `check`, for a file that is assumed to be `default_version` when it has no version of
+/// its own
+pub fn check_layer(contents: &str, default_version: u32, coverage: Coverage) -> Vec<Diagnostic> {
+  let mut value: Value = match serde_json::from_str(contents) {
+    Ok(value) => value,
+    Err(e) => {
+      // serde_json appends the position to its messages, it is shown separately here
+      let message = e.to_string();
+      let message = message.split(" at line ").next().unwrap_or_default();
+
+      let mut diagnostic = Diagnostic::error(JsonPath::root(), message);
+      diagnostic.position = Some((e.line(), e.column()));
+      return vec![diagnostic];
+    }
+  };
+
+  let mut out = match migrate::migrate_layer(&mut value, default_version, coverage) {
+    Ok(_) => check_value(&value),
+    Err(e) => vec![Diagnostic::error(
+      JsonPath::root().key("version"),
+      e.to_string(),
+    )],
+  };
+
+  for diagnostic in &mut out {
+    diagnostic.position = locate(contents, &diagnostic.path);
+  }
+  out.sort_by_key(|diagnostic| diagnostic.position.unwrap_or((usize::MAX, usize::MAX)));
+
+  out
+}
+
+pub fn check_file(path: &std::path::Path) -> std::io::Result<Vec<Diagnostic>> {
+  Ok(check(&std::fs::read_to_string(path)?))
+}
+
+/// A human readable summary of `diagnostics`, one problem per line
+pub fn report(path: &std::path::Path, diagnostics: &[Diagnostic]) -> String {
+  if diagnostics.is_empty() {
+    return format!("No problems found in {}", path.display());
+  }
+
+  let mut report = format!("{}:", path.display());
+  for diagnostic in diagnostics {
+    report += &format!("\n  {}", diagnostic);
+  }
+  report
+}
+
+/// Finds the line and column of `path` in a JSON document. Keys point at the key itself,
+/// array elements at the element.
+pub fn locate(contents: &str, path: &JsonPath) -> Option<(usize, usize)> {
+  let mut scanner = Scanner {
+    bytes: contents.as_bytes(),
+    pos: 0,
+  };
+  let offset = scanner.find(&path.0)?;
+
+  let before = &contents[..offset];
+  let line = before.matches('\n').count() + 1;
+  let column = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+    .chars()
+    .count()
+    + 1;
+  Some((line, column))
+}
+
+struct Scanner<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl Scanner<'_> {
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.pos).copied()
+  }
+
+  fn skip_whitespace(&mut self) {
+    while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+      self.pos += 1;
+    }
+  }
+
+  fn eat(&mut self, byte: u8) -> Option<()> {
+    self.skip_whitespace();
+    if self.peek()? == byte {
+      self.pos += 1;
+      Some(())
+    } else {
+      None
+    }
+  }
+
+  fn string(&mut self) -> Option<String> {
+    self.skip_whitespace();
+    let start = self.pos;
+    if self.peek()? != b'"' {
+      return None;
+    }
+    self.pos += 1;
+
+    loop {
+      match self.peek()? {
+        b'\\' => self.pos += 2,
+        b'"' => break,
+        _ => self.pos += 1,
+      }
+    }
+    self.pos += 1;
+
+    serde_json::from_slice(&self.bytes[start..self.pos]).ok()
+  }
+
+  fn skip_value(&mut self) -> Option<()> {
+    self.skip_whitespace();
+    match self.peek()? {
+      b'"' => {
+        self.string()?;
+      }
+      b'{' => {
+        self.pos += 1;
+        if self.eat(b'}').is_some() {
+          return Some(());
+        }
+        loop {
+          self.string()?;
+          self.eat(b':')?;
+          self.skip_value()?;
+          if self.eat(b',').is_none() {
+            return self.eat(b'}');
+          }
+        }
+      }
+      b'[' => {
+        self.pos += 1;
+        if self.eat(b']').is_some() {
+          return Some(());
+        }
+        loop {
+          self.skip_value()?;
+          if self.eat(b',').is_none() {
+            return self.eat(b']');
+          }
+        }
+      }
+      _ => {
+        while !matches!(
+          self.peek(),
+          None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\n' | b'\r')
+        ) {
+          self.pos += 1;
+        }
+      }
+    }
+
+    Some(())
+  }
+
+  fn find(&mut self, path: &[Segment]) -> Option<usize> {
+    self.skip_whitespace();
+    let Some((segment, rest)) = path.split_first() else {
+      return Some(self.pos);
+    };
+
+    match segment {
+      Segment::Key(key) => {
+        self.eat(b'{')?;
+        loop {
+          self.skip_whitespace();
+          let key_start = self.pos;
+          let name = self.string()?;
+          self.eat(b':')?;
+
+          if name == *key {
+            return if rest.is_empty() {
+              Some(key_start)
+            } else {
+              self.find(rest)
+            };
+          }
+
+          self.skip_value()?;
+          self.eat(b',')?;
+        }
+      }
+      Segment::Index(index) => {
+        self.eat(b'[')?;
+        for _ in 0..*index {
+          self.skip_value()?;
+          self.eat(b',')?;
+        }
+        self.find(rest)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn path(segments: &[Segment]) -> JsonPath {
+    JsonPath(segments.to_vec())
+  }
+
+  fn key(key: &str) -> Segment {
+    Segment::Key(key.to_string())
+  }
+
+  /// A config of the current version, which has nothing to migrate
+  fn current(body: &str) -> String {
+    format!(
+      "{{\n  \"version\": {},\n{}\n}}",
+      migrate::CONFIG_VERSION,
+      body
+    )
+  }
+
+  fn messages(diagnostics: &[Diagnostic]) -> Vec<String> {
+    diagnostics.iter().map(ToString::to_string).collect()
+  }
+
+  #[test]
+  fn locates_nested_keys() {
+    let contents = "{\n  \"power\": {\n    \"enabled\": true,\n    \"timer\": 300\n  }\n}";
+
+    assert_eq!(locate(contents, &path(&[key("power")])), Some((2, 3)));
+    assert_eq!(
+      locate(contents, &path(&[key("power"), key("timer")])),
+      Some((4, 5))
+    );
+    assert_eq!(locate(contents, &path(&[key("missing")])), None);
+    assert_eq!(locate(contents, &JsonPath::root()), Some((1, 1)));
+  }
+
+  #[test]
+  fn locates_array_elements() {
+    let contents = r#"{"apps": ["a.exe", {"b": [1, 2]}, "c.exe"], "x": 1}"#;
+
+    assert_eq!(
+      locate(contents, &path(&[key("apps"), Segment::Index(2)])),
+      Some((1, 35))
+    );
+    assert_eq!(
+      locate(
+        contents,
+        &path(&[key("apps"), Segment::Index(1), key("b"), Segment::Index(1)])
+      ),
+      Some((1, 30))
+    );
+    assert_eq!(locate(contents, &path(&[key("x")])), Some((1, 45)));
+    assert_eq!(
+      locate(contents, &path(&[key("apps"), Segment::Index(3)])),
+      None
+    );
+  }
+
+  #[test]
+  fn skips_escaped_quotes_in_strings() {
+    let contents = r#"{"a": "say \"hi\", \\", "b\"": {}, "b": 1}"#;
+
+    assert_eq!(locate(contents, &path(&[key("b\"")])), Some((1, 25)));
+    assert_eq!(locate(contents, &path(&[key("b")])), Some((1, 36)));
+  }
+
+  #[test]
+  fn counts_columns_in_characters() {
+    let contents = "{\"name\": \"Électricité ⚡\", \"timer\": 1}";
+
+    assert_eq!(locate(contents, &path(&[key("timer")])), Some((1, 27)));
+  }
+
+  #[test]
+  fn check_reports_positions_in_the_file() {
+    let contents = current("  \"power\": {\n    \"percentage\": 150\n  }");
+    let diagnostics = check(&contents);
+
+    assert_eq!(
+      messages(&diagnostics),
+      ["error at line 4, column 5: power.percentage: must be between 1 and 100, or 0 to disable it, got 150"]
+    );
+  }
+
+  #[test]
+  fn check_reports_syntax_errors() {
+    let diagnostics = check("{\n  \"startup\": flase\n}");
+
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].is_error());
+    assert_eq!(diagnostics[0].position.map(|(line, _)| line), Some(2));
+  }
+
+  #[test]
+  fn profiles_are_checked_like_the_top_level() {
+    let mut profile = Profile::default();
+    profile.power.percentage = 101;
+    profile.power.cpu_threshold = 101;
+    profile.microphone.apps = vec![
+      "discord.exe".to_string(),
+      "C:\\apps\\zoom.exe".to_string(),
+      "Teams.exe".to_string(),
+      "slack".to_string(),
+      "discord.exe".to_string(),
+    ];
+    profile.autostart.apps = vec![" ".to_string()];
+
+    let mut out = Vec::new();
+    validate_profile(
+      &profile,
+      &JsonPath::root().key("profiles").key("Work"),
+      &mut out,
+    );
+
+    assert_eq!(
+      messages(&out),
+      [
+        "error: profiles.Work.power.percentage: must be between 1 and 100, or 0 to disable it, got 101",
+        "error: profiles.Work.microphone.apps[1]: `C:\\apps\\zoom.exe` must be an executable name, not a path",
+        "error: profiles.Work.microphone.apps[2]: `Teams.exe` must be lowercase, process names are compared as `teams.exe`",
+        "error: profiles.Work.microphone.apps[3]: `slack` must be an executable name like `discord.exe`",
+        "warning: profiles.Work.microphone.apps[4]: `discord.exe` is listed twice",
+        "error: profiles.Work.power.cpu_threshold: must be between 1 and 100, or 0 to disable it, got 101",
+        "error: profiles.Work.autostart.apps[0]: must not be empty",
+      ]
+    );
+  }
+
+  #[test]
+  fn a_default_config_is_valid() {
+    assert_eq!(validate(&Config::with_default_profiles()), []);
+  }
+
+  fn tier(name: &str, enter_at: u32, exit_at: u32) -> BatteryTier {
+    BatteryTier {
+      name: name.to_string(),
+      enter_at,
+      exit_at,
+      ..BatteryTier::default()
+    }
+  }
+
+  #[test]
+  fn tiers_need_a_name_and_thresholds() {
+    let tiers = [
+      tier("Low", 30, 35),
+      tier("", 20, 25),
+      tier("Low", 0, 0),
+      tier("Critical", 30, 101),
+      tier("Late", 10, 5),
+    ];
+
+    let mut out = Vec::new();
+    validate_tiers(&tiers, &JsonPath::root().key("tiers"), &mut out);
+
+    assert_eq!(
+      messages(&out),
+      [
+        "error: tiers[1].name: must not be empty",
+        "warning: tiers[2].name: `Low` is used by another tier",
+        "error: tiers[2].enter_at: must be between 1 and 100, got 0",
+        "warning: tiers[3].enter_at: is the same as another tier, only one of them is ever entered",
+        "error: tiers[3].exit_at: must be between 0 and 100, got 101",
+        "warning: tiers[4].exit_at: is below `enter_at`, the tier is left as soon as the battery charges above 10",
+      ]
+    );
+  }
+
+  #[test]
+  fn tiers_are_parsed_one_by_one() {
+    let contents = current(
+      r#"  "power": {
+    "tiers": [
+      { "name": "Low", "enter_at": 30, "exit_at": 35 },
+      { "name": "Critical", "enter_at": "ten" }
+    ]
+  }"#,
+    );
+    let diagnostics = check(&contents);
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+      diagnostics[0].path,
+      JsonPath::root().key("power").key("tiers").index(1)
+    );
+    assert_eq!(diagnostics[0].position, Some((6, 7)));
+  }
+}
@@ -0,0 +1,137 @@
+use std::{
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+use anyhow::Result;
+
+use super::{layers::LayeredConfig, store::ConfigStore};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileStamp {
+  modified: Option<SystemTime>,
+  len: u64,
+}
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+  let metadata = std::fs::metadata(path).ok()?;
+  Some(FileStamp {
+    modified: metadata.modified().ok(),
+    len: metadata.len(),
+  })
+}
+
+/// Notices when the config file has been modified on disk
+pub struct ConfigWatcher {
+  path: PathBuf,
+  last: Option<FileStamp>,
+}
+
+impl ConfigWatcher {
+  pub fn new(path: PathBuf) -> Self {
+    let last = stamp(&path);
+    Self { path, last }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Returns true if the file changed since the last call
+  pub fn poll(&mut self) -> bool {
+    let current = stamp(&self.path);
+    if current == self.last {
+      return false;
+    }
+
+    self.last = current;
+    current.is_some()
+  }
+}
+
+/// Re-reads every config layer and swaps the result into `store`.
+///
+/// Returns `Ok(true)` when the running config changed. An invalid file leaves the store
+/// untouched and is reported as an error.
+pub fn reload(store: &ConfigStore, layers: &LayeredConfig) -> Result<bool> {
+  let config = layers.resolve()?.config;
+
+  if config == store.snapshot() {
+    return Ok(false);
+  }
+
+  store.replace(config);
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{config::Config, testing::temp_dir};
+
+  fn layers(name: &str) -> LayeredConfig {
+    let dir = temp_dir(name);
+    LayeredConfig {
+      machine: dir.join("machine.json"),
+      user: dir.join("user.json"),
+      overrides: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn notices_a_file_being_created_changed_and_removed() {
+    let path = temp_dir("watch-poll").join("user.json");
+    let mut watcher = ConfigWatcher::new(path.clone());
+    assert!(!watcher.poll());
+
+    std::fs::write(&path, "{}").unwrap();
+    assert!(watcher.poll());
+    assert!(!watcher.poll());
+
+    std::fs::write(&path, r#"{"power": {}}"#).unwrap();
+    assert!(watcher.poll());
+
+    // Nothing to reload until it comes back
+    std::fs::remove_file(&path).unwrap();
+    assert!(!watcher.poll());
+    assert!(!watcher.poll());
+  }
+
+  #[test]
+  fn reloading_swaps_in_the_new_config() {
+    let layers = layers("watch-reload");
+    let store = ConfigStore::new(layers.resolve().unwrap().config);
+    let changes = store.subscribe();
+
+    std::fs::write(&layers.user, r#"{"power": {"timer": 60}}"#).unwrap();
+    assert!(reload(&store, &layers).unwrap());
+    assert_eq!(store.snapshot().power.timer, 60);
+    assert_eq!(changes.try_recv().unwrap().power.timer, 60);
+
+    // The same config again is not a change
+    assert!(!reload(&store, &layers).unwrap());
+    assert!(changes.try_recv().is_err());
+  }
+
+  #[test]
+  fn an_invalid_file_keeps_the_previous_config() {
+    let layers = layers("watch-invalid");
+    std::fs::write(&layers.user, r#"{"power": {"timer": 60}}"#).unwrap();
+    let store = ConfigStore::new(layers.resolve().unwrap().config);
+    let changes = store.subscribe();
+
+    for invalid in [r#"{"power": {"timer": "#, r#"{"power": {"timer": "soon"}}"#] {
+      std::fs::write(&layers.user, invalid).unwrap();
+      let error = reload(&store, &layers).unwrap_err();
+      assert!(format!("{:#}", error).contains("user.json"), "{:#}", error);
+      assert_eq!(store.snapshot().power.timer, 60);
+      assert!(changes.try_recv().is_err());
+    }
+
+    // Fixing the file picks up where it left off
+    std::fs::write(&layers.user, r#"{"power": {"timer": 90}}"#).unwrap();
+    assert!(reload(&store, &layers).unwrap());
+    assert_eq!(store.snapshot().power.timer, 90);
+    assert_ne!(store.snapshot(), Config::new());
+  }
+}
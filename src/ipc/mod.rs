@@ -0,0 +1,224 @@
+#[cfg(windows)]
+mod pipe;
+#[cfg(unix)]
+mod socket;
+
+#[cfg(windows)]
+pub use pipe::{connect_to, endpoint, Listener, Stream};
+#[cfg(unix)]
+pub use socket::{connect_to, endpoint, Listener, Stream};
+
+use std::{
+  io::{self, BufRead, BufReader, Read, Write},
+  panic::{catch_unwind, AssertUnwindSafe},
+  path::Path,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{cli::Output, log::warning};
+
+/// A command line forwarded by a later launch. Requests and replies are one JSON line each.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+  pub args: Vec<String>,
+}
+
+/// The longest request the running instance reads, a command line being far shorter
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// The longest reply a forwarding launch reads, room for a week of battery history
+const MAX_REPLY: u64 = 16 * 1024 * 1024;
+
+/// Forwarded commands served at once, more connections are closed right away
+const MAX_CONNECTIONS: usize = 8;
+
+fn write_line(stream: &mut impl Write, value: &impl Serialize) -> Result<()> {
+  let mut line = serde_json::to_string(value)?;
+  line.push('\n');
+  stream.write_all(line.as_bytes())?;
+  stream.flush()?;
+  Ok(())
+}
+
+/// Returns `None` when the other end hung up without sending anything. Fails on a line longer
+/// than `limit` rather than reading it all.
+fn read_line<T: DeserializeOwned>(stream: &mut impl Read, limit: u64) -> Result<Option<T>> {
+  let mut line = String::new();
+  BufReader::new(stream.take(limit + 1)).read_line(&mut line)?;
+  if line.is_empty() {
+    return Ok(None);
+  }
+  if line.len() as u64 > limit {
+    return Err(anyhow!("The message is longer than {} bytes", limit));
+  }
+  Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// Claims the endpoint of the running instance, failing with `AddrInUse` if it is taken
+pub fn listen() -> std::io::Result<Listener> {
+  Listener::bind(&endpoint())
+}
+
+/// Forwards `args` to the running instance and returns its output, or `None` if there is no
+/// running instance
+pub fn forward(args: &[String]) -> Result<Option<Output>> {
+  forward_to(&endpoint(), args)
+}
+
+/// Whether `e` came from a running instance that does not let this user connect, which is
+/// the only failure where the command has not reached it
+pub fn is_access_denied(e: &anyhow::Error) -> bool {
+  e.downcast_ref::<io::Error>()
+    .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+fn forward_to(path: &Path, args: &[String]) -> Result<Option<Output>> {
+  let Some(mut stream) = connect_to(path)? else {
+    return Ok(None);
+  };
+
+  write_line(
+    &mut stream,
+    &Request {
+      args: args.to_vec(),
+    },
+  )?;
+  let output = read_line(&mut stream, MAX_REPLY)?;
+  Ok(Some(output.ok_or_else(|| {
+    anyhow!("The running instance closed the connection")
+  })?))
+}
+
+/// Answers the request on `stream` with `handle`. A connection that only checked whether an
+/// instance is running is closed without an answer.
+pub fn serve(mut stream: Stream, handle: impl FnOnce(&Request) -> Output) -> Result<()> {
+  match read_line::<Request>(&mut stream, MAX_REQUEST)? {
+    Some(request) => write_line(&mut stream, &handle(&request)),
+    None => Ok(()),
+  }
+}
+
+/// Serves every connection to `listener` with `handle` on a thread of its own, so a client
+/// that never sends its request only holds up itself
+pub fn serve_all(
+  listener: Listener,
+  handle: impl Fn(&Request) -> Output + Send + Sync + 'static,
+) -> ! {
+  let handle = Arc::new(handle);
+  let connections = Arc::new(AtomicUsize::new(0));
+  loop {
+    let stream = match listener.accept() {
+      Ok(stream) => stream,
+      Err(e) => {
+        warning!("Cannot accept a forwarded command: {}", e);
+        std::thread::sleep(Duration::from_secs(1));
+        continue;
+      }
+    };
+
+    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+      connections.fetch_sub(1, Ordering::SeqCst);
+      warning!("Too many forwarded commands, closing a new one");
+      continue;
+    }
+
+    let handle = handle.clone();
+    let connections = connections.clone();
+    std::thread::spawn(move || {
+      // A command that panics only loses its own connection
+      match catch_unwind(AssertUnwindSafe(|| {
+        serve(stream, |request| handle(request))
+      })) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warning!("Cannot answer a forwarded command: {:#}", e),
+        Err(_) => warning!("A forwarded command panicked"),
+      }
+      connections.fetch_sub(1, Ordering::SeqCst);
+    });
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use std::thread;
+
+  use super::*;
+  use crate::testing::temp_dir;
+
+  fn echo(request: &Request) -> Output {
+    Output {
+      code: 0,
+      stdout: request.args.join(" "),
+      stderr: String::new(),
+    }
+  }
+
+  #[test]
+  fn forwards_a_command_and_its_output() {
+    let path = temp_dir("ipc-round-trip").join("socket");
+    let listener = Listener::bind(&path).unwrap();
+    let server = thread::spawn(move || serve(listener.accept().unwrap(), echo));
+
+    let args = ["status".to_string(), "--json".to_string()];
+    let output = forward_to(&path, &args).unwrap().unwrap();
+    assert_eq!(output.stdout, "status --json");
+    server.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn nothing_to_forward_to_without_an_instance() {
+    let path = temp_dir("ipc-missing").join("socket");
+    assert!(forward_to(&path, &["status".to_string()])
+      .unwrap()
+      .is_none());
+  }
+
+  #[test]
+  fn a_second_instance_cannot_listen() {
+    let path = temp_dir("ipc-second").join("socket");
+    let _listener = Listener::bind(&path).unwrap();
+    let error = Listener::bind(&path).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+  }
+
+  #[test]
+  fn a_connection_that_only_checks_is_not_answered() {
+    let path = temp_dir("ipc-check").join("socket");
+    let listener = Listener::bind(&path).unwrap();
+    drop(connect_to(&path).unwrap());
+
+    let served = serve(listener.accept().unwrap(), |_| panic!("Nothing was asked"));
+    assert!(served.is_ok());
+  }
+
+  #[test]
+  fn an_overlong_request_is_refused() {
+    let path = temp_dir("ipc-overlong").join("socket");
+    let listener = Listener::bind(&path).unwrap();
+    let server = thread::spawn(move || serve(listener.accept().unwrap(), echo));
+
+    let mut stream = connect_to(&path).unwrap().unwrap();
+    stream
+      .write_all(&vec![b'a'; MAX_REQUEST as usize + 10])
+      .unwrap();
+    assert!(server.join().unwrap().is_err());
+  }
+
+  #[test]
+  fn a_silent_client_does_not_hold_up_the_others() {
+    let path = temp_dir("ipc-silent").join("socket");
+    let listener = Listener::bind(&path).unwrap();
+    thread::spawn(move || serve_all(listener, echo));
+
+    let _silent = connect_to(&path).unwrap().unwrap();
+    let output = forward_to(&path, &["status".to_string()]).unwrap().unwrap();
+    assert_eq!(output.stdout, "status");
+  }
+}
@@ -0,0 +1,169 @@
+use std::{
+  fs::File,
+  io::{self, Read, Write},
+  os::windows::{
+    fs::OpenOptionsExt,
+    io::{AsRawHandle, FromRawHandle},
+  },
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use windows::{
+  core::HSTRING,
+  Win32::{
+    Foundation::{
+      LocalFree, BOOL, ERROR_ACCESS_DENIED, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, HANDLE, HLOCAL,
+    },
+    Security::{
+      Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+      PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+    },
+    Storage::FileSystem::{
+      FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_GENERIC_READ, FILE_WRITE_DATA, PIPE_ACCESS_DUPLEX,
+    },
+    System::Pipes::{
+      ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+      PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    },
+  },
+};
+
+/// How long a client keeps trying while every pipe instance is busy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Full access for the system, administrators and whoever created the pipe, so an elevated tray
+/// app is still reachable. The interactive user may read and write, but not create instances
+/// that would take clients away from the running one.
+const PIPE_SDDL: &str = "D:(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)(A;;0x12019B;;;IU)";
+
+/// What a client asks for, within what `PIPE_SDDL` grants the interactive user
+const CLIENT_ACCESS: u32 = FILE_GENERIC_READ.0 | FILE_WRITE_DATA.0;
+
+/// One end of a connected pipe
+pub struct Stream(File);
+
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.read(buf)
+  }
+}
+
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.write(buf)
+  }
+
+  /// Waits until the other end has read everything, so closing the pipe loses nothing
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.sync_all()
+  }
+}
+
+/// One pipe per user session
+pub fn endpoint() -> PathBuf {
+  let user = std::env::var("USERNAME").unwrap_or_default();
+  PathBuf::from(format!(r"\\.\pipe\PwccaAuto-{}", user))
+}
+
+fn create(path: &Path, first: bool) -> io::Result<File> {
+  let mut open_mode = PIPE_ACCESS_DUPLEX;
+  if first {
+    open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+  }
+
+  let mut descriptor = PSECURITY_DESCRIPTOR::default();
+  unsafe {
+    ConvertStringSecurityDescriptorToSecurityDescriptorW(
+      &HSTRING::from(PIPE_SDDL),
+      SDDL_REVISION_1,
+      &mut descriptor,
+      None,
+    )
+  }?;
+  let attributes = SECURITY_ATTRIBUTES {
+    nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+    lpSecurityDescriptor: descriptor.0,
+    bInheritHandle: BOOL(0),
+  };
+
+  let handle = unsafe {
+    CreateNamedPipeW(
+      &HSTRING::from(path.as_os_str()),
+      open_mode,
+      PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+      PIPE_UNLIMITED_INSTANCES,
+      4096,
+      4096,
+      0,
+      Some(&attributes),
+    )
+  };
+  // Taken before freeing the descriptor can overwrite it
+  let error = io::Error::last_os_error();
+  unsafe { LocalFree(HLOCAL(descriptor.0)) };
+
+  if handle.is_invalid() {
+    if first && error.raw_os_error() == Some(ERROR_ACCESS_DENIED.0 as i32) {
+      return Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "Another instance is already running",
+      ));
+    }
+    return Err(error);
+  }
+
+  Ok(unsafe { File::from_raw_handle(handle.0) })
+}
+
+/// Always keeps one pipe instance waiting, so the name stays claimed between clients
+pub struct Listener {
+  path: PathBuf,
+  pending: Mutex<File>,
+}
+
+impl Listener {
+  pub fn bind(path: &Path) -> io::Result<Self> {
+    Ok(Self {
+      path: path.to_path_buf(),
+      pending: Mutex::new(create(path, true)?),
+    })
+  }
+
+  pub fn accept(&self) -> io::Result<Stream> {
+    let mut pending = self.pending.lock().unwrap();
+
+    if let Err(e) = unsafe { ConnectNamedPipe(HANDLE(pending.as_raw_handle()), None) } {
+      // The client got in between creating the instance and waiting for it
+      if e.code() != ERROR_PIPE_CONNECTED.to_hresult() {
+        return Err(e.into());
+      }
+    }
+
+    let next = create(&self.path, false)?;
+    Ok(Stream(std::mem::replace(&mut *pending, next)))
+  }
+}
+
+/// Returns `None` when nothing listens at `path`. Fails with `PermissionDenied` when the
+/// running instance does not let this user in.
+pub fn connect_to(path: &Path) -> io::Result<Option<Stream>> {
+  let deadline = Instant::now() + BUSY_TIMEOUT;
+
+  loop {
+    match File::options()
+      .read(true)
+      .write(true)
+      .access_mode(CLIENT_ACCESS)
+      .open(path)
+    {
+      Ok(file) => return Ok(Some(Stream(file))),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) && Instant::now() < deadline => {
+        std::thread::sleep(Duration::from_millis(50));
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
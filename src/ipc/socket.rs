@@ -0,0 +1,63 @@
+use std::{
+  io,
+  os::unix::net::{UnixListener, UnixStream},
+  path::{Path, PathBuf},
+};
+
+pub type Stream = UnixStream;
+
+/// One socket per user in the temp directory
+pub fn endpoint() -> PathBuf {
+  let user = std::env::var("USER").unwrap_or_default();
+  std::env::temp_dir().join(format!("PwccaAuto-{}.sock", user))
+}
+
+pub struct Listener {
+  listener: UnixListener,
+  path: PathBuf,
+}
+
+impl Listener {
+  pub fn bind(path: &Path) -> io::Result<Self> {
+    if connect_to(path)?.is_some() {
+      return Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "Another instance is already running",
+      ));
+    }
+
+    // Left behind by an instance that did not exit cleanly
+    let _ = std::fs::remove_file(path);
+
+    Ok(Self {
+      listener: UnixListener::bind(path)?,
+      path: path.to_path_buf(),
+    })
+  }
+
+  pub fn accept(&self) -> io::Result<Stream> {
+    Ok(self.listener.accept()?.0)
+  }
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+/// Returns `None` when nothing listens at `path`
+pub fn connect_to(path: &Path) -> io::Result<Option<Stream>> {
+  match UnixStream::connect(path) {
+    Ok(stream) => Ok(Some(stream)),
+    Err(e)
+      if matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+      ) =>
+    {
+      Ok(None)
+    }
+    Err(e) => Err(e),
+  }
+}
@@ -0,0 +1,423 @@
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs::{File, OpenOptions},
+  io::Write,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{Mutex, RwLock},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The log is rotated once it would grow past this size
+pub const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// Rotated logs kept next to the current one, `PwccaAuto.log.1` being the newest
+pub const MAX_LOG_FILES: usize = 3;
+
+pub const LOG_FILE: &str = "PwccaAuto.log";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+pub const LEVELS: [Level; 5] = [
+  Level::Error,
+  Level::Warn,
+  Level::Info,
+  Level::Debug,
+  Level::Trace,
+];
+
+impl Level {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+      Level::Trace => "trace",
+    }
+  }
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.as_str())
+  }
+}
+
+impl FromStr for Level {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    LEVELS
+      .into_iter()
+      .find(|level| level.as_str().eq_ignore_ascii_case(s))
+      .ok_or_else(|| anyhow!("Unknown log level {}", s))
+  }
+}
+
+/// Which records are written: `level` for everything, or the level of the longest matching
+/// entry in `targets`, such as `rules` or `supervisor::module`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+  level: Level,
+  targets: BTreeMap<String, Level>,
+}
+
+impl Filter {
+  fn level_for(&self, target: &str) -> Level {
+    self
+      .targets
+      .iter()
+      .filter(|(prefix, _)| {
+        target == prefix.as_str()
+          || target
+            .strip_prefix(prefix.as_str())
+            .is_some_and(|rest| rest.starts_with("::"))
+      })
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map_or(self.level, |(_, level)| *level)
+  }
+}
+
+struct Sink {
+  path: PathBuf,
+  file: File,
+  size: u64,
+}
+
+impl Sink {
+  fn open(path: &Path) -> Result<Self> {
+    if let Some(directory) = path.parent() {
+      std::fs::create_dir_all(directory)?;
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Self {
+      path: path.to_path_buf(),
+      file,
+      size,
+    })
+  }
+
+  fn rotated(&self, index: usize) -> PathBuf {
+    let mut name = self.path.clone().into_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+  }
+
+  /// Shifts every log one number up, dropping the oldest, and starts an empty one
+  fn rotate(&mut self) -> Result<()> {
+    for index in (1..MAX_LOG_FILES).rev() {
+      let from = self.rotated(index);
+      if from.exists() {
+        std::fs::rename(&from, self.rotated(index + 1))?;
+      }
+    }
+    std::fs::rename(&self.path, self.rotated(1))?;
+
+    *self = Sink::open(&self.path)?;
+    Ok(())
+  }
+
+  fn write(&mut self, line: &str) -> Result<()> {
+    let length = line.len() as u64 + 1;
+    if self.size > 0 && self.size + length > MAX_LOG_SIZE {
+      self.rotate()?;
+    }
+
+    writeln!(self.file, "{}", line)?;
+    self.size += length;
+    Ok(())
+  }
+}
+
+/// Seconds since the Unix epoch as `2024-01-31T12:00:00.000Z`
+pub fn timestamp(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let seconds = since_epoch.as_secs();
+  let (days, rest) = (seconds / 86400, seconds % 86400);
+
+  // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`
+  let z = days as i64 + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    rest / 3600,
+    rest % 3600 / 60,
+    rest % 60,
+    since_epoch.subsec_millis()
+  )
+}
+
+/// `PwccaAuto::supervisor::module` as `supervisor::module`
+fn short_target(target: &str) -> &str {
+  target.split_once("::").map_or(target, |(_, rest)| rest)
+}
+
+/// Writes every record that passes the filter as one JSON line to the log file, and as text to
+/// the console for a debug run
+pub struct Logger {
+  filter: RwLock<Filter>,
+  sink: Mutex<Option<Sink>>,
+}
+
+impl Logger {
+  pub const fn new() -> Self {
+    Self {
+      filter: RwLock::new(Filter {
+        level: Level::Info,
+        targets: BTreeMap::new(),
+      }),
+      sink: Mutex::new(None),
+    }
+  }
+
+  /// Starts writing to `path`. Records logged before this only went to the console.
+  pub fn open(&self, path: &Path) -> Result<()> {
+    *self.sink.lock().unwrap() = Some(Sink::open(path)?);
+    Ok(())
+  }
+
+  pub fn path(&self) -> Option<PathBuf> {
+    self
+      .sink
+      .lock()
+      .unwrap()
+      .as_ref()
+      .map(|sink| sink.path.clone())
+  }
+
+  pub fn configure(&self, level: Level, targets: &BTreeMap<String, Level>) {
+    *self.filter.write().unwrap() = Filter {
+      level,
+      targets: targets.clone(),
+    };
+  }
+
+  pub fn level(&self) -> Level {
+    self.filter.read().unwrap().level
+  }
+
+  pub fn enabled(&self, level: Level, target: &str) -> bool {
+    level <= self.filter.read().unwrap().level_for(short_target(target))
+  }
+
+  pub fn log(&self, level: Level, target: &str, args: fmt::Arguments) {
+    if !self.enabled(level, target) {
+      return;
+    }
+
+    let time = timestamp(SystemTime::now());
+    let target = short_target(target);
+    let message = args.to_string();
+
+    #[cfg(debug_assertions)]
+    println!("{} {:5} {}: {}", time, level, target, message);
+
+    let mut sink = self.sink.lock().unwrap();
+    if let Some(sink) = sink.as_mut() {
+      let record = json!({
+        "time": time,
+        "level": level,
+        "target": target,
+        "thread": std::thread::current().name().unwrap_or("unnamed"),
+        "message": message,
+      });
+
+      // There is nowhere left to report a log that cannot be written
+      let _ = sink.write(&record.to_string());
+    }
+  }
+}
+
+pub static LOGGER: Logger = Logger::new();
+
+/// The log file next to the per-user config at `config_path`
+pub fn log_path(config_path: &Path) -> PathBuf {
+  config_path
+    .parent()
+    .unwrap_or(Path::new("."))
+    .join(LOG_FILE)
+}
+
+macro_rules! log {
+  ($level:expr, $($arg:tt)+) => {
+    $crate::log::LOGGER.log($level, module_path!(), format_args!($($arg)+))
+  };
+}
+
+macro_rules! error {
+  ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! warning {
+  ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+  ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+  ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+  ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+pub(crate) use {debug, error, info, log, trace, warning};
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::testing::temp_dir;
+
+  fn filter(targets: &[(&str, Level)]) -> Filter {
+    Filter {
+      level: Level::Info,
+      targets: targets
+        .iter()
+        .map(|(target, level)| (target.to_string(), *level))
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn the_longest_matching_target_wins() {
+    let filter = filter(&[
+      ("rules", Level::Debug),
+      ("supervisor", Level::Warn),
+      ("supervisor::module", Level::Trace),
+    ]);
+
+    assert_eq!(filter.level_for("rules"), Level::Debug);
+    assert_eq!(filter.level_for("rules::tiers"), Level::Debug);
+    assert_eq!(filter.level_for("supervisor"), Level::Warn);
+    assert_eq!(filter.level_for("supervisor::module"), Level::Trace);
+    assert_eq!(filter.level_for("supervisor::module::tests"), Level::Trace);
+    assert_eq!(filter.level_for("app"), Level::Info);
+  }
+
+  #[test]
+  fn targets_match_whole_module_names() {
+    let filter = filter(&[("rule", Level::Trace), ("api", Level::Error)]);
+
+    assert_eq!(filter.level_for("rules"), Level::Info);
+    assert_eq!(filter.level_for("rule"), Level::Trace);
+    assert_eq!(filter.level_for("app"), Level::Info);
+    assert_eq!(filter.level_for("api::http"), Level::Error);
+  }
+
+  #[test]
+  fn the_crate_name_is_left_out_of_targets() {
+    assert_eq!(
+      short_target("PwccaAuto::supervisor::module"),
+      "supervisor::module"
+    );
+    assert_eq!(short_target("PwccaAuto"), "PwccaAuto");
+  }
+
+  #[test]
+  fn rotates_before_growing_past_the_size_limit() {
+    let path = temp_dir("log-rotate").join(LOG_FILE);
+    let mut sink = Sink::open(&path).unwrap();
+
+    // Three lines fit in a file, the fourth starts the next one
+    let line = |index: usize| format!("{:03}{}", index, "x".repeat(300_000 - 3));
+    for index in 0..15 {
+      sink.write(&line(index)).unwrap();
+    }
+
+    let first_lines = (0..=MAX_LOG_FILES)
+      .map(|index| {
+        let path = if index == 0 {
+          path.clone()
+        } else {
+          sink.rotated(index)
+        };
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert!(contents.len() as u64 <= MAX_LOG_SIZE);
+        contents
+          .lines()
+          .map(|line| line[..3].to_string())
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      first_lines,
+      [
+        ["012", "013", "014"],
+        ["009", "010", "011"],
+        ["006", "007", "008"],
+        ["003", "004", "005"],
+      ]
+    );
+    assert!(!sink.rotated(MAX_LOG_FILES + 1).exists());
+  }
+
+  #[test]
+  fn an_overlong_line_still_gets_written() {
+    let path = temp_dir("log-overlong").join(LOG_FILE);
+    let mut sink = Sink::open(&path).unwrap();
+
+    sink.write(&"x".repeat(MAX_LOG_SIZE as usize * 2)).unwrap();
+    assert!(!sink.rotated(1).exists());
+
+    sink.write("next").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "next\n");
+    assert!(sink.rotated(1).exists());
+  }
+
+  #[test]
+  fn reopening_carries_on_with_the_size_so_far() {
+    let path = temp_dir("log-reopen").join(LOG_FILE);
+    Sink::open(&path).unwrap().write("first").unwrap();
+
+    let sink = Sink::open(&path).unwrap();
+    assert_eq!(sink.size, "first\n".len() as u64);
+  }
+
+  #[test]
+  fn timestamps() {
+    let at = |seconds: u64, millis: u64| {
+      timestamp(UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis))
+    };
+
+    assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(at(946_684_799, 999), "1999-12-31T23:59:59.999Z");
+    assert_eq!(at(951_782_400, 0), "2000-02-29T00:00:00.000Z");
+    assert_eq!(at(1_706_702_400, 0), "2024-01-31T12:00:00.000Z");
+    assert_eq!(at(1_709_210_096, 789), "2024-02-29T12:34:56.789Z");
+    // 2100 is not a leap year
+    assert_eq!(at(4_107_542_399, 0), "2100-02-28T23:59:59.000Z");
+    assert_eq!(at(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+  }
+}
@@ -1,406 +1,294 @@
 #![allow(dead_code)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod api;
+#[cfg(windows)]
+mod app;
+mod backend;
+mod battery;
+mod bus;
+mod cli;
 mod config;
+mod ipc;
+mod log;
+#[cfg(windows)]
 mod mods;
-
-use config::Config;
-use mods::{
-  connection::{is_ethernet_plugged_in, set_wifi_state},
-  display::{get_all_frequencies, get_current_frequency, set_new_frequency, turn_off_monitor},
-  media::{
-    change_default_output, enumerate_audio_devices, get_active_audio_applications,
-    get_default_device, init,
-    types::{device::DeviceType, error::AudioDeviceError},
-  },
-  power::{
-    get_active_power_scheme, get_all_power_schemes, get_power_status, set_active_power_scheme,
-  },
-  process::get_processes_by_name,
-  startup::{
-    registry::{get_all_startup_items, set_startup_item_state},
-    task_scheduler::TaskScheduler,
-  },
-  taskbar::taskbar_automation,
+mod replay;
+mod rules;
+mod supervisor;
+#[cfg(test)]
+mod testing;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use backend::{
+  dry_run::{self, DryRun},
+  Backend, PowerBackend,
 };
-
-use anyhow::Result;
-use std::{mem::MaybeUninit, time::Duration};
-use trayicon::{MenuBuilder, TrayIcon, TrayIconBuilder};
-use windows::{
-  core::w,
-  Win32::{
-    Foundation::{CloseHandle, HANDLE, HWND, TRUE, WIN32_ERROR},
-    Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
-    System::Threading::{GetCurrentProcess, OpenProcessToken},
-    UI::WindowsAndMessaging::{
-      DispatchMessageW, GetMessageW, MessageBoxW, TranslateMessage, MB_ICONERROR, MB_OK,
-      MB_SYSTEMMODAL,
-    },
-  },
+use config::{
+  layers::{self, Layer, LayeredConfig, Override},
+  validate,
 };
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-enum Events {
-  LeftClickTrayIcon,
-
-  Startup,
-
-  Discord,
-  Ethernet,
-  Taskbar,
-
-  TurnOffMonitor,
-  RefreshRate,
-
-  Exit,
-}
-
-static mut CONFIG: Config = Config::new();
-
-fn setup_tray_icon_menu(tray_icon: &mut trayicon::TrayIcon<Events>) -> Result<()> {
-  tray_icon
-    .set_menu(
-      &MenuBuilder::new()
-        .checkable(
-          "Run with Windows",
-          unsafe { CONFIG.startup },
-          Events::Startup,
-        )
-        .separator()
-        .checkable(
-          "Microphone",
-          unsafe { CONFIG.microphone.enabled },
-          Events::Discord,
-        )
-        .checkable("Ethernet", unsafe { CONFIG.ethernet }, Events::Ethernet)
-        .checkable(
-          "Taskbar",
-          unsafe { CONFIG.taskbar.enabled },
-          Events::Taskbar,
-        )
-        .separator()
-        .item("Turn off monitor", Events::TurnOffMonitor)
-        .item(
-          format!("Refresh Rate: {} Hz", get_current_frequency()).as_str(),
-          Events::RefreshRate,
-        )
-        .separator()
-        .item("Exit", Events::Exit),
-    )
-    .unwrap();
-
-  unsafe {
-    let _ = CONFIG.write();
-  };
-
-  Ok(())
-}
-
-fn is_elevated() -> Result<bool> {
-  let mut elevated = false;
-  let mut token_handle = HANDLE::default();
-
-  unsafe {
-    if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token_handle).is_ok() {
-      let mut elevation = TOKEN_ELEVATION::default();
-      let size = std::mem::size_of_val(&elevation) as u32;
-      let mut returnlength = 0;
-
-      if GetTokenInformation(
-        token_handle,
-        TokenElevation,
-        Some(&mut elevation as *mut _ as *mut _),
-        size,
-        &mut returnlength,
-      )
-      .is_ok()
-      {
-        elevated = elevation.TokenIsElevated != 0;
-      }
-    };
-
-    let _ = CloseHandle(token_handle);
-  };
-
-  Ok(elevated)
+enum Command {
+  Run,
+  CheckConfig(Option<PathBuf>),
+  PrintConfig,
+  Replay(PathBuf),
+  Cli(Vec<String>),
 }
 
 fn main() -> Result<()> {
-  // Check if another instance is running
-  if get_processes_by_name("PwccaAuto")?.len() > 1 {
-    unsafe {
-      MessageBoxW(
-        HWND::default(),
-        w!("Another instance is already running"),
-        w!("Error"),
-        MB_SYSTEMMODAL | MB_ICONERROR | MB_OK,
-      )
-    };
-
-    std::process::exit(1);
-  }
+  let args: Vec<String> = std::env::args().skip(1).collect();
 
-  // Check if the process is elevated
-  if !is_elevated()? {
-    unsafe {
-      MessageBoxW(
-        HWND::default(),
-        w!("This application requires administrator privileges"),
-        w!("Error"),
-        MB_SYSTEMMODAL | MB_ICONERROR | MB_OK,
-      )
-    };
-
-    std::process::exit(1);
+  // Release builds have no console of their own, borrow the one we were started from
+  #[cfg(windows)]
+  if !args.is_empty() {
+    app::attach_console();
   }
 
-  // Main application starts here
-  unsafe {
-    CONFIG = Config::read()?;
-  };
-
-  println!("Running Pwcca Auto");
-
-  let (sender, receiver) = std::sync::mpsc::channel::<Events>();
-
-  // Tray icon
-  let mut tray_icon = TrayIconBuilder::new()
-    .sender(move |e| sender.send(*e).unwrap())
-    .icon_from_buffer(include_bytes!("../res/icon.ico"))
-    .tooltip("Pwcca Auto")
-    .on_click(Events::LeftClickTrayIcon)
-    .build()
-    .unwrap();
-
-  setup_tray_icon_menu(&mut tray_icon)?;
-
-  // Threading
-  let _ = std::thread::Builder::new()
-    .name("Startup_Thread".to_string())
-    .spawn(startup_thread);
-  let _ = std::thread::Builder::new()
-    .name("Power_Thread".to_string())
-    .spawn(power_thread);
-  let _ = std::thread::Builder::new()
-    .name("Media_Thread".to_string())
-    .spawn(media_thread);
-  let _ = std::thread::Builder::new()
-    .name("Connection_Thread".to_string())
-    .spawn(connection_thread);
-  let _ = std::thread::Builder::new()
-    .name("Taskbar_Thread".to_string())
-    .spawn(taskbar_thread);
-  let _ = std::thread::Builder::new()
-    .name("Tray_Thread".to_string())
-    .spawn(move || tray_thread(receiver, tray_icon));
-
-  // Application loop
-  loop {
-    unsafe {
-      let mut msg = MaybeUninit::uninit();
-      let bret = GetMessageW(msg.as_mut_ptr(), None, 0, 0);
-      if bret == TRUE {
-        let _ = TranslateMessage(msg.as_ptr());
-        DispatchMessageW(msg.as_ptr());
-      } else {
-        break;
+  let mut overrides = layers::env_overrides(std::env::vars())?;
+  let mut command = Command::Run;
+  let mut json = false;
+  let mut record = None;
+  let mut speed = None;
+
+  let mut args = args.into_iter().peekable();
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--set" => {
+        let assignment = args
+          .next()
+          .ok_or_else(|| anyhow!("--set expects key=value"))?;
+        overrides.push(Override::parse(Layer::Cli, &assignment)?);
       }
-    }
-  }
-
-  Ok(())
-}
-
-fn startup_thread() -> Result<()> {
-  // Initialize the tray thread
-  println!("  + Running Startup Thread");
-
-  loop {
-    let disallow = ["Discord", "WallpaperEngine", "Overwolf", "Joplin.lnk"];
-    let disallow: Vec<String> = disallow.iter().map(|s| s.to_string()).collect();
-
-    let is_plugged_in = get_power_status().is_plugged_in;
-    let startup_items = get_all_startup_items()?;
-
-    for item in &startup_items {
-      if disallow.contains(&item.name) {
-        set_startup_item_state(item, is_plugged_in)
-          .unwrap_or_else(|_| panic!("Cannot disable {} startup", item.name));
+      "--check-config" => {
+        let path = args.next_if(|arg| !arg.starts_with("--"));
+        command = Command::CheckConfig(path.map(PathBuf::from));
+      }
+      "--print-config" => command = Command::PrintConfig,
+      "--record" => {
+        let path = args
+          .next()
+          .ok_or_else(|| anyhow!("--record expects a file"))?;
+        record = Some(PathBuf::from(path));
+      }
+      "--replay" => {
+        let path = args
+          .next()
+          .ok_or_else(|| anyhow!("--replay expects a file"))?;
+        command = Command::Replay(PathBuf::from(path));
       }
+      "--speed" => {
+        let factor = args
+          .next()
+          .and_then(|factor| factor.parse::<f64>().ok())
+          .filter(|factor| *factor > 0.0)
+          .ok_or_else(|| anyhow!("--speed expects a positive number"))?;
+        speed = Some(factor);
+      }
+      "--dry-run" => overrides.push(Override::parse(Layer::Cli, "dry_run=true")?),
+      "--json" => json = true,
+      _ if cli::COMMANDS.contains(&arg.as_str()) => {
+        let mut cli_args = vec![arg];
+        cli_args.extend(args.by_ref());
+        command = Command::Cli(cli_args);
+      }
+      _ => return Err(anyhow!("Unknown argument {}", arg)),
     }
-
-    std::thread::sleep(Duration::from_secs(1));
   }
-}
-
-fn tray_thread(receiver: std::sync::mpsc::Receiver<Events>, mut tray_icon: TrayIcon<Events>) {
-  // Initialize the tray thread
-  println!("  + Running Tray Thread");
 
-  let task_scheduler = TaskScheduler::new().expect("Cannot construct task scheduler");
-
-  receiver.iter().for_each(|m| match m {
-    Events::LeftClickTrayIcon => {
-      tray_icon.show_menu().unwrap();
+  let layers = LayeredConfig::new(overrides)?;
+  match command {
+    Command::Run => run(layers, record),
+    Command::CheckConfig(path) => std::process::exit(check_config(&layers, path)?),
+    Command::PrintConfig => {
+      println!("{}", layers.report(&layers.resolve()?));
+      Ok(())
     }
-    Events::Startup => {
-      unsafe { CONFIG.toggle_startup() };
-
-      if unsafe { CONFIG.startup } {
-        let _ = task_scheduler.create_startup_task("PwccaAuto");
-      } else {
-        let _ = task_scheduler.delete_startup_task("PwccaAuto");
+    Command::Replay(path) => replay_recording(&layers, &path, speed, json),
+    Command::Cli(mut cli_args) => {
+      if json {
+        cli_args.push("--json".to_string());
       }
 
-      let _ = setup_tray_icon_menu(&mut tray_icon);
-    }
-    Events::Discord => {
-      unsafe { CONFIG.toggle_microphone() };
-      let _ = setup_tray_icon_menu(&mut tray_icon);
-    }
-    Events::Ethernet => {
-      unsafe { CONFIG.toggle_ethernet() };
-      let _ = setup_tray_icon_menu(&mut tray_icon);
-    }
-    Events::Taskbar => {
-      unsafe { CONFIG.toggle_taskbar() };
-      let _ = setup_tray_icon_menu(&mut tray_icon);
-    }
-    Events::TurnOffMonitor => turn_off_monitor(),
-    Events::RefreshRate => {
-      let refresh_rate = get_current_frequency();
-      let max_refresh_rate = get_all_frequencies().last().copied().unwrap();
-      set_new_frequency(if refresh_rate == 60 {
-        max_refresh_rate
+      // The running instance answers for itself, otherwise the command runs right here
+      let forwarded = if forwards(&layers) {
+        ipc::forward(&cli_args)
       } else {
-        60
-      });
-
-      let _ = setup_tray_icon_menu(&mut tray_icon);
+        Ok(None)
+      };
+      let output = match forwarded {
+        Ok(Some(output)) => output,
+        Ok(None) => run_cli(&cli_args, &layers),
+        // Running it here would change the files and the machine behind the instance's back
+        Err(e) if ipc::is_access_denied(&e) => {
+          return Err(e.context("The running instance does not take commands from this user"))
+        }
+        Err(e) => return Err(e),
+      };
+      output.print();
+      std::process::exit(output.code)
     }
-    Events::Exit => std::process::exit(0),
-  });
+  }
 }
 
-fn media_thread() -> Result<(), AudioDeviceError> {
-  // Initialize the media thread
-  println!("  + Running Media Thread");
-
-  init()?;
-
-  let mut connected = false;
-
-  loop {
-    if unsafe { CONFIG.microphone.enabled } {
-      let config_includes = unsafe { &CONFIG.microphone.apps };
-
-      let all_outputs = enumerate_audio_devices(&DeviceType::Output)?;
-
-      if all_outputs.len() > 1 {
-        let current_output = get_default_device(&DeviceType::Output)?;
+/// Whether a command goes to the running instance. `--set` and `--dry-run` only change the
+/// config of this launch, which the running instance knows nothing about, so the command then
+/// runs here instead.
+fn forwards(layers: &LayeredConfig) -> bool {
+  !layers.overrides.iter().any(|o| o.layer == Layer::Cli)
+}
 
-        let programs = get_active_audio_applications(&DeviceType::Input)?;
+/// Runs a command in this process, only recording the changes when the config asks for a dry
+/// run
+fn run_here(
+  args: &[String],
+  layers: &LayeredConfig,
+  backend: &DryRun<impl Backend>,
+) -> cli::Output {
+  backend.set_enabled(
+    layers
+      .resolve()
+      .is_ok_and(|resolved| resolved.config.dry_run),
+  );
+
+  let (mut output, planned) = backend.capture(|| {
+    dry_run::because(cli::reason(args), || {
+      cli::execute(args, layers, Some(backend), &cli::Offline(layers))
+    })
+  });
+  output.note_planned(&planned);
+  output
+}
 
-        if config_includes.iter().any(|e| programs.contains(e)) {
-          connected = true;
+#[cfg(windows)]
+fn run_cli(args: &[String], layers: &LayeredConfig) -> cli::Output {
+  run_here(args, layers, &DryRun::new(backend::win32::Win32Backend))
+}
 
-          if current_output.device_type == "Speakers" {
-            let headphones = all_outputs
-              .iter()
-              .find(|device| device.device_type == "Headphones")
-              .unwrap();
+#[cfg(not(windows))]
+fn run_cli(args: &[String], layers: &LayeredConfig) -> cli::Output {
+  cli::execute(args, layers, None, &cli::Offline(layers))
+}
 
-            change_default_output(headphones.device_id)?
-          }
-        } else if connected {
-          connected = false;
+#[cfg(windows)]
+fn run(layers: LayeredConfig, record: Option<PathBuf>) -> Result<()> {
+  app::run(layers, record)
+}
 
-          if current_output.device_type == "Headphones" {
-            let headphones = all_outputs
-              .iter()
-              .find(|device| device.device_type == "Speakers")
-              .unwrap();
+#[cfg(not(windows))]
+fn run(_layers: LayeredConfig, _record: Option<PathBuf>) -> Result<()> {
+  Err(anyhow::Error::msg("PwccaAuto only runs on Windows"))
+}
 
-            change_default_output(headphones.device_id)?
-          }
-        }
-      }
+/// Prints what the rules of the current config do over a recording, against a simulated
+/// machine, so it works anywhere
+fn replay_recording(
+  layers: &LayeredConfig,
+  path: &Path,
+  speed: Option<f64>,
+  json: bool,
+) -> Result<()> {
+  let config = layers.resolve()?.config;
+  let snapshots = replay::load(path)?;
+  let backend = backend::fake::FakeBackend::new();
+  let config = rules::schemes::with_schemes(&config, &backend.power_schemes()?);
+  let timeline = replay::replay(&snapshots, &config, &backend, speed);
+
+  if json {
+    println!("{}", serde_json::to_string_pretty(&timeline)?);
+  } else if timeline.is_empty() {
+    println!("No rule fired over {} snapshot(s)", snapshots.len());
+  } else {
+    for entry in &timeline {
+      println!("{}", entry);
     }
-
-    std::thread::sleep(Duration::from_secs(1));
   }
-}
-
-fn connection_thread() -> Result<()> {
-  // Initialize the connection thread
-  println!("  + Running Connection Thread");
 
-  loop {
-    if unsafe { CONFIG.ethernet } {
-      let _ = set_wifi_state(!is_ethernet_plugged_in());
-    }
+  Ok(())
+}
 
-    std::thread::sleep(Duration::from_secs(1));
+/// Prints every problem in the config files, exiting with 1 if any of them is an error
+fn check_config(layers: &LayeredConfig, path: Option<PathBuf>) -> Result<i32> {
+  let checked = match path {
+    Some(path) => vec![(path.clone(), validate::check_file(&path)?)],
+    None => layers.check_files()?,
+  };
+  if checked.is_empty() {
+    println!("No config file found");
   }
-}
 
-fn power_thread() -> Result<(), WIN32_ERROR> {
-  // Initialize the power thread
-  println!("  + Running Power Thread");
-
-  let mut on_battery_secs = 0;
-  let all_power_schemes = get_all_power_schemes()?;
-  let power = unsafe { CONFIG.power };
-
-  let powersaver = all_power_schemes
-    .iter()
-    .find(|scheme| scheme.name == "POWERSAVER")
-    .unwrap();
-  let ultra = all_power_schemes
-    .iter()
-    .find(|scheme| scheme.name == "Ultra")
-    .unwrap();
-
-  loop {
-    if power.enabled {
-      let is_plugged_in = get_power_status().is_plugged_in;
-
-      if power.timer != 0 && on_battery_secs > power.timer {
-        set_active_power_scheme(&powersaver.guid)?;
-      }
+  let mut failed = false;
+  for (path, diagnostics) in checked {
+    println!("{}", validate::report(&path, &diagnostics));
+    failed |= diagnostics.iter().any(|d| d.is_error());
+  }
 
-      if power.percentage != 0
-        && !is_plugged_in
-        && get_power_status().remaining_percentage < power.percentage
-      {
-        set_active_power_scheme(&powersaver.guid)?;
-      }
+  Ok(if failed { 1 } else { 0 })
+}
 
-      if is_plugged_in && get_active_power_scheme()?.guid == powersaver.guid {
-        set_active_power_scheme(&ultra.guid)?;
-      }
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    backend::fake::{FakeBackend, FakeCall},
+    testing::temp_dir,
+  };
 
-      if !is_plugged_in {
-        on_battery_secs += 1;
-      } else {
-        on_battery_secs = 0;
-      }
+  fn layers(name: &str, overrides: &[&str]) -> LayeredConfig {
+    let dir = temp_dir(name);
+    LayeredConfig {
+      machine: dir.join("machine.json"),
+      user: dir.join("user.json"),
+      overrides: overrides
+        .iter()
+        .map(|o| Override::parse(Layer::Cli, o).unwrap())
+        .collect(),
     }
+  }
 
-    std::thread::sleep(Duration::from_secs(1));
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
   }
-}
 
-fn taskbar_thread() {
-  // Initialize the taskbar thread
-  println!("  + Running Taskbar Thread");
+  #[test]
+  fn only_plain_commands_are_forwarded() {
+    assert!(forwards(&layers("forward-plain", &[])));
+    assert!(!forwards(&layers("forward-dry-run", &["dry_run=true"])));
+    assert!(!forwards(&layers("forward-set", &["power.timer=60"])));
+
+    let mut from_env = layers("forward-env", &[]);
+    from_env.overrides =
+      layers::env_overrides([("PWCCA_POWER__TIMER".to_string(), "60".to_string())].into_iter())
+        .unwrap();
+    assert!(forwards(&from_env));
+  }
 
-  loop {
-    if unsafe { CONFIG.taskbar.enabled } {
-      taskbar_automation();
-    }
+  #[test]
+  fn a_dry_run_here_changes_nothing() {
+    let layers = layers("run-here-dry-run", &["dry_run=true"]);
+    let backend = DryRun::new(FakeBackend::new());
+
+    let output = run_here(&args(&["power", "set", "Balanced"]), &layers, &backend);
+    assert_eq!(output.code, cli::EXIT_OK, "{}", output.stderr);
+    assert_eq!(backend.inner().take_calls(), []);
+    assert!(output.stderr.contains("Balanced"), "{}", output.stderr);
+  }
 
-    std::thread::sleep(Duration::from_secs(1));
+  #[test]
+  fn a_command_here_changes_the_machine() {
+    let layers = layers("run-here", &[]);
+    let backend = DryRun::new(FakeBackend::new());
+
+    let output = run_here(&args(&["power", "set", "Balanced"]), &layers, &backend);
+    assert_eq!(output.code, cli::EXIT_OK, "{}", output.stderr);
+    assert_eq!(
+      backend.inner().take_calls(),
+      [FakeCall::SetActivePowerScheme(
+        "381B4222-F694-41F0-9685-FF5BB260DF2E".to_string()
+      )]
+    );
   }
 }
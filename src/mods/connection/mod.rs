@@ -13,8 +13,8 @@ use windows::Win32::{
     WiFi::{
       dot11_radio_state_off, dot11_radio_state_on, wlan_intf_opcode_radio_state, WlanCloseHandle,
       WlanEnumInterfaces, WlanFreeMemory, WlanGetAvailableNetworkList, WlanGetNetworkBssList,
-      WlanOpenHandle, WlanSetInterface, WLAN_AVAILABLE_NETWORK, WLAN_BSS_ENTRY,
-      WLAN_INTERFACE_INFO, WLAN_PHY_RADIO_STATE,
+      WlanOpenHandle, WlanQueryInterface, WlanSetInterface, WLAN_AVAILABLE_NETWORK, WLAN_BSS_ENTRY,
+      WLAN_INTERFACE_INFO, WLAN_PHY_RADIO_STATE, WLAN_RADIO_STATE,
     },
   },
 };
@@ -221,3 +221,43 @@ pub fn set_wifi_state(on: bool) -> Result<(), WlanHandlerError> {
   unsafe { WlanCloseHandle(handle, None) };
   Ok(())
 }
+
+/// Whether any wireless interface has its radio switched on
+pub fn get_wifi_state() -> Result<bool, WlanHandlerError> {
+  let handle = open_handle()?;
+  let enum_interfaces = enum_interfaces(&handle)?;
+
+  let mut is_on = false;
+  for interface in enum_interfaces {
+    let mut data_size = 0;
+    let mut data = std::ptr::null_mut();
+
+    unsafe {
+      let result = WIN32_ERROR(WlanQueryInterface(
+        handle,
+        std::ptr::addr_of!(interface.InterfaceGuid),
+        wlan_intf_opcode_radio_state,
+        None,
+        &mut data_size,
+        &mut data,
+        None,
+      ));
+      if result != ERROR_SUCCESS {
+        continue;
+      }
+
+      let radio_state = &*(data as *const WLAN_RADIO_STATE);
+      is_on |= radio_state.PhyRadioState[..radio_state.dwNumberOfPhys as usize]
+        .iter()
+        .any(|phy| {
+          phy.dot11SoftwareRadioState == dot11_radio_state_on
+            && phy.dot11HardwareRadioState == dot11_radio_state_on
+        });
+
+      WlanFreeMemory(data);
+    }
+  }
+
+  unsafe { WlanCloseHandle(handle, None) };
+  Ok(is_on)
+}
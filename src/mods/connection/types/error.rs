@@ -52,3 +52,11 @@ impl WlanHandlerError {
     }
   }
 }
+
+impl std::fmt::Display for WlanHandlerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}: {}", self.kind, self.error)
+  }
+}
+
+impl std::error::Error for WlanHandlerError {}
@@ -3,7 +3,7 @@
 mod policy_config;
 pub mod types;
 
-use std::{path::Path, str::FromStr};
+use std::{cell::Cell, path::Path, str::FromStr};
 
 use types::{
   device::{Device, DeviceType},
@@ -20,35 +20,44 @@ use windows::{
       DEVICE_STATE_ACTIVE,
     },
     System::{
-      Com::{CoCreateInstance, CoInitialize, CLSCTX_ALL, STGM_READ},
+      Com::{CoCreateInstance, CoInitialize, CoUninitialize, CLSCTX_ALL, STGM_READ},
       ProcessStatus::GetProcessImageFileNameW,
       Threading::{OpenProcess, PROCESS_ALL_ACCESS},
     },
   },
 };
 
-static mut IS_INITIALIZED: bool = false;
+thread_local! {
+  // COM has to be initialized once on every thread that talks to the audio endpoints
+  static IS_INITIALIZED: Cell<bool> = const { Cell::new(false) };
+}
 
 pub fn init() -> Result<(), AudioDeviceError> {
-  unsafe {
-    if IS_INITIALIZED {
-      return Ok(());
-    }
+  if IS_INITIALIZED.get() {
+    return Ok(());
+  }
 
-    let res = CoInitialize(None);
-    if res.is_err() {
-      return Err(AudioDeviceError::new(
-        ErrorEnum::InitializationFailed,
-        res.into(),
-      ));
-    }
-    IS_INITIALIZED = true;
-    Ok(())
+  let res = unsafe { CoInitialize(None) };
+  if res.is_err() {
+    return Err(AudioDeviceError::new(
+      ErrorEnum::InitializationFailed,
+      res.into(),
+    ));
+  }
+  IS_INITIALIZED.set(true);
+  Ok(())
+}
+
+/// Undoes `init` on the current thread, releasing COM once a worker is done with audio
+pub fn uninit() {
+  if IS_INITIALIZED.get() {
+    unsafe { CoUninitialize() };
+    IS_INITIALIZED.set(false);
   }
 }
 
 fn init_check() -> Result<(), AudioDeviceError> {
-  if !unsafe { IS_INITIALIZED } {
+  if !IS_INITIALIZED.get() {
     return Err(AudioDeviceError::new_with_message(
       ErrorEnum::NotInitialized,
       "Audio device not initialized.".to_string(),
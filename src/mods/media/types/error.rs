@@ -40,3 +40,11 @@ impl AudioDeviceError {
     Self { kind, error }
   }
 }
+
+impl std::fmt::Display for AudioDeviceError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}: {}", self.kind, self.error)
+  }
+}
+
+impl std::error::Error for AudioDeviceError {}
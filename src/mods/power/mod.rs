@@ -1,4 +1,4 @@
-mod types;
+pub mod types;
 
 use std::{ffi::OsString, os::windows::ffi::OsStringExt};
 
@@ -15,19 +15,45 @@ use windows::{
 
 use types::{PowerScheme, SystemPowerStatus};
 
+// Values of SYSTEM_POWER_STATUS, 255 and u32::MAX standing for unknown
+const AC_LINE_OFFLINE: u8 = 0;
+const AC_LINE_ONLINE: u8 = 1;
+const BATTERY_FLAG_CHARGING: u8 = 8;
+const BATTERY_FLAG_NO_BATTERY: u8 = 128;
+const BATTERY_FLAG_UNKNOWN: u8 = 255;
+const BATTERY_PERCENT_UNKNOWN: u8 = 255;
+const BATTERY_TIME_UNKNOWN: u32 = u32::MAX;
+
 #[allow(dead_code)]
-pub fn get_power_status() -> SystemPowerStatus {
-  unsafe {
-    let mut system_power_status = SYSTEM_POWER_STATUS::default();
-    GetSystemPowerStatus(&mut system_power_status).unwrap();
-
-    SystemPowerStatus {
-      is_plugged_in: system_power_status.ACLineStatus == 1,
-      is_battery_saver_enabled: system_power_status.SystemStatusFlag == 1,
-      remaining_percentage: system_power_status.BatteryLifePercent as u32,
-      remaining_time: system_power_status.BatteryLifeTime,
+pub fn get_power_status() -> windows::core::Result<SystemPowerStatus> {
+  let mut system_power_status = SYSTEM_POWER_STATUS::default();
+  unsafe { GetSystemPowerStatus(&mut system_power_status)? };
+
+  let flag = system_power_status.BatteryFlag;
+  let has_battery = match flag {
+    BATTERY_FLAG_UNKNOWN => None,
+    flag => Some(flag & BATTERY_FLAG_NO_BATTERY == 0),
+  };
+
+  Ok(SystemPowerStatus {
+    is_plugged_in: match system_power_status.ACLineStatus {
+      AC_LINE_ONLINE => Some(true),
+      AC_LINE_OFFLINE => Some(false),
+      _ => None,
+    },
+    has_battery,
+    is_charging: has_battery == Some(true) && flag & BATTERY_FLAG_CHARGING != 0,
+    is_battery_saver_enabled: system_power_status.SystemStatusFlag == 1,
+    remaining_percentage: match system_power_status.BatteryLifePercent {
+      BATTERY_PERCENT_UNKNOWN => None,
+      percent => Some(percent.min(100) as u32),
     }
-  }
+    .filter(|_| has_battery == Some(true)),
+    remaining_time: match system_power_status.BatteryLifeTime {
+      BATTERY_TIME_UNKNOWN => None,
+      time => Some(time),
+    },
+  })
 }
 
 #[allow(dead_code)]
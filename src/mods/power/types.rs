@@ -3,10 +3,15 @@ use windows::core::GUID;
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct SystemPowerStatus {
-  pub is_plugged_in: bool,
+  /// `None` when Windows does not know the AC line status
+  pub is_plugged_in: Option<bool>,
+  /// `None` when Windows cannot read the battery
+  pub has_battery: Option<bool>,
+  pub is_charging: bool,
   pub is_battery_saver_enabled: bool,
-  pub remaining_percentage: u32,
-  pub remaining_time: u32,
+  pub remaining_percentage: Option<u32>,
+  /// Seconds
+  pub remaining_time: Option<u32>,
 }
 
 #[allow(dead_code)]
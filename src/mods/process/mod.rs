@@ -2,13 +2,27 @@
 
 use anyhow::Result;
 use windows::Win32::{
-  Foundation::{CloseHandle, HMODULE, MAX_PATH},
+  Foundation::{CloseHandle, FILETIME, HMODULE, MAX_PATH},
   System::{
     ProcessStatus::{EnumProcessModulesEx, EnumProcesses, GetModuleBaseNameW, LIST_MODULES_ALL},
-    Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ},
+    Threading::{GetSystemTimes, OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ},
   },
 };
 
+/// Idle and total processor time since boot across every core, in 100 ns units
+pub fn get_system_times() -> Result<(u64, u64)> {
+  let (mut idle, mut kernel, mut user) = (
+    FILETIME::default(),
+    FILETIME::default(),
+    FILETIME::default(),
+  );
+  unsafe { GetSystemTimes(Some(&mut idle), Some(&mut kernel), Some(&mut user))? };
+
+  let ticks = |time: FILETIME| (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+  // Kernel time includes the idle time
+  Ok((ticks(idle), ticks(kernel) + ticks(user)))
+}
+
 pub fn get_processes_exec_name() -> Result<Vec<String>> {
   Ok(
     get_processes()?
@@ -4,7 +4,7 @@ pub mod types;
 
 use std::fs;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use types::{
   regkey::RegKey,
   startup_status::{StartupGroup, StartupItem, StartupKind, StartupState},
@@ -133,8 +133,8 @@ fn get_startup_item_state(
         .iter()
         .map(|e| e.name.clone())
         .position(|e| e == value);
-      if contain.is_some() {
-        let item = &items[contain.unwrap()];
+      if let Some(index) = contain {
+        let item = &items[index];
 
         result.push(StartupState {
           kind: item.kind,
@@ -159,7 +159,7 @@ pub fn set_startup_item_state(item: &StartupState, status: bool) -> Result<()> {
 
   let key = RegKey::open(root, PCWSTR(HSTRING::from(&item.state_path).as_ptr()))?;
 
-  key.set_value_data(&item.name, status);
-
-  Ok(())
+  key
+    .set_value_data(&item.name, status)
+    .with_context(|| format!("Cannot set the startup state of {}", item.name))
 }
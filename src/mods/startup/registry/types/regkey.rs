@@ -12,6 +12,8 @@ use windows::{
   },
 };
 
+use crate::log::error;
+
 #[derive(Debug)]
 pub struct RegKey {
   hkey: HKEY,
@@ -96,14 +98,16 @@ impl RegKey {
     values
   }
 
-  pub fn set_value_data(&self, name: &str, value: bool) {
+  pub fn set_value_data(&self, name: &str, value: bool) -> Result<()> {
     let value = if value { [2] } else { [3] };
 
     unsafe {
       let result = RegSetValueExW(self.hkey, &HSTRING::from(name), 0, REG_BINARY, Some(&value));
 
-      if result != ERROR_SUCCESS {
-        println!("Error setting value: {}", result.to_hresult().message());
+      if result == ERROR_SUCCESS {
+        Ok(())
+      } else {
+        Err(anyhow::Error::msg(result.to_hresult().message()))
       }
     }
   }
@@ -124,7 +128,7 @@ impl RegKey {
       );
 
       if result != ERROR_SUCCESS {
-        println!("Error getting value: {}", result.to_hresult().message());
+        error!("Error getting value: {}", result.to_hresult().message());
       }
     }
 
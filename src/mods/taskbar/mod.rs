@@ -6,7 +6,7 @@ use std::{path::Path, str::FromStr};
 
 use types::TaskbarSize;
 use windows::Win32::{
-  Foundation::{CloseHandle, BOOL, FALSE, HWND, LPARAM, MAX_PATH, TRUE},
+  Foundation::{CloseHandle, BOOL, HWND, LPARAM, MAX_PATH, TRUE},
   System::{
     ProcessStatus::GetProcessImageFileNameW,
     Threading::{OpenProcess, PROCESS_ALL_ACCESS},
@@ -16,25 +16,22 @@ use windows::Win32::{
       SHAppBarMessage, ABM_GETSTATE, ABM_GETTASKBARPOS, ABM_SETSTATE, ABS_ALWAYSONTOP,
       ABS_AUTOHIDE, APPBARDATA,
     },
-    WindowsAndMessaging::{EnumWindows, GetWindowThreadProcessId, IsWindowVisible, IsZoomed},
+    WindowsAndMessaging::{
+      EnumWindows, GetForegroundWindow, GetWindowThreadProcessId, IsWindowVisible, IsZoomed,
+    },
   },
 };
 
-use crate::CONFIG;
-
-static mut PROGRAMS: Vec<HWND> = Vec::new();
-
-unsafe extern "system" fn enum_window(handle: HWND, _lparam: LPARAM) -> BOOL {
-  PROGRAMS = Vec::new();
+unsafe extern "system" fn enum_window(handle: HWND, lparam: LPARAM) -> BOOL {
+  let programs = &mut *(lparam.0 as *mut Vec<String>);
 
-  if IsWindowVisible(handle) == TRUE && IsZoomed(handle) == TRUE && !PROGRAMS.contains(&handle) {
+  if IsWindowVisible(handle) == TRUE && IsZoomed(handle) == TRUE {
     let mut process_id = 0;
     unsafe { GetWindowThreadProcessId(handle, Some(&mut process_id)) };
 
     let process_name = get_process_name(process_id);
-    if CONFIG.taskbar.apps.contains(&process_name) {
-      PROGRAMS.push(handle);
-      return FALSE;
+    if !process_name.is_empty() && !programs.contains(&process_name) {
+      programs.push(process_name);
     }
   }
 
@@ -71,12 +68,42 @@ fn get_process_name(process_id: u32) -> String {
   String::new()
 }
 
-pub fn taskbar_automation() {
-  let _ = unsafe { EnumWindows(Some(enum_window), LPARAM(0)) };
-  hide_taskbar(unsafe { PROGRAMS.is_empty() });
+pub fn get_maximized_window_processes() -> Vec<String> {
+  let mut programs = Vec::<String>::new();
+  let _ = unsafe {
+    EnumWindows(
+      Some(enum_window),
+      LPARAM(std::ptr::addr_of_mut!(programs) as isize),
+    )
+  };
+
+  programs
+}
+
+pub fn get_foreground_window_process() -> Option<String> {
+  let handle = unsafe { GetForegroundWindow() };
+  if handle.is_invalid() {
+    return None;
+  }
+
+  let mut process_id = 0;
+  unsafe { GetWindowThreadProcessId(handle, Some(&mut process_id)) };
+
+  let process_name = get_process_name(process_id);
+  (!process_name.is_empty()).then_some(process_name)
+}
+
+pub fn is_taskbar_autohide() -> bool {
+  let mut pdata = APPBARDATA {
+    cbSize: std::mem::size_of::<APPBARDATA>() as u32,
+    ..Default::default()
+  };
+  let state = unsafe { SHAppBarMessage(ABM_GETSTATE, &mut pdata) };
+
+  state as u32 & ABS_AUTOHIDE != 0
 }
 
-fn hide_taskbar(hide: bool) {
+pub fn hide_taskbar(hide: bool) {
   let mut pdata = APPBARDATA {
     cbSize: std::mem::size_of::<APPBARDATA>() as u32,
     ..Default::default()
@@ -0,0 +1,261 @@
+use std::{
+  fmt,
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, Write},
+  path::Path,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  backend::Backend,
+  bus::state::MachineState,
+  config::Config,
+  rules::{types::Action, RuleEngine},
+};
+
+/// The machine as the sampler saw it at `at_ms`, milliseconds since the Unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+  pub at_ms: u64,
+  pub state: MachineState,
+}
+
+/// Appends a snapshot to a recording, one JSON line each, every time the machine changes
+pub struct Recorder {
+  file: File,
+  last: Option<MachineState>,
+}
+
+impl Recorder {
+  pub fn open(path: &Path) -> Result<Self> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .with_context(|| format!("Cannot open {}", path.display()))?;
+    Ok(Self { file, last: None })
+  }
+
+  pub fn record(&mut self, state: &MachineState) -> Result<()> {
+    if self.last.as_ref() == Some(state) {
+      return Ok(());
+    }
+
+    let at_ms = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis() as u64;
+    let snapshot = Snapshot {
+      at_ms,
+      state: state.clone(),
+    };
+    writeln!(self.file, "{}", serde_json::to_string(&snapshot)?)?;
+
+    self.last = Some(state.clone());
+    Ok(())
+  }
+}
+
+/// Reads a recording, which has to be in order
+pub fn load(path: &Path) -> Result<Vec<Snapshot>> {
+  let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+
+  let mut snapshots: Vec<Snapshot> = Vec::new();
+  for (i, line) in BufReader::new(file).lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let snapshot: Snapshot =
+      serde_json::from_str(&line).with_context(|| format!("{} line {}", path.display(), i + 1))?;
+    if snapshots
+      .last()
+      .is_some_and(|last| last.at_ms > snapshot.at_ms)
+    {
+      return Err(anyhow!(
+        "{} line {}: snapshot is older than the one before it",
+        path.display(),
+        i + 1
+      ));
+    }
+    snapshots.push(snapshot);
+  }
+
+  Ok(snapshots)
+}
+
+/// A rule firing or exiting, `at` after the first snapshot
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+  #[serde(rename = "at_secs", serialize_with = "as_secs")]
+  pub at: Duration,
+  pub rule: String,
+  pub entered: bool,
+  pub actions: Vec<Action>,
+  pub errors: Vec<String>,
+}
+
+fn as_secs<S: serde::Serializer>(at: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_f64(at.as_secs_f64())
+}
+
+impl fmt::Display for TimelineEntry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let secs = self.at.as_secs();
+    write!(
+      f,
+      "+{:02}:{:02}:{:02} {} {}: {:?}",
+      secs / 3600,
+      secs % 3600 / 60,
+      secs % 60,
+      self.rule,
+      if self.entered { "fired" } else { "exited" },
+      self.actions
+    )?;
+    for error in &self.errors {
+      write!(f, "\n  {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+/// Runs the rules and battery tiers of `config` over a recording and returns what they did, in order.
+///
+/// Time only exists in the recording: the engine is woken at every snapshot and at every
+/// battery timer in between, so a day replays in moments. With `speed`, the replay waits
+/// `speed` times less than the recording took instead.
+pub fn replay(
+  snapshots: &[Snapshot],
+  config: &Config,
+  backend: &impl Backend,
+  speed: Option<f64>,
+) -> Vec<TimelineEntry> {
+  let Some(first) = snapshots.first() else {
+    return Vec::new();
+  };
+
+  let mut engine = RuleEngine::new();
+  engine.configure(config);
+
+  let mut timeline = Vec::new();
+  let mut now = Duration::ZERO;
+  let mut tick = |engine: &mut RuleEngine, state: &MachineState, at: Duration| {
+    if let Some(speed) = speed.filter(|speed| *speed > 0.0) {
+      std::thread::sleep(at.saturating_sub(now).div_f64(speed));
+    }
+    now = at;
+
+    for firing in engine.tick(state, backend, at) {
+      timeline.push(TimelineEntry {
+        at,
+        rule: firing.rule,
+        entered: firing.entered,
+        actions: firing.actions,
+        errors: firing.errors,
+      });
+    }
+  };
+
+  let mut previous: Option<(&MachineState, Duration)> = None;
+  for snapshot in snapshots {
+    let at = Duration::from_millis(snapshot.at_ms - first.at_ms);
+
+    // Timers that run out before the machine changes again
+    if let Some((state, mut last)) = previous {
+      while let Some(deadline) = engine.next_deadline(last).map(|wait| last + wait) {
+        if deadline >= at {
+          break;
+        }
+        tick(&mut engine, state, deadline);
+        last = deadline;
+      }
+    }
+
+    tick(&mut engine, &snapshot.state, at);
+    previous = Some((&snapshot.state, at));
+  }
+
+  timeline
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+  use crate::{
+    backend::{fake::FakeBackend, PowerBackend},
+    rules::schemes,
+  };
+
+  /// A recording of a commute: unplugged for a while, the battery running low, plugged in
+  /// for a few minutes and unplugged again
+  fn commute_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/replay/testdata/commute.jsonl")
+  }
+
+  fn commute() -> Vec<Snapshot> {
+    load(&commute_path()).unwrap()
+  }
+
+  fn power_config(backend: &FakeBackend) -> Config {
+    let mut config = Config::new();
+    config.power.enabled = true;
+    config.power.timer = 300;
+    config.power.percentage = 40;
+    schemes::with_schemes(&config, &backend.power_schemes().unwrap())
+  }
+
+  fn timeline(backend: &FakeBackend) -> Vec<(u64, String, bool)> {
+    replay(&commute(), &power_config(backend), backend, None)
+      .into_iter()
+      .map(|entry| (entry.at.as_secs(), entry.rule, entry.entered))
+      .collect()
+  }
+
+  #[test]
+  fn replays_the_firing_timeline() {
+    let fired = |secs: u64, rule: &str| (secs, rule.to_string(), true);
+    let exited = |secs: u64, rule: &str| (secs, rule.to_string(), false);
+
+    assert_eq!(
+      timeline(&FakeBackend::new()),
+      [
+        fired(60, "Heavy startup apps off on battery"),
+        // Between two snapshots, five minutes after unplugging
+        fired(361, "Power saver after a while on battery"),
+        fired(900, "Power saver on low battery"),
+        fired(1200, "Performance when plugged in"),
+        exited(1200, "Heavy startup apps off on battery"),
+        fired(1500, "Heavy startup apps off on battery"),
+        fired(1700, "Power saver on low battery"),
+        // Plugging in started the timer over
+        fired(1801, "Power saver after a while on battery"),
+      ]
+    );
+  }
+
+  #[test]
+  fn out_of_order_snapshots_are_refused() {
+    let dir = crate::testing::temp_dir("replay-order");
+    let path = dir.join("recording.jsonl");
+    let mut lines = std::fs::read_to_string(commute_path())
+      .unwrap()
+      .lines()
+      .map(str::to_string)
+      .collect::<Vec<_>>();
+    lines.swap(1, 2);
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let error = load(&path).unwrap_err().to_string();
+    assert!(
+      error.ends_with("line 3: snapshot is older than the one before it"),
+      "{}",
+      error
+    );
+  }
+}
@@ -0,0 +1,370 @@
+use super::types::{Action, Condition, Rule};
+use crate::{backend::types::DeviceType, config::Config};
+
+pub const DISALLOWED_STARTUP_ITEMS: [&str; 4] =
+  ["Discord", "WallpaperEngine", "Overwolf", "Joplin.lnk"];
+
+/// What holds the power scheme where it is instead of saving power, if anything
+pub fn power_exemption(config: &Config) -> Option<Condition> {
+  let power = &config.power;
+
+  let mut exemptions = Vec::new();
+  if !power.exempt_apps.is_empty() {
+    exemptions.push(Condition::ProcessRunning(power.exempt_apps.clone()));
+  }
+  if power.cpu_threshold != 0 {
+    exemptions.push(Condition::CpuLoadAbove(power.cpu_threshold));
+  }
+  (!exemptions.is_empty()).then_some(Condition::Any(exemptions))
+}
+
+/// The built-in automations as rules, switched on and off by the config toggles
+pub fn default_rules(config: &Config) -> Vec<Rule> {
+  let power = &config.power;
+  // Power saving waits for the exemption to clear
+  let saving_conditions: Vec<Condition> = power_exemption(config)
+    .map(|exemption| Condition::Not(Box::new(exemption)))
+    .into_iter()
+    .collect();
+
+  vec![
+    Rule {
+      name: "Power saver after a while on battery".to_string(),
+      enabled: power.enabled && power.timer != 0,
+      trigger: Condition::OnBatteryFor(power.timer),
+      conditions: saving_conditions.clone(),
+      actions: vec![Action::SetPowerScheme(power.battery_scheme.clone())],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Power saver on low battery".to_string(),
+      // Battery tiers take over from the single threshold, with hysteresis
+      enabled: power.enabled && power.percentage != 0 && power.tiers.is_empty(),
+      trigger: Condition::All(vec![
+        Condition::OnBattery,
+        Condition::BatteryBelow(power.percentage),
+      ]),
+      conditions: saving_conditions,
+      actions: vec![Action::SetPowerScheme(power.battery_scheme.clone())],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Performance when plugged in".to_string(),
+      enabled: power.enabled,
+      trigger: Condition::PluggedIn,
+      conditions: vec![Condition::PowerSchemeIs(power.battery_scheme.clone())],
+      actions: vec![Action::SetPowerScheme(power.ac_scheme.clone())],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Headphones while using the microphone".to_string(),
+      enabled: config.microphone.enabled,
+      trigger: Condition::AudioSessionActive {
+        device: DeviceType::Input,
+        apps: config.microphone.apps.clone(),
+      },
+      // Engages whatever the output was, so the speakers always come back after the call
+      actions: vec![Action::SetDefaultOutput("Headphones".to_string())],
+      exit_actions: vec![Action::SetDefaultOutput("Speakers".to_string())],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Wi-Fi off on ethernet".to_string(),
+      enabled: config.ethernet,
+      trigger: Condition::EthernetConnected,
+      actions: vec![Action::SetWifi(false)],
+      exit_actions: vec![Action::SetWifi(true)],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Heavy startup apps off on battery".to_string(),
+      enabled: true,
+      trigger: Condition::OnBattery,
+      actions: vec![Action::SetStartupItems {
+        items: DISALLOWED_STARTUP_ITEMS.map(String::from).to_vec(),
+        enabled: false,
+      }],
+      exit_actions: vec![Action::SetStartupItems {
+        items: DISALLOWED_STARTUP_ITEMS.map(String::from).to_vec(),
+        enabled: true,
+      }],
+      ..Rule::default()
+    },
+    Rule {
+      name: "Taskbar hidden unless an app is maximized".to_string(),
+      enabled: config.taskbar.enabled,
+      trigger: Condition::Not(Box::new(Condition::WindowMaximized(
+        config.taskbar.apps.clone(),
+      ))),
+      actions: vec![Action::SetTaskbarAutohide(true)],
+      exit_actions: vec![Action::SetTaskbarAutohide(false)],
+      ..Rule::default()
+    },
+  ]
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::{
+    backend::{
+      fake::{FakeBackend, FakeCall},
+      types::{AudioDevice, StartupItem},
+      PowerBackend,
+    },
+    bus::state::MachineState,
+    rules::{schemes, tiers::BatteryTier, RuleEngine},
+  };
+
+  const POWER_SAVER: &str = "A1841308-3541-4FAB-BC81-F71556F20B4A";
+  const BALANCED: &str = "381B4222-F694-41F0-9685-FF5BB260DF2E";
+
+  fn engine(config: &Config, backend: &FakeBackend) -> RuleEngine {
+    let mut engine = RuleEngine::new();
+    engine.configure(&schemes::with_schemes(
+      config,
+      &backend.power_schemes().unwrap(),
+    ));
+    engine
+  }
+
+  fn on_battery(percentage: u32) -> MachineState {
+    MachineState {
+      plugged_in: false,
+      battery_percentage: percentage,
+      ..MachineState::new()
+    }
+  }
+
+  fn power_config() -> Config {
+    let mut config = Config::new();
+    config.power.enabled = true;
+    config.power.timer = 300;
+    config.power.percentage = 40;
+    config
+  }
+
+  #[test]
+  fn power_saver_after_the_timer() {
+    let backend = FakeBackend::new();
+    let mut engine = engine(&power_config(), &backend);
+    let machine = on_battery(80);
+
+    engine.tick(&machine, &backend, Duration::ZERO);
+    engine.tick(&machine, &backend, Duration::from_secs(300));
+    assert_eq!(backend.take_calls(), []);
+
+    engine.tick(&machine, &backend, Duration::from_secs(301));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(POWER_SAVER.to_string())]
+    );
+
+    // Plugging in goes back to the AC scheme, Balanced being the fallback here
+    let plugged_in = MachineState {
+      power_scheme: backend.active_power_scheme().ok(),
+      ..MachineState::new()
+    };
+    engine.tick(&plugged_in, &backend, Duration::from_secs(302));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(BALANCED.to_string())]
+    );
+  }
+
+  #[test]
+  fn power_saver_on_low_battery() {
+    let backend = FakeBackend::new();
+    let mut engine = engine(&power_config(), &backend);
+
+    engine.tick(&on_battery(41), &backend, Duration::ZERO);
+    assert_eq!(backend.take_calls(), []);
+
+    engine.tick(&on_battery(39), &backend, Duration::from_secs(1));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(POWER_SAVER.to_string())]
+    );
+  }
+
+  #[test]
+  fn tiers_replace_the_low_battery_rule() {
+    let backend = FakeBackend::new();
+    let mut config = power_config();
+    config.power.tiers = vec![BatteryTier {
+      name: "Low".to_string(),
+      enter_at: 20,
+      exit_at: 30,
+      actions: vec![Action::SetPowerScheme(POWER_SAVER.to_string())],
+      ..BatteryTier::default()
+    }];
+    let mut engine = engine(&config, &backend);
+
+    // Below the old threshold but above the tier
+    engine.tick(&on_battery(30), &backend, Duration::ZERO);
+    assert_eq!(backend.take_calls(), []);
+
+    engine.tick(&on_battery(20), &backend, Duration::from_secs(1));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(POWER_SAVER.to_string())]
+    );
+  }
+
+  #[test]
+  fn power_saver_waits_for_exempt_apps() {
+    let backend = FakeBackend::new();
+    let mut config = power_config();
+    config.power.exempt_apps = vec!["Game.exe".to_string()];
+    let mut engine = engine(&config, &backend);
+
+    let gaming = MachineState {
+      processes: vec!["game".to_string()],
+      ..on_battery(80)
+    };
+    engine.tick(&gaming, &backend, Duration::ZERO);
+    engine.tick(&gaming, &backend, Duration::from_secs(400));
+    assert_eq!(backend.take_calls(), []);
+
+    engine.tick(&on_battery(80), &backend, Duration::from_secs(401));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetActivePowerScheme(POWER_SAVER.to_string())]
+    );
+  }
+
+  #[test]
+  fn power_off_changes_nothing() {
+    let backend = FakeBackend::new();
+    let mut engine = engine(&Config::new(), &backend);
+
+    engine.tick(&on_battery(10), &backend, Duration::ZERO);
+    engine.tick(&on_battery(10), &backend, Duration::from_secs(1000));
+    assert_eq!(backend.take_calls(), []);
+  }
+
+  fn in_call(output: &str) -> MachineState {
+    MachineState {
+      input_applications: vec!["discord".to_string()],
+      default_output: Some(AudioDevice {
+        id: output.to_lowercase(),
+        kind: output.to_string(),
+        name: output.to_string(),
+      }),
+      ..MachineState::new()
+    }
+  }
+
+  fn microphone_config() -> Config {
+    let mut config = Config::new();
+    config.microphone.enabled = true;
+    config.microphone.apps = vec!["discord.exe".to_string()];
+    config
+  }
+
+  #[test]
+  fn headphones_during_a_call() {
+    let backend = FakeBackend::new();
+    let mut engine = engine(&microphone_config(), &backend);
+
+    engine.tick(&MachineState::new(), &backend, Duration::ZERO);
+    engine.tick(&in_call("Speakers"), &backend, Duration::from_secs(1));
+    engine.tick(&MachineState::new(), &backend, Duration::from_secs(2));
+    assert_eq!(
+      backend.take_calls(),
+      [
+        FakeCall::SetDefaultOutput("headphones".to_string()),
+        FakeCall::SetDefaultOutput("speakers".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn speakers_come_back_after_a_call_started_on_headphones() {
+    let backend = FakeBackend::new();
+    let mut engine = engine(&microphone_config(), &backend);
+
+    engine.tick(&in_call("Headphones"), &backend, Duration::ZERO);
+    assert_eq!(engine.engaged(), ["Headphones while using the microphone"]);
+    backend.take_calls();
+
+    engine.tick(&MachineState::new(), &backend, Duration::from_secs(1));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetDefaultOutput("speakers".to_string())]
+    );
+  }
+
+  #[test]
+  fn wifi_follows_ethernet() {
+    let backend = FakeBackend::new();
+    let mut config = Config::new();
+    config.ethernet = true;
+    let mut engine = engine(&config, &backend);
+
+    let wired = MachineState {
+      ethernet: true,
+      ..MachineState::new()
+    };
+    engine.tick(&MachineState::new(), &backend, Duration::ZERO);
+    engine.tick(&wired, &backend, Duration::from_secs(1));
+    engine.tick(&MachineState::new(), &backend, Duration::from_secs(2));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetWifiState(false), FakeCall::SetWifiState(true)]
+    );
+  }
+
+  #[test]
+  fn heavy_startup_apps_off_on_battery() {
+    let backend = FakeBackend::new();
+    backend.update(|state| {
+      state.startup_items = vec![
+        StartupItem {
+          name: "Discord".to_string(),
+          enabled: true,
+        },
+        StartupItem {
+          name: "OneDrive".to_string(),
+          enabled: true,
+        },
+      ]
+    });
+    let mut engine = engine(&Config::new(), &backend);
+
+    engine.tick(&on_battery(90), &backend, Duration::ZERO);
+    engine.tick(&MachineState::new(), &backend, Duration::from_secs(1));
+    assert_eq!(
+      backend.take_calls(),
+      [
+        FakeCall::SetStartupItemState("Discord".to_string(), false),
+        FakeCall::SetStartupItemState("Discord".to_string(), true),
+      ]
+    );
+  }
+
+  #[test]
+  fn taskbar_shown_while_an_app_is_maximized() {
+    let backend = FakeBackend::new();
+    let mut config = Config::new();
+    config.taskbar.enabled = true;
+    config.taskbar.apps = vec!["code".to_string()];
+    let mut engine = engine(&config, &backend);
+
+    let maximized = MachineState {
+      maximized_windows: vec!["code".to_string()],
+      ..MachineState::new()
+    };
+    engine.tick(&MachineState::new(), &backend, Duration::ZERO);
+    engine.tick(&maximized, &backend, Duration::from_secs(1));
+    assert_eq!(
+      backend.take_calls(),
+      [
+        FakeCall::SetTaskbarAutohide(true),
+        FakeCall::SetTaskbarAutohide(false),
+      ]
+    );
+  }
+}
@@ -0,0 +1,709 @@
+pub mod defaults;
+pub mod runtime;
+pub mod schemes;
+pub mod sensors;
+pub mod tiers;
+pub mod types;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use runtime::RuntimeState;
+use sensors::Sensors;
+use tiers::BatteryTier;
+use types::{Action, Condition, Firing, Rule};
+
+use crate::{
+  backend::{dry_run, types::DeviceType, Backend},
+  bus::{sampler::Source, state::MachineState},
+  config::Config,
+  log::error,
+};
+
+/// The default rules built from the config toggles, followed by the rules from the config
+pub fn rules_for(config: &Config) -> Vec<Rule> {
+  let mut rules = defaults::default_rules(config);
+  rules.extend(config.rules.iter().cloned());
+  rules
+}
+
+/// The battery tiers `config` enables, from the highest threshold to the lowest
+pub fn tiers_for(config: &Config) -> Vec<BatteryTier> {
+  if config.power.enabled {
+    tiers::sorted(config.power.tiers.clone())
+  } else {
+    Vec::new()
+  }
+}
+
+/// Whether any rule `config` enables needs `source` to be sampled
+pub fn reads(config: &Config, source: Source) -> bool {
+  rules_for(config).iter().any(|rule| rule.reads(source))
+}
+
+pub fn run_action(backend: &impl Backend, action: &Action) -> Result<()> {
+  match action {
+    Action::SetPowerScheme(wanted) => {
+      let schemes = backend.power_schemes()?;
+      let scheme = schemes::find(&schemes, wanted)
+        .ok_or_else(|| anyhow!("Cannot find power scheme {}", wanted))?;
+
+      backend.set_active_power_scheme(scheme)
+    }
+    Action::SetDefaultOutput(wanted) => {
+      let outputs = backend.audio_devices(DeviceType::Output)?;
+      let device = outputs
+        .iter()
+        .find(|device| device.kind == *wanted || device.name == *wanted)
+        .ok_or_else(|| anyhow!("Cannot find {} output", wanted))?;
+
+      backend.set_default_output(device)
+    }
+    Action::SetWifi(on) => backend.set_wifi_state(*on),
+    Action::SetRefreshRate(frequency) => backend.set_refresh_rate(*frequency),
+    Action::TurnOffMonitor => backend.turn_off_monitor(),
+    Action::SetStartupItems { items, enabled } => {
+      for item in backend.startup_items()? {
+        if items.contains(&item.name) {
+          backend
+            .set_startup_item_state(&item.name, *enabled)
+            .map_err(|e| e.context(format!("Cannot change {} startup", item.name)))?;
+        }
+      }
+
+      Ok(())
+    }
+    Action::SetTaskbarAutohide(hide) => backend.set_taskbar_autohide(*hide),
+  }
+}
+
+struct RuleState {
+  rule: Rule,
+  engaged: bool,
+  last_error: Option<String>,
+}
+
+/// Evaluates every rule once per tick and runs the actions of the rules that changed state
+#[derive(Default)]
+pub struct RuleEngine {
+  rules: Vec<RuleState>,
+  on_battery_since: Option<Duration>,
+  /// Time spent on battery before a restart, restored from a `RuntimeState`
+  on_battery_before: Duration,
+  tiers: Vec<BatteryTier>,
+  /// Index in `tiers` of the tier the battery is in
+  tier: Option<usize>,
+  /// Keeps the battery from entering a lower tier while it holds
+  exemption: Option<Condition>,
+}
+
+impl RuleEngine {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Replaces the rules and battery tiers with those of `config`
+  pub fn configure(&mut self, config: &Config) {
+    self.set_rules(rules_for(config));
+    self.set_tiers(tiers_for(config));
+    self.exemption = defaults::power_exemption(config);
+  }
+
+  /// Replaces the battery tiers, which have to be sorted. The battery stays in the tier of the
+  /// same name, without any action.
+  pub fn set_tiers(&mut self, tiers: Vec<BatteryTier>) {
+    let current = self.battery_tier().map(String::from);
+    self.tier = current.and_then(|name| tiers.iter().position(|tier| tier.name == name));
+    self.tiers = tiers;
+  }
+
+  /// Name of the tier the battery is in
+  pub fn battery_tier(&self) -> Option<&str> {
+    self
+      .tier
+      .and_then(|index| self.tiers.get(index))
+      .map(|tier| tier.name.as_str())
+  }
+
+  /// Replaces the rules, keeping the state of every rule that did not change
+  pub fn set_rules(&mut self, rules: Vec<Rule>) {
+    let mut previous = std::mem::take(&mut self.rules);
+
+    self.rules = rules
+      .into_iter()
+      .map(
+        |rule| match previous.iter().position(|state| state.rule == rule) {
+          Some(index) => previous.swap_remove(index),
+          None => RuleState {
+            rule,
+            engaged: false,
+            last_error: None,
+          },
+        },
+      )
+      .collect();
+  }
+
+  /// Names of the rules that fired and have not exited yet
+  pub fn engaged(&self) -> Vec<&str> {
+    self
+      .rules
+      .iter()
+      .filter(|state| state.engaged)
+      .map(|state| state.rule.name.as_str())
+      .collect()
+  }
+
+  pub fn on_battery_since(&self) -> Option<Duration> {
+    self.on_battery_since
+  }
+
+  fn on_battery_for(&self, now: Duration) -> Option<Duration> {
+    self
+      .on_battery_since
+      .map(|since| now.saturating_sub(since) + self.on_battery_before)
+  }
+
+  /// What a later launch needs to carry on, `unix_now` being `now` as the time since the Unix
+  /// epoch
+  pub fn runtime_state(
+    &self,
+    now: Duration,
+    unix_now: Duration,
+    boot_time: Option<u64>,
+  ) -> RuntimeState {
+    RuntimeState {
+      saved_at: unix_now.as_secs(),
+      boot_time,
+      on_battery_since: self
+        .on_battery_for(now)
+        .map(|elapsed| unix_now.saturating_sub(elapsed).as_secs()),
+      engaged: self.engaged().into_iter().map(String::from).collect(),
+      battery_tier: self.battery_tier().map(String::from),
+      originals: Vec::new(),
+    }
+  }
+
+  /// Carries on from `state`, which has to be checked with `RuntimeState::stale_because` first.
+  /// Rules that are gone since are skipped, a plug-in is noticed on the next tick.
+  pub fn restore(&mut self, state: &RuntimeState, now: Duration, unix_now: u64) {
+    for rule in &mut self.rules {
+      if rule.rule.enabled && state.engaged.contains(&rule.rule.name) {
+        rule.engaged = true;
+      }
+    }
+
+    if let Some(name) = &state.battery_tier {
+      self.tier = self.tiers.iter().position(|tier| tier.name == *name);
+    }
+
+    if let Some(since) = state.on_battery_since {
+      self.on_battery_since = Some(now);
+      self.on_battery_before = Duration::from_secs(unix_now.saturating_sub(since));
+    }
+  }
+
+  /// Starts over after everything the rules changed was put back. Rules whose trigger still
+  /// holds fire again on the next tick, the battery timer keeps running.
+  pub fn reset(&mut self) {
+    for rule in &mut self.rules {
+      rule.engaged = false;
+    }
+    self.tier = None;
+  }
+
+  /// Every `on_battery_for` threshold used by an enabled rule
+  fn battery_timers(&self) -> Vec<u32> {
+    fn collect(condition: &Condition, timers: &mut Vec<u32>) {
+      match condition {
+        Condition::All(conditions) | Condition::Any(conditions) => {
+          conditions.iter().for_each(|c| collect(c, timers))
+        }
+        Condition::Not(condition) => collect(condition, timers),
+        Condition::OnBatteryFor(secs) => timers.push(*secs),
+        _ => {}
+      }
+    }
+
+    let mut timers = Vec::new();
+    for state in self.rules.iter().filter(|state| state.rule.enabled) {
+      collect(&state.rule.trigger, &mut timers);
+      state
+        .rule
+        .conditions
+        .iter()
+        .for_each(|c| collect(c, &mut timers));
+    }
+    timers
+  }
+
+  /// How long until a rule can change without any event, when a battery timer is running
+  pub fn next_deadline(&self, now: Duration) -> Option<Duration> {
+    let elapsed = self.on_battery_for(now)?;
+
+    self
+      .battery_timers()
+      .into_iter()
+      // Conditions compare with `>`, the rule fires on the tick after the exact second
+      .map(|secs| Duration::from_secs(secs as u64 + 1))
+      .filter(|timer| *timer > elapsed)
+      .map(|timer| timer - elapsed)
+      .min()
+  }
+
+  /// Evaluates every rule against `machine`. `now` is the time since an arbitrary fixed point,
+  /// only differences are used.
+  pub fn tick(
+    &mut self,
+    machine: &MachineState,
+    backend: &impl Backend,
+    now: Duration,
+  ) -> Vec<Firing> {
+    // An unknown power source neither starts nor stops the battery timer
+    let power_known = machine.power_known();
+    match (power_known, machine.plugged_in) {
+      (false, _) => {}
+      (true, true) => {
+        self.on_battery_since = None;
+        self.on_battery_before = Duration::ZERO;
+      }
+      (true, false) => {
+        self.on_battery_since.get_or_insert(now);
+      }
+    }
+    let sensors = Sensors::new(machine, self.on_battery_for(now));
+
+    let mut firings = Vec::new();
+    for state in &mut self.rules {
+      if !state.rule.enabled {
+        state.engaged = false;
+        continue;
+      }
+      // Rules about power stay as they are until the power status is known again
+      if !power_known && state.rule.reads(Source::Power) {
+        continue;
+      }
+
+      match step(&sensors, backend, state) {
+        Ok(Some(firing)) => firings.push(firing),
+        Ok(None) => {}
+        Err(e) => {
+          // Reported once, the same error would otherwise repeat on every event
+          let error = format!("{:#}", e);
+          if state.last_error.as_ref() != Some(&error) {
+            error!("Rule {} failed: {}", state.rule.name, error);
+            state.last_error = Some(error);
+          }
+        }
+      }
+    }
+
+    if power_known {
+      self.step_tier(&sensors, machine, backend, &mut firings);
+    }
+    firings
+  }
+
+  /// Leaves the tier the battery was in and enters the one it is in now, when they differ.
+  /// A lower tier waits for the exemption to clear.
+  fn step_tier(
+    &mut self,
+    sensors: &Sensors<'_>,
+    machine: &MachineState,
+    backend: &impl Backend,
+    out: &mut Vec<Firing>,
+  ) {
+    let next = tiers::tier_for(&self.tiers, self.tier, machine);
+    if next == self.tier {
+      return;
+    }
+
+    let lower = next.is_some_and(|next| self.tier.is_none_or(|tier| next > tier));
+    let exempt = self.exemption.as_ref().is_some_and(|exemption| {
+      sensors
+        .check(exemption)
+        .map_err(|e| error!("Cannot check the power exemption: {:#}", e))
+        .unwrap_or(false)
+    });
+    if lower && exempt {
+      return;
+    }
+
+    let left = self.tier.and_then(|index| self.tiers.get(index));
+    let entered = next.and_then(|index| self.tiers.get(index));
+    for (tier, entering) in [(left, false), (entered, true)] {
+      let Some(tier) = tier else {
+        continue;
+      };
+
+      let actions = if entering {
+        &tier.actions
+      } else {
+        &tier.exit_actions
+      };
+      if actions.is_empty() {
+        continue;
+      }
+      let reason = format!(
+        "the battery {} tier {}",
+        if entering { "entered" } else { "left" },
+        tier.name
+      );
+      out.push(Firing {
+        rule: tier.name.clone(),
+        entered: entering,
+        actions: actions.clone(),
+        errors: run_actions(backend, actions, &reason),
+      });
+    }
+
+    self.tier = next;
+  }
+}
+
+/// Runs every action, even after one fails, and returns the errors
+fn run_actions(backend: &impl Backend, actions: &[Action], reason: &str) -> Vec<String> {
+  actions
+    .iter()
+    .filter_map(|action| dry_run::because(reason, || run_action(backend, action)).err())
+    .map(|e| format!("{:#}", e))
+    .collect()
+}
+
+fn step(
+  sensors: &Sensors<'_>,
+  backend: &impl Backend,
+  state: &mut RuleState,
+) -> Result<Option<Firing>> {
+  let triggered = sensors.check(&state.rule.trigger)?;
+  state.last_error = None;
+
+  let actions = if triggered && !state.engaged {
+    for condition in &state.rule.conditions {
+      if !sensors.check(condition)? {
+        return Ok(None);
+      }
+    }
+
+    state.engaged = true;
+    &state.rule.actions
+  } else if !triggered && state.engaged {
+    state.engaged = false;
+    &state.rule.exit_actions
+  } else {
+    return Ok(None);
+  };
+
+  if actions.is_empty() {
+    return Ok(None);
+  }
+
+  let reason = format!(
+    "rule {} {}",
+    state.rule.name,
+    if state.engaged { "fired" } else { "exited" }
+  );
+  Ok(Some(Firing {
+    rule: state.rule.name.clone(),
+    entered: state.engaged,
+    actions: actions.clone(),
+    errors: run_actions(backend, actions, &reason),
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::{
+    fake::{FakeBackend, FakeCall},
+    PowerBackend,
+  };
+
+  fn wifi_rule() -> Rule {
+    Rule {
+      name: "Wi-Fi off at night".to_string(),
+      trigger: Condition::TimeBetween {
+        from: "22:00".to_string(),
+        to: "06:00".to_string(),
+      },
+      actions: vec![Action::SetWifi(false)],
+      exit_actions: vec![Action::SetWifi(true)],
+      ..Rule::default()
+    }
+  }
+
+  fn at(hour: u32) -> MachineState {
+    MachineState {
+      local_time: crate::backend::types::LocalTime { hour, minute: 0 },
+      ..MachineState::new()
+    }
+  }
+
+  #[test]
+  fn runs_actions_on_entering_and_exit_actions_on_leaving() {
+    let backend = FakeBackend::new();
+    let mut engine = RuleEngine::new();
+    engine.set_rules(vec![wifi_rule()]);
+
+    assert_eq!(engine.tick(&at(12), &backend, Duration::ZERO), []);
+    let firings = engine.tick(&at(23), &backend, Duration::from_secs(1));
+    assert_eq!(firings.len(), 1);
+    assert!(firings[0].entered);
+    assert_eq!(engine.engaged(), ["Wi-Fi off at night"]);
+
+    // Nothing runs again while the trigger keeps holding
+    assert_eq!(engine.tick(&at(1), &backend, Duration::from_secs(2)), []);
+    engine.tick(&at(7), &backend, Duration::from_secs(3));
+    assert_eq!(
+      backend.take_calls(),
+      [FakeCall::SetWifiState(false), FakeCall::SetWifiState(true)]
+    );
+    assert!(engine.engaged().is_empty());
+  }
+
+  #[test]
+  fn conditions_are_only_checked_before_firing() {
+    let backend = FakeBackend::new();
+    let mut engine = RuleEngine::new();
+    engine.set_rules(vec![Rule {
+      conditions: vec![Condition::EthernetConnected],
+      ..wifi_rule()
+    }]);
+
+    engine.tick(&at(23), &backend, Duration::ZERO);
+    assert_eq!(backend.take_calls(), []);
+
+    let wired = MachineState {
+      ethernet: true,
+      ..at(23)
+    };
+    engine.tick(&wired, &backend, Duration::from_secs(1));
+    engine.tick(&at(23), &backend, Duration::from_secs(2));
+    assert_eq!(backend.take_calls(), [FakeCall::SetWifiState(false)]);
+  }
+
+  #[test]
+  fn failed_actions_are_reported_and_the_others_still_run() {
+    let backend = FakeBackend::new();
+    backend.update(|state| state.failing = vec!["set_wifi_state"]);
+    let mut engine = RuleEngine::new();
+    engine.set_rules(vec![Rule {
+      actions: vec![Action::SetWifi(false), Action::SetRefreshRate(60)],
+      ..wifi_rule()
+    }]);
+
+    let firings = engine.tick(&at(23), &backend, Duration::ZERO);
+    assert_eq!(firings[0].errors, ["set_wifi_state failed"]);
+    assert_eq!(backend.take_calls(), [FakeCall::SetRefreshRate(60)]);
+  }
+
+  #[test]
+  fn replacing_the_rules_keeps_unchanged_ones_engaged() {
+    let backend = FakeBackend::new();
+    let mut engine = RuleEngine::new();
+    engine.set_rules(vec![wifi_rule()]);
+    engine.tick(&at(23), &backend, Duration::ZERO);
+    backend.take_calls();
+
+    engine.set_rules(vec![wifi_rule()]);
+    assert_eq!(engine.tick(&at(23), &backend, Duration::from_secs(1)), []);
+    assert_eq!(engine.engaged(), ["Wi-Fi off at night"]);
+
+    // A disabled rule forgets it was engaged without running its exit actions
+    engine.set_rules(vec![Rule {
+      enabled: false,
+      ..wifi_rule()
+    }]);
+    engine.tick(&at(7), &backend, Duration::from_secs(2));
+    assert!(engine.engaged().is_empty());
+    assert_eq!(backend.take_calls(), []);
+  }
+
+  fn power_engine(backend: &FakeBackend) -> RuleEngine {
+    let mut config = crate::config::Config::new();
+    config.power.enabled = true;
+    config.power.timer = 300;
+    let mut engine = RuleEngine::new();
+    engine.configure(&schemes::with_schemes(
+      &config,
+      &backend.power_schemes().unwrap(),
+    ));
+    engine
+  }
+
+  fn on_battery() -> MachineState {
+    MachineState {
+      plugged_in: false,
+      battery_percentage: 80,
+      ..MachineState::new()
+    }
+  }
+
+  #[test]
+  fn restoring_carries_on_with_the_battery_timer() {
+    let backend = FakeBackend::new();
+    let mut engine = power_engine(&backend);
+    let unix_now = 1_760_000_000;
+    let state = RuntimeState {
+      on_battery_since: Some(unix_now - 200),
+      ..RuntimeState::default()
+    };
+
+    let now = Duration::from_secs(1000);
+    engine.restore(&state, now, unix_now);
+    assert_eq!(engine.next_deadline(now), Some(Duration::from_secs(101)));
+
+    engine.tick(&on_battery(), &backend, now + Duration::from_secs(100));
+    assert!(!engine
+      .engaged()
+      .contains(&"Power saver after a while on battery"));
+    engine.tick(&on_battery(), &backend, now + Duration::from_secs(101));
+    assert!(engine
+      .engaged()
+      .contains(&"Power saver after a while on battery"));
+  }
+
+  #[test]
+  fn restored_rules_exit_without_firing_again() {
+    let backend = FakeBackend::new();
+    let mut engine = power_engine(&backend);
+    let state = RuntimeState {
+      engaged: vec![
+        "Heavy startup apps off on battery".to_string(),
+        "A rule that is gone".to_string(),
+      ],
+      ..RuntimeState::default()
+    };
+
+    engine.restore(&state, Duration::ZERO, 1_760_000_000);
+    assert_eq!(engine.engaged(), ["Heavy startup apps off on battery"]);
+
+    // Still on battery: the actions already ran before the restart
+    let firings = engine.tick(&on_battery(), &backend, Duration::ZERO);
+    assert!(firings
+      .iter()
+      .all(|firing| firing.rule != "Heavy startup apps off on battery"));
+
+    let firings = engine.tick(&MachineState::new(), &backend, Duration::from_secs(1));
+    assert!(firings
+      .iter()
+      .any(|firing| firing.rule == "Heavy startup apps off on battery" && !firing.entered));
+  }
+
+  #[test]
+  fn a_reset_fires_the_rules_again() {
+    let backend = FakeBackend::new();
+    let mut engine = tier_engine(&backend);
+    let battery = MachineState {
+      battery_percentage: 25,
+      ..on_battery()
+    };
+    engine.tick(&battery, &backend, Duration::ZERO);
+    assert_eq!(engine.engaged(), ["Heavy startup apps off on battery"]);
+    assert_eq!(engine.battery_tier(), Some("Low"));
+
+    engine.reset();
+    assert!(engine.engaged().is_empty());
+    assert_eq!(engine.battery_tier(), None);
+    assert_eq!(engine.on_battery_since(), Some(Duration::ZERO));
+
+    let firings = engine.tick(&battery, &backend, Duration::from_secs(1));
+    let fired = firings
+      .iter()
+      .map(|firing| (firing.rule.as_str(), firing.entered))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      fired,
+      [("Heavy startup apps off on battery", true), ("Low", true)]
+    );
+  }
+
+  #[test]
+  fn saved_state_restores_the_same_engine() {
+    let backend = FakeBackend::new();
+    let mut engine = power_engine(&backend);
+    let unix_now = Duration::from_secs(1_760_000_000);
+    engine.tick(&on_battery(), &backend, Duration::ZERO);
+    engine.tick(&on_battery(), &backend, Duration::from_secs(50));
+    let state = engine.runtime_state(Duration::from_secs(50), unix_now, Some(1));
+    assert_eq!(state.on_battery_since, Some(unix_now.as_secs() - 50));
+
+    let mut restored = power_engine(&backend);
+    restored.restore(&state, Duration::ZERO, unix_now.as_secs());
+    assert_eq!(restored.engaged(), engine.engaged());
+    assert_eq!(
+      restored.next_deadline(Duration::ZERO),
+      engine.next_deadline(Duration::from_secs(50))
+    );
+  }
+
+  fn tier_engine(backend: &FakeBackend) -> RuleEngine {
+    let mut config = crate::config::Config::new();
+    config.power.enabled = true;
+    config.power.timer = 0;
+    config.power.exempt_apps = vec!["game.exe".to_string()];
+    config.power.tiers = ["Low", "Critical"]
+      .into_iter()
+      .zip([(30, 35), (10, 15)])
+      .map(|(name, (enter_at, exit_at))| tiers::BatteryTier {
+        name: name.to_string(),
+        enter_at,
+        exit_at,
+        actions: vec![Action::SetWifi(false)],
+        exit_actions: vec![Action::SetWifi(true)],
+      })
+      .collect();
+    let mut engine = RuleEngine::new();
+    engine.configure(&schemes::with_schemes(
+      &config,
+      &backend.power_schemes().unwrap(),
+    ));
+    engine
+  }
+
+  fn tier_edges(firings: Vec<Firing>) -> Vec<(String, bool)> {
+    firings
+      .into_iter()
+      .filter(|firing| firing.rule == "Low" || firing.rule == "Critical")
+      .map(|firing| (firing.rule, firing.entered))
+      .collect()
+  }
+
+  #[test]
+  fn a_lower_tier_waits_for_the_exemption_to_clear() {
+    let backend = FakeBackend::new();
+    let mut engine = tier_engine(&backend);
+    let gaming = |percentage| MachineState {
+      processes: vec!["game".to_string()],
+      battery_percentage: percentage,
+      ..on_battery()
+    };
+    let battery = |percentage| MachineState {
+      battery_percentage: percentage,
+      ..on_battery()
+    };
+
+    let firings = engine.tick(&gaming(25), &backend, Duration::ZERO);
+    assert_eq!(tier_edges(firings), []);
+    assert_eq!(engine.battery_tier(), None);
+
+    let firings = engine.tick(&battery(25), &backend, Duration::from_secs(1));
+    assert_eq!(tier_edges(firings), [("Low".to_string(), true)]);
+
+    // Going lower waits again, but the tier already entered stays
+    let firings = engine.tick(&gaming(5), &backend, Duration::from_secs(2));
+    assert_eq!(tier_edges(firings), []);
+    assert_eq!(engine.battery_tier(), Some("Low"));
+
+    let firings = engine.tick(&battery(5), &backend, Duration::from_secs(3));
+    assert_eq!(
+      tier_edges(firings),
+      [("Low".to_string(), false), ("Critical".to_string(), true)]
+    );
+
+    // Charging out of the tiers does not wait
+    let firings = engine.tick(&gaming(40), &backend, Duration::from_secs(4));
+    assert_eq!(tier_edges(firings), [("Critical".to_string(), false)]);
+    assert_eq!(engine.battery_tier(), None);
+  }
+}
@@ -0,0 +1,199 @@
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{backend::journal::Original, config::persist};
+
+pub const RUNTIME_FILE: &str = "runtime.json";
+
+/// A saved state is thrown away when the app was gone for longer than this, since nobody knows
+/// what happened to the machine in the meantime
+pub const MAX_DOWNTIME: Duration = Duration::from_secs(10 * 60);
+
+/// Boot times are derived from the uptime and drift by a second or two between launches
+const BOOT_TIME_TOLERANCE: u64 = 30;
+
+/// What the rule engine needs to carry on where a previous launch stopped
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RuntimeState {
+  /// Seconds since the Unix epoch
+  pub saved_at: u64,
+  pub boot_time: Option<u64>,
+  /// When the machine went on battery, in seconds since the Unix epoch
+  pub on_battery_since: Option<u64>,
+  /// Rules that fired and have not exited, so their exit actions still have to run
+  pub engaged: Vec<String>,
+  /// Name of the battery tier the machine was in
+  pub battery_tier: Option<String>,
+  /// Settings as they were before the app changed them, which outlive a reboot
+  pub originals: Vec<Original>,
+}
+
+/// The time since the Unix epoch
+pub fn unix_now() -> Duration {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+}
+
+/// The runtime state next to the per-user config at `config_path`
+pub fn runtime_path(config_path: &Path) -> PathBuf {
+  config_path
+    .parent()
+    .unwrap_or(Path::new("."))
+    .join(RUNTIME_FILE)
+}
+
+impl RuntimeState {
+  /// Returns `None` when nothing was saved yet
+  pub fn load(path: &Path) -> Result<Option<Self>> {
+    let contents = match std::fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(anyhow!(e).context(format!("Cannot open {}", path.display()))),
+    };
+
+    let state =
+      serde_json::from_str(&contents).with_context(|| format!("Cannot read {}", path.display()))?;
+    Ok(Some(state))
+  }
+
+  /// Keeps no backups like the config files do, as it is saved on every change and once a minute
+  pub fn save(&self, path: &Path) -> Result<()> {
+    if let Some(directory) = path.parent() {
+      std::fs::create_dir_all(directory)?;
+    }
+
+    persist::replace(path, &serde_json::to_string_pretty(self)?)
+      .with_context(|| format!("Cannot write {}", path.display()))
+  }
+
+  /// Ends the runtime of a launch that put everything back, keeping only the battery timer
+  pub fn forget_changes(path: &Path) -> Result<()> {
+    if let Some(mut state) = Self::load(path)? {
+      state.engaged.clear();
+      state.originals.clear();
+      state.save(path)?;
+    }
+    Ok(())
+  }
+
+  /// Why the counters and engaged rules no longer apply to a machine booted at `boot_time`, or
+  /// `None` when they still do
+  pub fn stale_because(&self, boot_time: Option<u64>, now: u64) -> Option<&'static str> {
+    match (self.boot_time, boot_time) {
+      (Some(saved), Some(current)) if saved.abs_diff(current) > BOOT_TIME_TOLERANCE => {
+        return Some("the machine restarted since");
+      }
+      (None, _) | (_, None) => return Some("the boot time is unknown"),
+      _ => {}
+    }
+
+    if now < self.saved_at {
+      return Some("it was saved in the future");
+    }
+    if now - self.saved_at > MAX_DOWNTIME.as_secs() {
+      return Some("it is too old");
+    }
+
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::temp_dir;
+
+  const BOOT: u64 = 1_760_000_000;
+  const SAVED: u64 = BOOT + 3600;
+
+  fn saved() -> RuntimeState {
+    RuntimeState {
+      saved_at: SAVED,
+      boot_time: Some(BOOT),
+      on_battery_since: Some(SAVED - 120),
+      engaged: vec!["Power saver after a while on battery".to_string()],
+      ..RuntimeState::default()
+    }
+  }
+
+  #[test]
+  fn a_recent_save_from_this_boot_applies() {
+    assert_eq!(saved().stale_because(Some(BOOT), SAVED + 60), None);
+    assert_eq!(
+      saved().stale_because(Some(BOOT + BOOT_TIME_TOLERANCE), SAVED),
+      None
+    );
+    assert_eq!(
+      saved().stale_because(Some(BOOT), SAVED + MAX_DOWNTIME.as_secs()),
+      None
+    );
+  }
+
+  #[test]
+  fn a_save_from_another_boot_is_stale() {
+    assert_eq!(
+      saved().stale_because(Some(BOOT + BOOT_TIME_TOLERANCE + 1), SAVED + 60),
+      Some("the machine restarted since")
+    );
+    assert_eq!(
+      saved().stale_because(None, SAVED + 60),
+      Some("the boot time is unknown")
+    );
+    let without_boot = RuntimeState {
+      boot_time: None,
+      ..saved()
+    };
+    assert_eq!(
+      without_boot.stale_because(Some(BOOT), SAVED + 60),
+      Some("the boot time is unknown")
+    );
+  }
+
+  #[test]
+  fn an_old_or_future_save_is_stale() {
+    assert_eq!(
+      saved().stale_because(Some(BOOT), SAVED + MAX_DOWNTIME.as_secs() + 1),
+      Some("it is too old")
+    );
+    assert_eq!(
+      saved().stale_because(Some(BOOT), SAVED - 1),
+      Some("it was saved in the future")
+    );
+  }
+
+  #[test]
+  fn saving_keeps_no_backups() {
+    let dir = temp_dir("runtime-save");
+    let path = dir.join(RUNTIME_FILE);
+
+    for minute in 0..3 {
+      let state = RuntimeState {
+        saved_at: SAVED + minute * 60,
+        ..saved()
+      };
+      state.save(&path).unwrap();
+      assert_eq!(RuntimeState::load(&path).unwrap(), Some(state));
+    }
+    let files = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(files, 1);
+  }
+
+  #[test]
+  fn forgetting_the_changes_keeps_the_battery_timer() {
+    let path = temp_dir("runtime-forget").join(RUNTIME_FILE);
+    assert_eq!(RuntimeState::load(&path).unwrap(), None);
+
+    saved().save(&path).unwrap();
+    RuntimeState::forget_changes(&path).unwrap();
+    let state = RuntimeState::load(&path).unwrap().unwrap();
+    assert!(state.engaged.is_empty());
+    assert_eq!(state.on_battery_since, saved().on_battery_since);
+  }
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+  backend::types::PowerScheme,
+  config::{Config, PowerConfig},
+  log::warning,
+};
+
+/// GUIDs of the schemes Windows ships with, whatever their names in the current language
+pub const BALANCED: &str = "381b4222-f694-41f0-9685-ff5bb260df2e";
+pub const POWER_SAVER: &str = "a1841308-3541-4fab-bc81-f71556f20b4a";
+pub const HIGH_PERFORMANCE: &str = "8c5e7fda-e8bf-4a96-9a85-a6e23a8c635c";
+
+/// Used in order when the battery scheme does not exist
+pub const BATTERY_FALLBACKS: [&str; 2] = [POWER_SAVER, BALANCED];
+/// Used in order when the AC scheme does not exist
+pub const AC_FALLBACKS: [&str; 2] = [HIGH_PERFORMANCE, BALANCED];
+
+/// The scheme named `wanted`, or with `wanted` as its GUID
+pub fn find<'a>(schemes: &'a [PowerScheme], wanted: &str) -> Option<&'a PowerScheme> {
+  schemes
+    .iter()
+    .find(|scheme| scheme.name == wanted || scheme.guid.eq_ignore_ascii_case(wanted))
+}
+
+/// The scheme `wanted`, or the first of `fallbacks` that exists when it does not or is empty
+pub fn resolve<'a>(
+  schemes: &'a [PowerScheme],
+  wanted: &str,
+  fallbacks: &[&str],
+) -> Result<&'a PowerScheme> {
+  if let Some(scheme) = find(schemes, wanted) {
+    return Ok(scheme);
+  }
+
+  let scheme = fallbacks
+    .iter()
+    .find_map(|fallback| find(schemes, fallback))
+    .ok_or_else(|| {
+      let names = schemes
+        .iter()
+        .map(|scheme| scheme.name.as_str())
+        .collect::<Vec<_>>();
+      anyhow!(
+        "Cannot find power scheme {}, nor any built-in one to use instead. The schemes on this \
+         machine are: {}",
+        if wanted.is_empty() {
+          "(none set)"
+        } else {
+          wanted
+        },
+        names.join(", ")
+      )
+    })?;
+
+  if !wanted.is_empty() {
+    warning!(
+      "Cannot find power scheme {}, using {} instead",
+      wanted,
+      scheme.name
+    );
+  }
+  Ok(scheme)
+}
+
+/// The battery and AC schemes of `power` as they exist on this machine
+pub fn resolve_power(
+  power: &PowerConfig,
+  schemes: &[PowerScheme],
+) -> Result<(PowerScheme, PowerScheme)> {
+  let battery = resolve(schemes, &power.battery_scheme, &BATTERY_FALLBACKS)
+    .map_err(|e| e.context("The battery power scheme cannot be used"))?;
+  let ac = resolve(schemes, &power.ac_scheme, &AC_FALLBACKS)
+    .map_err(|e| e.context("The AC power scheme cannot be used"))?;
+
+  Ok((battery.clone(), ac.clone()))
+}
+
+/// `config` with its power schemes pointing at the GUIDs they resolve to, so the default rules
+/// only name schemes that exist. Schemes that cannot be resolved are left alone.
+pub fn with_schemes(config: &Config, schemes: &[PowerScheme]) -> Config {
+  let mut config = config.clone();
+  let power = &mut config.power;
+
+  if let Ok(battery) = resolve(schemes, &power.battery_scheme, &BATTERY_FALLBACKS) {
+    power.battery_scheme = battery.guid.clone();
+  }
+  if let Ok(ac) = resolve(schemes, &power.ac_scheme, &AC_FALLBACKS) {
+    power.ac_scheme = ac.guid.clone();
+  }
+  config
+}
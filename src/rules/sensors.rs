@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use super::types::{parse_clock, Condition};
+use crate::{backend::types::process_name, bus::state::MachineState};
+
+/// Evaluates conditions against the machine state as reported on the bus
+pub struct Sensors<'a> {
+  state: &'a MachineState,
+  /// Kept by the engine, the state only knows the current power source
+  on_battery_for: Option<Duration>,
+}
+
+impl<'a> Sensors<'a> {
+  pub fn new(state: &'a MachineState, on_battery_for: Option<Duration>) -> Self {
+    Self {
+      state,
+      on_battery_for,
+    }
+  }
+
+  pub fn check(&self, condition: &Condition) -> Result<bool> {
+    let state = self.state;
+    let any_of = |running: &[String], apps: &[String]| {
+      apps.iter().any(|app| running.contains(&process_name(app)))
+    };
+
+    Ok(match condition {
+      Condition::All(conditions) => {
+        for condition in conditions {
+          if !self.check(condition)? {
+            return Ok(false);
+          }
+        }
+        true
+      }
+      Condition::Any(conditions) => {
+        for condition in conditions {
+          if self.check(condition)? {
+            return Ok(true);
+          }
+        }
+        false
+      }
+      Condition::Not(condition) => !self.check(condition)?,
+
+      Condition::PluggedIn => state.plugged_in,
+      Condition::OnBattery => !state.plugged_in,
+      Condition::OnBatteryFor(secs) => self
+        .on_battery_for
+        .is_some_and(|elapsed| elapsed > Duration::from_secs(*secs as u64)),
+      Condition::BatteryBelow(percentage) => {
+        state.has_battery() && state.battery_percentage < *percentage
+      }
+      Condition::DrainRateAbove(rate) => state.drain_rate.is_some_and(|drain| drain > *rate),
+      Condition::TimeToEmptyBelow(minutes) => state
+        .minutes_to_empty
+        .is_some_and(|left| !state.plugged_in && left < *minutes),
+      Condition::PowerSchemeIs(scheme) => state
+        .power_scheme
+        .as_ref()
+        .is_some_and(|active| active.name == *scheme || active.guid.eq_ignore_ascii_case(scheme)),
+
+      Condition::AudioSessionActive { device, apps } => {
+        any_of(state.audio_applications(*device), apps)
+      }
+      Condition::DefaultOutputIs(device) => state
+        .default_output
+        .as_ref()
+        .is_some_and(|output| output.kind == *device || output.name == *device),
+
+      Condition::EthernetConnected => state.ethernet,
+
+      Condition::ProcessRunning(apps) => any_of(&state.processes, apps),
+      Condition::WindowMaximized(apps) => any_of(&state.maximized_windows, apps),
+      Condition::CpuLoadAbove(percentage) => state.cpu_load > *percentage,
+
+      Condition::TimeBetween { from, to } => {
+        let (from, to) = parse_clock(from)
+          .zip(parse_clock(to))
+          .ok_or_else(|| anyhow!("Invalid time range {} - {}", from, to))?;
+        let now = state.local_time.hour * 60 + state.local_time.minute;
+
+        if from <= to {
+          from <= now && now < to
+        } else {
+          now >= from || now < to
+        }
+      }
+    })
+  }
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+use super::types::Action;
+use crate::bus::state::MachineState;
+
+/// A battery range with settings of its own. It is entered once the battery drops to
+/// `enter_at` percent and left once it charges above `exit_at`, so a battery hovering around
+/// the threshold does not flap between tiers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct BatteryTier {
+  pub name: String,
+  pub enter_at: u32,
+  pub exit_at: u32,
+
+  pub actions: Vec<Action>,
+  pub exit_actions: Vec<Action>,
+}
+
+impl BatteryTier {
+  /// Whether the battery is in this tier, given that it already was when `inside`
+  fn holds(&self, percentage: u32, inside: bool) -> bool {
+    percentage <= self.enter_at || (inside && percentage <= self.exit_at.max(self.enter_at))
+  }
+}
+
+/// Orders `tiers` from the highest threshold to the lowest, the order they are entered in
+pub fn sorted(mut tiers: Vec<BatteryTier>) -> Vec<BatteryTier> {
+  tiers.sort_by_key(|tier| std::cmp::Reverse(tier.enter_at));
+  tiers
+}
+
+/// The lowest tier the battery is in, with `tiers` as `sorted` returns them and `current` the
+/// tier it was in so far
+pub fn tier_for(
+  tiers: &[BatteryTier],
+  current: Option<usize>,
+  machine: &MachineState,
+) -> Option<usize> {
+  if machine.plugged_in || !machine.has_battery() {
+    return None;
+  }
+
+  tiers.iter().enumerate().rev().find_map(|(i, tier)| {
+    let inside = current.is_some_and(|current| current >= i);
+    tier.holds(machine.battery_percentage, inside).then_some(i)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::types::BatteryPresence;
+
+  fn tier(name: &str, enter_at: u32, exit_at: u32) -> BatteryTier {
+    BatteryTier {
+      name: name.to_string(),
+      enter_at,
+      exit_at,
+      ..BatteryTier::default()
+    }
+  }
+
+  fn tiers() -> Vec<BatteryTier> {
+    sorted(vec![tier("Critical", 10, 15), tier("Low", 30, 35)])
+  }
+
+  fn on_battery(percentage: u32) -> MachineState {
+    MachineState {
+      plugged_in: false,
+      battery_percentage: percentage,
+      ..MachineState::new()
+    }
+  }
+
+  /// The tier after each level in turn, starting outside of every tier
+  fn walk(levels: &[u32]) -> Vec<Option<&'static str>> {
+    let tiers = tiers();
+    let mut current = None;
+    levels
+      .iter()
+      .map(|level| {
+        current = tier_for(&tiers, current, &on_battery(*level));
+        current.map(|index| ["Low", "Critical"][index])
+      })
+      .collect()
+  }
+
+  #[test]
+  fn sorted_from_the_highest_threshold() {
+    let names = tiers()
+      .into_iter()
+      .map(|tier| tier.name)
+      .collect::<Vec<_>>();
+    assert_eq!(names, ["Low", "Critical"]);
+  }
+
+  #[test]
+  fn entered_at_the_threshold() {
+    assert_eq!(walk(&[31, 30]), [None, Some("Low")]);
+  }
+
+  #[test]
+  fn left_only_above_the_exit_threshold() {
+    assert_eq!(
+      walk(&[30, 34, 35, 36]),
+      [Some("Low"), Some("Low"), Some("Low"), None]
+    );
+  }
+
+  #[test]
+  fn does_not_flap_around_the_threshold() {
+    assert_eq!(walk(&[30, 31, 30, 31, 29, 32]), [Some("Low"); 6]);
+  }
+
+  #[test]
+  fn skips_past_several_tiers_at_once() {
+    assert_eq!(walk(&[50, 8]), [None, Some("Critical")]);
+    assert_eq!(walk(&[8, 40]), [Some("Critical"), None]);
+    assert_eq!(walk(&[8, 20]), [Some("Critical"), Some("Low")]);
+  }
+
+  #[test]
+  fn no_tier_when_plugged_in_or_without_a_battery() {
+    let tiers = tiers();
+    let plugged_in = MachineState {
+      plugged_in: true,
+      ..on_battery(5)
+    };
+    assert_eq!(tier_for(&tiers, Some(1), &plugged_in), None);
+
+    let absent = MachineState {
+      battery: BatteryPresence::Absent,
+      ..on_battery(5)
+    };
+    assert_eq!(tier_for(&tiers, None, &absent), None);
+  }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{backend::types::DeviceType, bus::sampler::Source};
+
+/// Something about the machine that is either true or false right now
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+  All(Vec<Condition>),
+  Any(Vec<Condition>),
+  Not(Box<Condition>),
+
+  // Power
+  PluggedIn,
+  OnBattery,
+  /// Seconds spent on battery since the charger was last unplugged
+  OnBatteryFor(u32),
+  BatteryBelow(u32),
+  /// Percent per hour lost while on battery, as estimated from the battery history
+  DrainRateAbove(u32),
+  /// Minutes left on battery, as estimated from the battery history
+  TimeToEmptyBelow(u32),
+  /// Name or GUID of the active power scheme
+  PowerSchemeIs(String),
+
+  // Audio sessions
+  AudioSessionActive {
+    device: DeviceType,
+    apps: Vec<String>,
+  },
+  /// Kind (`Speakers`, `Headphones`) or name of the default output
+  DefaultOutputIs(String),
+
+  // Network
+  EthernetConnected,
+
+  // Processes
+  ProcessRunning(Vec<String>),
+  WindowMaximized(Vec<String>),
+  /// Average processor load, in percent
+  CpuLoadAbove(u32),
+
+  // Time
+  /// Local time of day as `HH:MM`, wrapping around midnight when `from` is after `to`
+  TimeBetween {
+    from: String,
+    to: String,
+  },
+}
+
+impl Condition {
+  /// A condition that always holds
+  pub fn always() -> Self {
+    Condition::All(Vec::new())
+  }
+
+  /// Whether checking the condition needs `source` to be sampled
+  pub fn reads(&self, source: Source) -> bool {
+    match self {
+      Condition::All(conditions) | Condition::Any(conditions) => {
+        conditions.iter().any(|condition| condition.reads(source))
+      }
+      Condition::Not(condition) => condition.reads(source),
+      Condition::PluggedIn
+      | Condition::OnBattery
+      | Condition::OnBatteryFor(_)
+      | Condition::BatteryBelow(_)
+      | Condition::DrainRateAbove(_)
+      | Condition::TimeToEmptyBelow(_)
+      | Condition::PowerSchemeIs(_) => source == Source::Power,
+      Condition::AudioSessionActive { .. } | Condition::DefaultOutputIs(_) => {
+        source == Source::Audio
+      }
+      Condition::EthernetConnected => source == Source::Network,
+      Condition::ProcessRunning(_) => source == Source::Processes,
+      Condition::WindowMaximized(_) => source == Source::Windows,
+      Condition::CpuLoadAbove(_) => source == Source::Cpu,
+      Condition::TimeBetween { .. } => source == Source::Clock,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+  /// Name or GUID of the scheme
+  SetPowerScheme(String),
+  /// Kind (`Speakers`, `Headphones`) or name of the device
+  SetDefaultOutput(String),
+  SetWifi(bool),
+  SetRefreshRate(u32),
+  TurnOffMonitor,
+  /// Only the listed items that exist are changed
+  SetStartupItems {
+    items: Vec<String>,
+    enabled: bool,
+  },
+  SetTaskbarAutohide(bool),
+}
+
+/// Runs `actions` once `trigger` holds and every condition is met, then `exit_actions` once
+/// `trigger` stops holding. The conditions are only checked before the rule fires.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Rule {
+  pub name: String,
+  pub enabled: bool,
+
+  pub trigger: Condition,
+  pub conditions: Vec<Condition>,
+
+  pub actions: Vec<Action>,
+  pub exit_actions: Vec<Action>,
+}
+
+impl Rule {
+  /// Whether the rule is enabled and needs `source` to be sampled
+  pub fn reads(&self, source: Source) -> bool {
+    self.enabled
+      && (self.trigger.reads(source)
+        || self
+          .conditions
+          .iter()
+          .any(|condition| condition.reads(source)))
+  }
+}
+
+impl Default for Rule {
+  fn default() -> Self {
+    Self {
+      name: String::new(),
+      enabled: true,
+
+      trigger: Condition::always(),
+      conditions: Vec::new(),
+
+      actions: Vec::new(),
+      exit_actions: Vec::new(),
+    }
+  }
+}
+
+/// What a rule did during one tick
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firing {
+  pub rule: String,
+  /// True when the rule fired, false when it exited
+  pub entered: bool,
+  pub actions: Vec<Action>,
+  pub errors: Vec<String>,
+}
+
+/// Minutes since midnight for `HH:MM`
+pub fn parse_clock(time: &str) -> Option<u32> {
+  let (hour, minute) = time.split_once(':')?;
+  let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+  (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+}
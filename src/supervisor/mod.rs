@@ -0,0 +1,558 @@
+pub mod module;
+
+use std::{
+  any::Any,
+  fmt,
+  panic::{catch_unwind, AssertUnwindSafe},
+  sync::{
+    mpsc::{channel, Receiver, Sender},
+    Condvar, Mutex,
+  },
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::log::warning;
+
+/// Delay before the first restart, doubled after every crash up to `MAX_BACKOFF`
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A worker that crashes after running this long starts over from `INITIAL_BACKOFF`
+pub const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// The longest a worker may block before checking whether it should stop
+pub const STOP_CHECK: Duration = Duration::from_millis(500);
+
+/// Why a worker body returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+  Returned,
+  Failed(String),
+  Panicked(String),
+}
+
+impl fmt::Display for ExitReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ExitReason::Returned => write!(f, "returned"),
+      ExitReason::Failed(error) => write!(f, "failed: {}", error),
+      ExitReason::Panicked(message) => write!(f, "panicked: {}", message),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerState {
+  Running,
+  Restarting {
+    backoff: Duration,
+  },
+  /// Told to stop, but its thread has not finished yet
+  Stopping,
+  Stopped,
+}
+
+impl fmt::Display for WorkerState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WorkerState::Running => write!(f, "running"),
+      WorkerState::Restarting { backoff } => write!(f, "restarting in {}s", backoff.as_secs()),
+      WorkerState::Stopping => write!(f, "stopping"),
+      WorkerState::Stopped => write!(f, "stopped"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStatus {
+  pub name: String,
+  pub state: WorkerState,
+  pub restarts: u32,
+  pub last_exit: Option<ExitReason>,
+}
+
+impl WorkerStatus {
+  /// A worker that was stopped on purpose is not a problem, one waiting to restart is
+  pub fn is_healthy(&self) -> bool {
+    !matches!(self.state, WorkerState::Restarting { .. })
+  }
+}
+
+impl fmt::Display for WorkerStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.name, self.state)?;
+    if self.restarts > 0 {
+      write!(f, " after {} restart(s)", self.restarts)?;
+    }
+    if let Some(reason) = &self.last_exit {
+      write!(f, ", last {}", reason)?;
+    }
+    Ok(())
+  }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+  match payload.downcast::<String>() {
+    Ok(message) => *message,
+    Err(payload) => match payload.downcast::<&'static str>() {
+      Ok(message) => message.to_string(),
+      Err(_) => "unknown panic".to_string(),
+    },
+  }
+}
+
+/// What a worker body gets to find out when it should return
+pub struct Worker<'a> {
+  supervisor: &'a Supervisor,
+  index: usize,
+}
+
+impl Worker<'_> {
+  pub fn is_stopping(&self) -> bool {
+    self
+      .supervisor
+      .workers
+      .lock()
+      .unwrap()
+      .is_stopping(self.index)
+  }
+
+  /// Sleeps for `timeout` or until the worker is stopped. Returns whether it should return.
+  pub fn wait(&self, timeout: Duration) -> bool {
+    let workers = self.supervisor.workers.lock().unwrap();
+    let (workers, _) = self
+      .supervisor
+      .wake
+      .wait_timeout_while(workers, timeout, |workers| !workers.is_stopping(self.index))
+      .unwrap();
+    workers.is_stopping(self.index)
+  }
+}
+
+struct Workers {
+  statuses: Vec<WorkerStatus>,
+  handles: Vec<Option<JoinHandle<()>>>,
+  stop: Vec<bool>,
+  stop_all: bool,
+}
+
+impl Workers {
+  fn is_stopping(&self, index: usize) -> bool {
+    self.stop_all || self.stop[index]
+  }
+}
+
+/// Owns every long-running worker thread.
+///
+/// A worker that returns, fails or panics is restarted with exponential backoff until it is
+/// stopped, and every change of a worker's status goes out to the subscribers.
+pub struct Supervisor {
+  workers: Mutex<Workers>,
+  subscribers: Mutex<Vec<Sender<Vec<WorkerStatus>>>>,
+  wake: Condvar,
+  initial_backoff: Duration,
+  max_backoff: Duration,
+}
+
+impl Supervisor {
+  pub const fn new() -> Self {
+    Self::with_backoff(INITIAL_BACKOFF, MAX_BACKOFF)
+  }
+
+  /// Restarts after `initial` at first, doubling up to `max`
+  pub const fn with_backoff(initial: Duration, max: Duration) -> Self {
+    Self {
+      workers: Mutex::new(Workers {
+        statuses: Vec::new(),
+        handles: Vec::new(),
+        stop: Vec::new(),
+        stop_all: false,
+      }),
+      subscribers: Mutex::new(Vec::new()),
+      wake: Condvar::new(),
+      initial_backoff: initial,
+      max_backoff: max,
+    }
+  }
+
+  pub fn statuses(&self) -> Vec<WorkerStatus> {
+    self.workers.lock().unwrap().statuses.clone()
+  }
+
+  pub fn status(&self, name: &str) -> Option<WorkerStatus> {
+    self
+      .workers
+      .lock()
+      .unwrap()
+      .statuses
+      .iter()
+      .find(|status| status.name == name)
+      .cloned()
+  }
+
+  pub fn is_degraded(&self) -> bool {
+    self
+      .workers
+      .lock()
+      .unwrap()
+      .statuses
+      .iter()
+      .any(|status| !status.is_healthy())
+  }
+
+  pub fn subscribe(&self) -> Receiver<Vec<WorkerStatus>> {
+    let (sender, receiver) = channel();
+    self.subscribers.lock().unwrap().push(sender);
+    receiver
+  }
+
+  /// Runs `body` on its own thread named `name`, again and again until it is stopped.
+  ///
+  /// A stopped worker can be spawned again under the same name. The body should return soon
+  /// after `Worker::is_stopping` turns true.
+  pub fn spawn(
+    &'static self,
+    name: &str,
+    body: impl Fn(&Worker) -> Result<()> + Send + 'static,
+  ) -> Result<()> {
+    let mut workers = self.workers.lock().unwrap();
+    if workers.stop_all {
+      return Err(anyhow!("Cannot start {} while shutting down", name));
+    }
+
+    let status = WorkerStatus {
+      name: name.to_string(),
+      state: WorkerState::Running,
+      restarts: 0,
+      last_exit: None,
+    };
+    let index = match workers
+      .statuses
+      .iter()
+      .position(|status| status.name == name)
+    {
+      Some(index) if workers.statuses[index].state == WorkerState::Stopping => {
+        return Err(anyhow!("{} is still stopping", name))
+      }
+      Some(index) if workers.statuses[index].state != WorkerState::Stopped => {
+        return Err(anyhow!("{} is already running", name))
+      }
+      Some(index) => {
+        workers.statuses[index] = status;
+        workers.stop[index] = false;
+        index
+      }
+      None => {
+        workers.statuses.push(status);
+        workers.handles.push(None);
+        workers.stop.push(false);
+        workers.statuses.len() - 1
+      }
+    };
+
+    let worker_name = name.to_string();
+    let handle = std::thread::Builder::new()
+      .name(name.to_string())
+      .spawn(move || self.supervise(index, &worker_name, body))?;
+    workers.handles[index] = Some(handle);
+    drop(workers);
+
+    self.update(index, |_| {});
+    Ok(())
+  }
+
+  fn supervise(&self, index: usize, name: &str, body: impl Fn(&Worker) -> Result<()>) {
+    let worker = Worker {
+      supervisor: self,
+      index,
+    };
+    let mut backoff = self.initial_backoff;
+
+    loop {
+      let started = Instant::now();
+      let reason = match catch_unwind(AssertUnwindSafe(|| body(&worker))) {
+        Ok(Ok(())) => ExitReason::Returned,
+        Ok(Err(e)) => ExitReason::Failed(format!("{:#}", e)),
+        Err(payload) => ExitReason::Panicked(panic_message(payload)),
+      };
+
+      if worker.is_stopping() {
+        self.update(index, |status| status.state = WorkerState::Stopped);
+        return;
+      }
+
+      if started.elapsed() >= STABLE_AFTER {
+        backoff = self.initial_backoff;
+      }
+
+      warning!("{} {}, restarting in {:?}", name, reason, backoff);
+      self.update(index, |status| {
+        status.state = WorkerState::Restarting { backoff };
+        status.restarts += 1;
+        status.last_exit = Some(reason);
+      });
+
+      if worker.wait(backoff) {
+        self.update(index, |status| status.state = WorkerState::Stopped);
+        return;
+      }
+      backoff = (backoff * 2).min(self.max_backoff);
+
+      self.update(index, |status| {
+        if status.state != WorkerState::Stopping {
+          status.state = WorkerState::Running;
+        }
+      });
+    }
+  }
+
+  fn update(&self, index: usize, f: impl FnOnce(&mut WorkerStatus)) {
+    let mut workers = self.workers.lock().unwrap();
+    f(&mut workers.statuses[index]);
+
+    // Notify while still holding the lock so subscribers see changes in order
+    let statuses = workers.statuses.clone();
+    self
+      .subscribers
+      .lock()
+      .unwrap()
+      .retain(|subscriber| subscriber.send(statuses.clone()).is_ok());
+  }
+
+  /// Marks the worker as stopping, unless its thread is already done
+  fn stopping(&self, index: usize) {
+    self.update(index, |status| {
+      if status.state != WorkerState::Stopped {
+        status.state = WorkerState::Stopping;
+      }
+    });
+  }
+
+  fn join(handles: Vec<JoinHandle<()>>, timeout: Duration) -> Vec<String> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline && !handles.iter().all(JoinHandle::is_finished) {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+
+    handles
+      .iter()
+      .filter(|handle| !handle.is_finished())
+      .filter_map(|handle| handle.thread().name().map(str::to_string))
+      .collect()
+  }
+
+  /// Tells the worker `name` to stop and waits up to `timeout` for it.
+  /// Returns whether it is no longer running.
+  pub fn stop_worker(&self, name: &str, timeout: Duration) -> bool {
+    let (index, handle) = {
+      let mut workers = self.workers.lock().unwrap();
+      let Some(index) = workers
+        .statuses
+        .iter()
+        .position(|status| status.name == name)
+      else {
+        return true;
+      };
+      workers.stop[index] = true;
+      (index, workers.handles[index].take())
+    };
+    self.stopping(index);
+    self.wake.notify_all();
+
+    Self::join(handle.into_iter().collect(), timeout).is_empty()
+  }
+
+  /// Tells every worker to stop and waits up to `timeout` for them.
+  /// Returns the names of the workers that are still running.
+  pub fn stop(&self, timeout: Duration) -> Vec<String> {
+    let (count, handles) = {
+      let mut workers = self.workers.lock().unwrap();
+      workers.stop_all = true;
+      let handles = workers
+        .handles
+        .iter_mut()
+        .filter_map(Option::take)
+        .collect();
+      (workers.statuses.len(), handles)
+    };
+    for index in 0..count {
+      self.stopping(index);
+    }
+    self.wake.notify_all();
+
+    Self::join(handles, timeout)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  };
+
+  use super::*;
+
+  const MS: Duration = Duration::from_millis(1);
+  const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+  fn supervisor() -> &'static Supervisor {
+    Box::leak(Box::new(Supervisor::with_backoff(10 * MS, 40 * MS)))
+  }
+
+  /// Statuses of the worker `name` as they change, until `until` holds
+  fn watch(
+    changes: &Receiver<Vec<WorkerStatus>>,
+    name: &str,
+    until: impl Fn(&WorkerStatus) -> bool,
+  ) -> Vec<WorkerStatus> {
+    let mut seen = Vec::new();
+    while !seen.last().is_some_and(&until) {
+      let statuses = changes
+        .recv_timeout(STOP_TIMEOUT)
+        .expect("No status change");
+      seen.extend(statuses.into_iter().filter(|status| status.name == name));
+    }
+    seen
+  }
+
+  #[test]
+  fn a_panicking_worker_restarts_with_growing_delays() {
+    let supervisor = supervisor();
+    let changes = supervisor.subscribe();
+    supervisor
+      .spawn("panicking", |_| panic!("Out of coffee"))
+      .unwrap();
+
+    let seen = watch(&changes, "panicking", |status| status.restarts == 5);
+    let backoffs = seen
+      .iter()
+      .filter_map(|status| match status.state {
+        WorkerState::Restarting { backoff } => Some(backoff),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(backoffs, [10 * MS, 20 * MS, 40 * MS, 40 * MS, 40 * MS]);
+
+    let status = seen.last().unwrap();
+    assert_eq!(
+      status.last_exit,
+      Some(ExitReason::Panicked("Out of coffee".to_string()))
+    );
+    assert!(supervisor.is_degraded());
+
+    assert_eq!(supervisor.stop(STOP_TIMEOUT), Vec::<String>::new());
+    assert_eq!(
+      supervisor.status("panicking").unwrap().state,
+      WorkerState::Stopped
+    );
+    assert!(!supervisor.is_degraded());
+  }
+
+  #[test]
+  fn why_a_worker_exited_is_kept() {
+    let supervisor = supervisor();
+    let changes = supervisor.subscribe();
+    let runs = Arc::new(AtomicUsize::new(0));
+    supervisor
+      .spawn("flaky", {
+        let runs = runs.clone();
+        move |worker| match runs.fetch_add(1, Ordering::SeqCst) {
+          0 => Err(anyhow!("No network")),
+          1 => Ok(()),
+          _ => {
+            while !worker.wait(STOP_CHECK) {}
+            Ok(())
+          }
+        }
+      })
+      .unwrap();
+
+    let seen = watch(&changes, "flaky", |status| {
+      status.restarts == 2 && status.state == WorkerState::Running
+    });
+    let exits = seen
+      .iter()
+      .filter(|status| matches!(status.state, WorkerState::Restarting { .. }))
+      .map(|status| status.last_exit.clone().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      exits,
+      [
+        ExitReason::Failed("No network".to_string()),
+        ExitReason::Returned
+      ]
+    );
+    assert!(!supervisor.is_degraded());
+    assert_eq!(
+      supervisor.status("flaky").unwrap().to_string(),
+      "flaky: running after 2 restart(s), last returned"
+    );
+
+    assert!(supervisor.stop_worker("flaky", STOP_TIMEOUT));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+  }
+
+  #[test]
+  fn stop_joins_every_worker() {
+    let supervisor = supervisor();
+    let finished = Arc::new(AtomicUsize::new(0));
+    for name in ["first", "second", "third"] {
+      let finished = finished.clone();
+      supervisor
+        .spawn(name, move |worker| {
+          while !worker.wait(Duration::from_secs(60)) {}
+          std::thread::sleep(20 * MS);
+          finished.fetch_add(1, Ordering::SeqCst);
+          Ok(())
+        })
+        .unwrap();
+    }
+
+    assert_eq!(supervisor.stop(STOP_TIMEOUT), Vec::<String>::new());
+    assert_eq!(finished.load(Ordering::SeqCst), 3);
+    assert!(supervisor
+      .statuses()
+      .iter()
+      .all(|status| status.state == WorkerState::Stopped && status.restarts == 0));
+
+    assert!(supervisor.spawn("late", |_| Ok(())).is_err());
+  }
+
+  #[test]
+  fn stop_names_the_workers_still_running() {
+    let supervisor = supervisor();
+    supervisor
+      .spawn("stubborn", |_| {
+        std::thread::sleep(Duration::from_millis(300));
+        Ok(())
+      })
+      .unwrap();
+
+    assert_eq!(supervisor.stop(10 * MS), ["stubborn"]);
+  }
+
+  #[test]
+  fn a_stopped_worker_can_be_spawned_again() {
+    let supervisor = supervisor();
+    let body = |worker: &Worker| {
+      while !worker.wait(Duration::from_secs(60)) {}
+      Ok(())
+    };
+    supervisor.spawn("again", body).unwrap();
+    assert!(supervisor.spawn("again", body).is_err());
+
+    assert!(supervisor.stop_worker("again", STOP_TIMEOUT));
+    supervisor.spawn("again", body).unwrap();
+    assert_eq!(
+      supervisor.status("again").unwrap().state,
+      WorkerState::Running
+    );
+    assert_eq!(supervisor.statuses().len(), 1);
+
+    assert!(supervisor.stop_worker("again", STOP_TIMEOUT));
+  }
+}
@@ -0,0 +1,212 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use super::{Supervisor, Worker, WorkerState};
+use crate::{
+  config::Config,
+  log::{error, info},
+};
+
+/// How long a module gets to release its resources when it is switched off
+pub const MODULE_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A feature that only holds on to its resources while the config wants it
+pub trait Module: Send {
+  fn name(&self) -> &str;
+  /// Whether the module should be running with `config`
+  fn wanted(&self, config: &Config) -> bool;
+  fn start(&mut self) -> Result<()>;
+  fn stop(&mut self) -> Result<()>;
+  fn status(&self) -> WorkerState;
+}
+
+type Body = dyn Fn(&Worker) -> Result<()> + Send + Sync;
+
+/// A module backed by a supervised worker thread. Stopping it ends the thread, so everything
+/// the thread set up for itself goes away with it.
+pub struct WorkerModule {
+  name: String,
+  supervisor: &'static Supervisor,
+  wanted: fn(&Config) -> bool,
+  body: Arc<Body>,
+}
+
+impl WorkerModule {
+  pub fn new(
+    name: &str,
+    supervisor: &'static Supervisor,
+    wanted: fn(&Config) -> bool,
+    body: impl Fn(&Worker) -> Result<()> + Send + Sync + 'static,
+  ) -> Self {
+    Self {
+      name: name.to_string(),
+      supervisor,
+      wanted,
+      body: Arc::new(body),
+    }
+  }
+}
+
+impl Module for WorkerModule {
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn wanted(&self, config: &Config) -> bool {
+    (self.wanted)(config)
+  }
+
+  fn start(&mut self) -> Result<()> {
+    let body = self.body.clone();
+    self
+      .supervisor
+      .spawn(&self.name, move |worker| body(worker))
+  }
+
+  fn stop(&mut self) -> Result<()> {
+    if self.supervisor.stop_worker(&self.name, MODULE_STOP_TIMEOUT) {
+      Ok(())
+    } else {
+      Err(anyhow!("{} did not stop in time", self.name))
+    }
+  }
+
+  fn status(&self) -> WorkerState {
+    self
+      .supervisor
+      .status(&self.name)
+      .map_or(WorkerState::Stopped, |status| status.state)
+  }
+}
+
+/// Every module, started and stopped to follow the config
+#[derive(Default)]
+pub struct Modules {
+  modules: Vec<Box<dyn Module>>,
+}
+
+impl Modules {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn add(&mut self, module: impl Module + 'static) {
+    self.modules.push(Box::new(module));
+  }
+
+  pub fn statuses(&self) -> Vec<(&str, WorkerState)> {
+    self
+      .modules
+      .iter()
+      .map(|module| (module.name(), module.status()))
+      .collect()
+  }
+
+  /// Starts every stopped module `config` wants and stops every running one it no longer does.
+  /// A module that is still stopping is started by a later call, once it has stopped.
+  pub fn apply(&mut self, config: &Config) {
+    for module in &mut self.modules {
+      let result = match (module.wanted(config), module.status()) {
+        (true, WorkerState::Stopped) => module.start().map(|_| "Started"),
+        (false, WorkerState::Running | WorkerState::Restarting { .. }) => {
+          module.stop().map(|_| "Stopped")
+        }
+        _ => continue,
+      };
+
+      match result {
+        Ok(done) => info!("{} {}", done, module.name()),
+        Err(e) => error!("Cannot switch {}: {:#}", module.name(), e),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+
+  /// A module that runs while ethernet is on and counts how often it started and finished
+  fn module(name: &str) -> (Modules, &'static Supervisor, Arc<[AtomicUsize; 2]>) {
+    let supervisor: &'static Supervisor = Box::leak(Box::new(Supervisor::new()));
+    let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+
+    let mut modules = Modules::new();
+    modules.add(WorkerModule::new(
+      name,
+      supervisor,
+      |config| config.ethernet,
+      {
+        let counts = counts.clone();
+        move |worker| {
+          counts[0].fetch_add(1, Ordering::SeqCst);
+          while !worker.wait(Duration::from_millis(100)) {}
+          // Slow to let go of what it holds
+          std::thread::sleep(Duration::from_millis(50));
+          counts[1].fetch_add(1, Ordering::SeqCst);
+          Ok(())
+        }
+      },
+    ));
+    (modules, supervisor, counts)
+  }
+
+  fn config(ethernet: bool) -> Config {
+    Config {
+      ethernet,
+      ..Config::new()
+    }
+  }
+
+  fn counts(counts: &[AtomicUsize; 2]) -> (usize, usize) {
+    (
+      counts[0].load(Ordering::SeqCst),
+      counts[1].load(Ordering::SeqCst),
+    )
+  }
+
+  #[test]
+  fn follows_the_config() {
+    let (mut modules, _, runs) = module("Toggled");
+    modules.apply(&config(false));
+    assert_eq!(modules.statuses(), [("Toggled", WorkerState::Stopped)]);
+
+    modules.apply(&config(true));
+    modules.apply(&config(true));
+    assert_eq!(modules.statuses(), [("Toggled", WorkerState::Running)]);
+
+    modules.apply(&config(false));
+    assert_eq!(modules.statuses(), [("Toggled", WorkerState::Stopped)]);
+
+    modules.apply(&config(true));
+    modules.apply(&config(false));
+    assert_eq!(counts(&runs), (2, 2));
+  }
+
+  #[test]
+  fn a_module_still_stopping_starts_once_it_has_stopped() {
+    let (mut modules, supervisor, runs) = module("Slow");
+    modules.apply(&config(true));
+    while counts(&runs) != (1, 0) {
+      std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // Switched off, but it has not let go yet when it is switched back on
+    assert!(!supervisor.stop_worker("Slow", Duration::ZERO));
+    assert_eq!(modules.statuses(), [("Slow", WorkerState::Stopping)]);
+    modules.apply(&config(true));
+    assert_eq!(modules.statuses(), [("Slow", WorkerState::Stopping)]);
+
+    while modules.statuses()[0].1 == WorkerState::Stopping {
+      std::thread::sleep(Duration::from_millis(5));
+    }
+    modules.apply(&config(true));
+    assert_eq!(modules.statuses(), [("Slow", WorkerState::Running)]);
+
+    modules.apply(&config(false));
+    assert_eq!(counts(&runs), (2, 2));
+  }
+}
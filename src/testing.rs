@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::{
+  backend::dry_run::Planned,
+  cli::Instance,
+  config::{store::ConfigStore, Config},
+};
+
+/// An empty directory of its own for the test `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("pwcca-auto-{}-{}", std::process::id(), name));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+/// The running app without its tray, which is what saves the config there
+pub struct Running(pub ConfigStore);
+
+impl Instance for Running {
+  fn config(&self) -> Result<Config> {
+    Ok(self.0.snapshot())
+  }
+
+  fn update(&self, f: &mut dyn FnMut(&mut Config) -> Result<()>) -> Result<Config> {
+    let mut updated = self.0.snapshot();
+    f(&mut updated)?;
+    Ok(self.0.replace(updated))
+  }
+
+  fn reload(&self) -> Result<bool> {
+    Ok(false)
+  }
+
+  fn planned(&self) -> Result<Vec<Planned>> {
+    Ok(Vec::new())
+  }
+}